@@ -0,0 +1,118 @@
+// Compile time of boot/compiler.scm with the native compiler procedures and with their Scheme versions.
+// Run with `cargo bench --bench compile`.
+use std::{
+    fs,
+    panic::{self, AssertUnwindSafe},
+    time::{Duration, Instant},
+};
+
+use rmosh::{
+    gc::GcRef,
+    objects::{Object, Vector},
+    read::read_all,
+    vm::Vm,
+};
+
+const ITERATIONS: u32 = 5;
+
+fn main() {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../boot/compiler.scm");
+    let source = fs::read_to_string(path).expect("can't read boot/compiler.scm");
+
+    let mut scheme_vm = new_vm(&source, false);
+    let mut native_vm = new_vm(&source, true);
+
+    // Some forms use what rmosh doesn't support yet. They must fail in both modes and are skipped.
+    let scheme_code = compile_forms(&mut scheme_vm);
+    let native_code = compile_forms(&mut native_vm);
+    for (i, (scheme, native)) in scheme_code.iter().zip(&native_code).enumerate() {
+        if scheme != native {
+            panic!(
+                "form {} compiles differently\n  scheme: {:?}\n  native: {:?}",
+                i, scheme, native
+            );
+        }
+    }
+    let compiled: Vec<usize> = (0..scheme_code.len())
+        .filter(|&i| scheme_code[i].is_some())
+        .collect();
+
+    let scheme_time = bench(&mut scheme_vm, &compiled);
+    let native_time = bench(&mut native_vm, &compiled);
+
+    println!(
+        "compiled {} top-level forms of boot/compiler.scm, skipped {}",
+        compiled.len(),
+        scheme_code.len() - compiled.len()
+    );
+    println!("scheme compiler procedures: {:?} / iteration", scheme_time);
+    println!("native compiler procedures: {:?} / iteration", native_time);
+    println!(
+        "speedup: {:.2}x",
+        scheme_time.as_secs_f64() / native_time.as_secs_f64()
+    );
+}
+
+// A Vm which has the forms of source in the global variable bench-forms as a vector.
+// Globals are GC roots and survive Vm::reset, unlike Vm::push_root.
+fn new_vm(source: &str, use_native_compiler_procs: bool) -> Vm {
+    let mut vm = Vm::new();
+    vm.use_native_compiler_procs = use_native_compiler_procs;
+    vm.load_compiler();
+    let mut sexps = read_all(&mut vm.gc, source).expect("can't read boot/compiler.scm");
+    let mut forms = vec![];
+    while let Object::Pair(pair) = sexps {
+        forms.push(pair.car);
+        sexps = pair.cdr;
+    }
+    let forms = vm.gc.new_vector(&forms);
+    let symbol = vm.gc.intern("bench-forms");
+    vm.set_symbol_value(symbol, forms);
+    vm
+}
+
+fn forms(vm: &mut Vm) -> GcRef<Vector> {
+    let symbol = vm.gc.intern("bench-forms");
+    match vm.global_value(symbol) {
+        Some(Object::Vector(forms)) => forms,
+        _ => panic!("bench-forms is not defined"),
+    }
+}
+
+fn compile(vm: &mut Vm, i: usize) -> Object {
+    let symbol = vm.gc.intern("compile-no-optimize");
+    let compiler = vm.global_value(symbol).expect("compiler is not loaded");
+    let form = forms(vm).data[i];
+    vm.call_closure(compiler, &[form])
+}
+
+// Compile each form once. Returns the printed code, or None for forms which failed to compile.
+fn compile_forms(vm: &mut Vm) -> Vec<Option<String>> {
+    let len = forms(vm).len();
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let code = (0..len)
+        .map(
+            |i| match panic::catch_unwind(AssertUnwindSafe(|| compile(vm, i))) {
+                Ok(code) => Some(code.to_string()),
+                Err(_) => {
+                    vm.reset();
+                    None
+                }
+            },
+        )
+        .collect();
+    panic::set_hook(hook);
+    code
+}
+
+// Returns the average time to compile the forms.
+fn bench(vm: &mut Vm, forms: &[usize]) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        for &i in forms {
+            compile(vm, i);
+        }
+    }
+    start.elapsed() / ITERATIONS
+}
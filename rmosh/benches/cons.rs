@@ -0,0 +1,48 @@
+// Consing throughput with small objects in pages and with every object boxed.
+// Each iteration builds lists and drops them, so allocation and sweeping dominate.
+// Run with `cargo bench --bench cons`.
+use std::time::{Duration, Instant};
+
+use rmosh::{gc::GcConfig, vm::Vm};
+
+const LIST_LENGTH: usize = 1000;
+const LISTS: usize = 2000;
+const ITERATIONS: u32 = 5;
+
+fn main() {
+    let boxed = bench(false);
+    let paged = bench(true);
+    let pairs = (LIST_LENGTH * LISTS) as f64;
+    println!("{} lists of {} pairs per iteration", LISTS, LIST_LENGTH);
+    println!(
+        "boxed: {:?} / iteration, {:.1} Mpairs/s",
+        boxed,
+        pairs / boxed.as_secs_f64() / 1e6
+    );
+    println!(
+        "paged: {:?} / iteration, {:.1} Mpairs/s",
+        paged,
+        pairs / paged.as_secs_f64() / 1e6
+    );
+    println!("speedup: {:.2}x", boxed.as_secs_f64() / paged.as_secs_f64());
+}
+
+fn bench(use_pages: bool) -> Duration {
+    let mut vm = Vm::with_gc_config(GcConfig {
+        use_pages,
+        ..GcConfig::default()
+    });
+    vm.eval_string(&format!(
+        "(define (build n acc) (if (= n 0) acc (build (- n 1) (cons n acc))))
+         (define (run i) (if (= i 0) #t (begin (build {} (quote ())) (run (- i 1)))))",
+        LIST_LENGTH
+    ));
+    let program = format!("(run {})", LISTS);
+    // Warm up the heap.
+    vm.eval_string(&program);
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        vm.eval_string(&program);
+    }
+    start.elapsed() / ITERATIONS
+}
@@ -0,0 +1,77 @@
+// Pause times of minor and major collections with a large live heap.
+// Each round allocates short-lived pairs and a few which stay alive, then collects.
+// Run with `cargo bench --bench gc`.
+use std::time::{Duration, Instant};
+
+use rmosh::{gc::Collection, objects::Object, vm::Vm};
+
+const LIVE_OBJECTS: isize = 200_000;
+const ROUNDS: usize = 50;
+const GARBAGE_PER_ROUND: usize = 20_000;
+
+fn main() {
+    let major = bench(Collection::Major);
+    let minor = bench(Collection::Minor);
+    println!(
+        "{} live lists, {} short-lived pairs per collection",
+        LIVE_OBJECTS, GARBAGE_PER_ROUND
+    );
+    println!("major collection pause: {}", major);
+    println!("minor collection pause: {}", minor);
+    println!(
+        "speedup: {:.2}x",
+        major.average().as_secs_f64() / minor.average().as_secs_f64()
+    );
+}
+
+struct Pauses(Vec<Duration>);
+
+impl Pauses {
+    fn average(&self) -> Duration {
+        self.0.iter().sum::<Duration>() / self.0.len() as u32
+    }
+
+    fn max(&self) -> Duration {
+        self.0.iter().copied().max().unwrap_or_default()
+    }
+}
+
+impl std::fmt::Display for Pauses {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} average, {:?} max", self.average(), self.max())
+    }
+}
+
+fn bench(collection: Collection) -> Pauses {
+    let mut vm = Vm::new();
+    vm.eval_string(&format!(
+        "(define live (make-vector {} #f))
+         (define (fill i) (if (< i {}) (begin (vector-set! live i (list i i)) (fill (+ i 1)))))
+         (fill 0)",
+        LIVE_OBJECTS, LIVE_OBJECTS
+    ));
+    let symbol = vm.gc.intern("live");
+    let mut live = match vm.global_value(symbol) {
+        Some(Object::Vector(v)) => v,
+        _ => panic!("live is not defined"),
+    };
+    // Make the live heap old.
+    vm.collect_garbage(Collection::Major);
+
+    let mut pauses = vec![];
+    for round in 0..ROUNDS {
+        for i in 0..GARBAGE_PER_ROUND {
+            let pair = vm.gc.cons(Object::Number(i as isize), Object::Nil);
+            // Replace some live objects, as a program updating its data does.
+            if i % 100 == 0 {
+                let index = (round * GARBAGE_PER_ROUND + i) % live.data.len();
+                live.data[index] = pair;
+                vm.gc.write_barrier(live, pair);
+            }
+        }
+        let start = Instant::now();
+        vm.collect_garbage(collection);
+        pauses.push(start.elapsed());
+    }
+    Pauses(pauses)
+}
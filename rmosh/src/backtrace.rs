@@ -0,0 +1,116 @@
+/// Backtrace.
+/// Vm::backtrace walks the call frames on the stack and collects the closure each frame runs.
+/// Each closure is named by its source info and the global variable it is bound to, if any.
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+};
+
+use crate::{
+    gc::GcRef,
+    objects::{Closure, Object, Symbol},
+};
+
+pub struct BacktraceFrame {
+    // Name and arguments such as (fib n), or <closure> and <top-level> when the source info has no name.
+    pub name: String,
+    // Global variable bound to the closure, unless it is the name in the source info.
+    pub global: Option<String>,
+    // "file:line" where the closure is defined.
+    pub location: Option<String>,
+    // The closure whose code the frame runs.
+    pub closure: GcRef<Closure>,
+}
+
+impl Display for BacktraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if let Some(global) = &self.global {
+            write!(f, " bound to {}", global)?;
+        }
+        if let Some(location) = &self.location {
+            write!(f, " at {}", location)?;
+        }
+        Ok(())
+    }
+}
+
+// Active frames, innermost first.
+pub struct Backtrace {
+    pub frames: Vec<BacktraceFrame>,
+}
+
+impl Backtrace {
+    // closures are the display closures of the frames, innermost first.
+    pub fn new(closures: &[Object], globals: &HashMap<GcRef<Symbol>, Object>) -> Self {
+        let mut names: HashMap<GcRef<Closure>, &str> = HashMap::new();
+        for (symbol, value) in globals {
+            if let Object::Closure(closure) = value {
+                // Prefer the shortest name when a closure is bound to several globals, for a stable result.
+                let name = symbol.string.as_str();
+                names
+                    .entry(*closure)
+                    .and_modify(|old| {
+                        if (name.len(), name) < (old.len(), *old) {
+                            *old = name;
+                        }
+                    })
+                    .or_insert(name);
+            }
+        }
+        let frames = closures
+            .iter()
+            .filter_map(|&dc| code_closure(dc))
+            .map(|closure| {
+                let name = match (closure.name(), closure.src) {
+                    (Object::False, Object::Pair(_)) => "<closure>".to_string(),
+                    // Top-level code runs in a display closure without source info.
+                    (Object::False, _) => "<top-level>".to_string(),
+                    (name, _) => name.to_write_string(),
+                };
+                // (define (f x) ...) is already named f by its source info.
+                let src_name = match closure.name() {
+                    Object::Pair(pair) => match pair.car {
+                        Object::Symbol(symbol) => Some(symbol.string.to_owned()),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                let global = names
+                    .get(&closure)
+                    .filter(|&&global| src_name.as_deref() != Some(global))
+                    .map(|global| global.to_string());
+                BacktraceFrame {
+                    name,
+                    global,
+                    location: closure.location(),
+                    closure,
+                }
+            })
+            .collect();
+        Backtrace { frames }
+    }
+}
+
+impl Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "backtrace:")?;
+        for (i, frame) in self.frames.iter().enumerate() {
+            writeln!(f, "  {:3}  {}", i, frame)?;
+        }
+        Ok(())
+    }
+}
+
+// The closure whose code a display closure runs. Display closures of let refer it by prev.
+// The outermost display closure stands for the top-level.
+pub fn code_closure(dc: Object) -> Option<GcRef<Closure>> {
+    let mut obj = dc;
+    while let Object::Closure(closure) = obj {
+        if closure.ops_len > 0 || !matches!(closure.prev, Object::Closure(_)) {
+            return Some(closure);
+        }
+        obj = closure.prev;
+    }
+    None
+}
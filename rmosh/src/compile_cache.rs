@@ -0,0 +1,207 @@
+/// Auto compile cache.
+/// Code compiled while loading a file is saved to the cache directory together with the forms it was compiled from.
+/// The next load of the same file reuses the code as long as the file, its mtime and the compiler are unchanged.
+/// Each cached code is used only if its form is equal to the form being compiled, so a stale entry can't run wrong code.
+/// Cached code is also verified before it is used, and an entry which is broken or truncated is a cache miss and is rewritten.
+use std::{
+    collections::hash_map::DefaultHasher,
+    env, fs,
+    hash::{Hash, Hasher},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::UNIX_EPOCH,
+};
+
+use crate::{
+    compiler,
+    equal::Equal,
+    fasl::{Fasl, FaslWriter},
+    gc::Gc,
+    objects::Object,
+    verify::verify,
+};
+
+const MAGIC: &[u8; 8] = b"RMOSHACC";
+
+// Increment when the layout of cache files changes.
+const FORMAT_VERSION: u64 = 1;
+
+// $RMOSH_CACHE_DIR or ~/.rmosh/cache.
+pub fn default_cache_dir() -> Option<PathBuf> {
+    if let Some(dir) = env::var_os("RMOSH_CACHE_DIR") {
+        return Some(PathBuf::from(dir));
+    }
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".rmosh").join("cache"))
+}
+
+// Identifies the compiler. Code compiled by other compilers is never used.
+fn compiler_version() -> u64 {
+    static VERSION: OnceLock<u64> = OnceLock::new();
+    *VERSION.get_or_init(|| {
+        let mut hasher = DefaultHasher::new();
+        FORMAT_VERSION.hash(&mut hasher);
+        env!("CARGO_PKG_VERSION").hash(&mut hasher);
+        compiler::BIN_COMPILER.hash(&mut hasher);
+        hasher.finish()
+    })
+}
+
+#[derive(PartialEq)]
+struct CacheKey {
+    compiler_version: u64,
+    mtime: (u64, u32),
+    content_hash: u64,
+}
+
+impl CacheKey {
+    fn write(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&self.compiler_version.to_le_bytes());
+        bytes.extend_from_slice(&self.mtime.0.to_le_bytes());
+        bytes.extend_from_slice(&self.mtime.1.to_le_bytes());
+        bytes.extend_from_slice(&self.content_hash.to_le_bytes());
+    }
+
+    fn read(bytes: &mut &[u8]) -> Result<Self, io::Error> {
+        let mut magic = [0; 8];
+        bytes.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a cache file",
+            ));
+        }
+        let mut u64_buf = [0; 8];
+        let mut u32_buf = [0; 4];
+        bytes.read_exact(&mut u64_buf)?;
+        let compiler_version = u64::from_le_bytes(u64_buf);
+        bytes.read_exact(&mut u64_buf)?;
+        let secs = u64::from_le_bytes(u64_buf);
+        bytes.read_exact(&mut u32_buf)?;
+        let nanos = u32::from_le_bytes(u32_buf);
+        bytes.read_exact(&mut u64_buf)?;
+        let content_hash = u64::from_le_bytes(u64_buf);
+        Ok(CacheKey {
+            compiler_version,
+            mtime: (secs, nanos),
+            content_hash,
+        })
+    }
+}
+
+// Cache of one file which is being loaded.
+pub struct CacheSession {
+    entry_path: PathBuf,
+    key: CacheKey,
+    // (form, code) pairs from the cache, used in order.
+    cached: Vec<(Object, Object)>,
+    position: usize,
+    // (form, code) pairs of this load, saved at the end if the cache didn't cover them.
+    compiled: Vec<(Object, Object)>,
+    is_dirty: bool,
+}
+
+impl CacheSession {
+    // Start a session for path whose content is text. Returns None if the file can't be cached.
+    pub fn start(gc: &mut Gc, cache_dir: &Path, path: &Path, text: &str) -> Option<Self> {
+        let path = fs::canonicalize(path).ok()?;
+        let modified = fs::metadata(&path).ok()?.modified().ok()?;
+        let mtime = modified.duration_since(UNIX_EPOCH).ok()?;
+        let mut hasher = DefaultHasher::new();
+        text.hash(&mut hasher);
+        let key = CacheKey {
+            compiler_version: compiler_version(),
+            mtime: (mtime.as_secs(), mtime.subsec_nanos()),
+            content_hash: hasher.finish(),
+        };
+        let mut hasher = DefaultHasher::new();
+        path.hash(&mut hasher);
+        let entry_path = cache_dir.join(format!("{:016x}.fasl", hasher.finish()));
+        let cached = read_entry(gc, &entry_path, &key).unwrap_or_default();
+        Some(CacheSession {
+            entry_path,
+            key,
+            cached,
+            position: 0,
+            compiled: vec![],
+            is_dirty: false,
+        })
+    }
+
+    // Cached code for form, if the cache has it as the next form.
+    pub fn lookup(&mut self, gc: &mut Box<Gc>, form: Object) -> Option<Object> {
+        if self.is_dirty || self.position >= self.cached.len() {
+            self.is_dirty = true;
+            return None;
+        }
+        let (cached_form, code) = self.cached[self.position];
+        if Equal::new().is_equal(gc, &cached_form, &form) {
+            self.position += 1;
+            self.compiled.push((form, code));
+            Some(code)
+        } else {
+            // The rest of the cache doesn't correspond to this load.
+            self.is_dirty = true;
+            None
+        }
+    }
+
+    pub fn record(&mut self, form: Object, code: Object) {
+        self.compiled.push((form, code));
+    }
+
+    // Save the compiled code if the cache was missing or stale.
+    pub fn finish(self) {
+        if !self.is_dirty && self.position == self.cached.len() {
+            return;
+        }
+        // The cache is only an optimization, so failures are ignored.
+        let _ = self.write_entry();
+    }
+
+    fn write_entry(&self) -> Result<(), io::Error> {
+        let mut writer = FaslWriter::new();
+        self.key.write(&mut writer.bytes);
+        for &(form, code) in &self.compiled {
+            writer.write_sexp(form)?;
+            writer.write_sexp(code)?;
+        }
+        if let Some(dir) = self.entry_path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Write to a temporary file first, so that other processes never see a partial entry.
+        let tmp_path = self
+            .entry_path
+            .with_extension(format!("tmp{}", std::process::id()));
+        fs::write(&tmp_path, &writer.bytes)?;
+        fs::rename(&tmp_path, &self.entry_path)
+    }
+
+    // Objects the Vm must keep alive during the session.
+    pub fn objects(&self) -> impl Iterator<Item = &Object> {
+        self.cached
+            .iter()
+            .chain(self.compiled.iter())
+            .flat_map(|(form, code)| [form, code])
+    }
+}
+
+fn read_entry(gc: &mut Gc, entry_path: &Path, key: &CacheKey) -> Option<Vec<(Object, Object)>> {
+    let bytes = fs::read(entry_path).ok()?;
+    let mut bytes = bytes.as_slice();
+    if CacheKey::read(&mut bytes).ok()? != *key {
+        return None;
+    }
+    let mut fasl = Fasl { bytes };
+    let objects = fasl.read_all_sexp(gc).ok()?;
+    if !objects.len().is_multiple_of(2) {
+        return None;
+    }
+    // A corrupted entry must not run, so the whole entry is dropped if any code is broken.
+    let is_valid = |code: &Object| matches!(code, Object::Vector(v) if verify(&v.data).is_ok());
+    if !objects.chunks(2).all(|pair| is_valid(&pair[1])) {
+        return None;
+    }
+    Some(objects.chunks(2).map(|pair| (pair[0], pair[1])).collect())
+}
@@ -0,0 +1,222 @@
+/// Debugger.
+/// (break 'f) makes the VM stop when a procedure named f is called.
+/// When the VM stops, the debugger reads commands line by line from its input, stdin by default.
+///   step      stop at the next call
+///   next      stop at the next call which is not inside the current one
+///   finish    stop when the current call returns
+///   continue  run until the next breakpoint
+///   locals    show the arguments and free variables of the current call
+///   backtrace show the active frames
+/// The end of the input is the same as continue.
+use std::io::{self, BufRead, BufReader, Write};
+
+use crate::{
+    backtrace::Backtrace,
+    gc::GcRef,
+    objects::{Closure, Object},
+};
+
+const HELP: &str = "commands:
+  s, step       stop at the next call
+  n, next       stop at the next call which is not inside the current one
+  f, finish     stop when the current call returns
+  c, continue   run until the next breakpoint
+  l, locals     show the arguments and free variables
+  bt, backtrace show the active frames
+  h, help       show this help";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mode {
+    Run,
+    Step,
+    // Depth of the frame where next or finish was given.
+    Next(usize),
+    Finish(usize),
+}
+
+// Where the VM stopped.
+pub struct Stop {
+    // The closure called or returning, or a native procedure returning.
+    pub procedure: Object,
+    // Number of call frames including the one of procedure.
+    pub depth: usize,
+    // Arguments of the call, the rest argument is a list.
+    pub args: Vec<Object>,
+    // Value the call returns when stopped at return.
+    pub value: Option<Object>,
+    pub backtrace: Backtrace,
+}
+
+pub struct Debugger {
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+    // Names of procedures to break at.
+    breakpoints: Vec<String>,
+    mode: Mode,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Debugger::new(
+            Box::new(BufReader::new(io::stdin())),
+            Box::new(io::stdout()),
+        )
+    }
+}
+
+impl Debugger {
+    pub fn new(input: Box<dyn BufRead>, output: Box<dyn Write>) -> Self {
+        Debugger {
+            input,
+            output,
+            breakpoints: vec![],
+            mode: Mode::Run,
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, name: &str) {
+        if !self.breakpoints.iter().any(|b| b == name) {
+            self.breakpoints.push(name.to_owned());
+        }
+    }
+
+    pub fn remove_breakpoint(&mut self, name: &str) {
+        self.breakpoints.retain(|b| b != name);
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn breakpoints(&self) -> &[String] {
+        &self.breakpoints
+    }
+
+    // True when no call or return can stop the VM, so it can skip looking at them.
+    pub fn is_idle(&self) -> bool {
+        self.breakpoints.is_empty() && self.mode == Mode::Run
+    }
+
+    pub fn stops_at_call(&self, closure: GcRef<Closure>, depth: usize) -> bool {
+        match self.mode {
+            Mode::Step => true,
+            Mode::Next(d) if depth <= d => true,
+            _ => match procedure_name(Object::Closure(closure)) {
+                Some(name) => self.breakpoints.contains(&name),
+                None => false,
+            },
+        }
+    }
+
+    pub fn stops_at_return(&self, depth: usize) -> bool {
+        matches!(self.mode, Mode::Finish(d) if depth <= d)
+    }
+
+    // Show where the VM stopped and read commands until one resumes the VM.
+    pub fn enter(&mut self, stop: &Stop) {
+        let _ = match stop.value {
+            Some(value) => writeln!(
+                self.output,
+                "<- {} returns {}",
+                call_string(stop),
+                value.to_write_string()
+            ),
+            None => writeln!(self.output, "-> {}", call_string(stop)),
+        };
+        loop {
+            let _ = write!(self.output, "debug> ");
+            let _ = self.output.flush();
+            let mut line = String::new();
+            match self.input.read_line(&mut line) {
+                Ok(0) | Err(_) => {
+                    self.mode = Mode::Run;
+                    return;
+                }
+                Ok(_) => {}
+            }
+            match line.trim() {
+                "s" | "step" => self.mode = Mode::Step,
+                "n" | "next" => self.mode = Mode::Next(stop.depth),
+                // We are already out of the call when stopped at its return.
+                "f" | "finish" if stop.value.is_some() => {
+                    self.mode = Mode::Finish(stop.depth.saturating_sub(1))
+                }
+                "f" | "finish" => self.mode = Mode::Finish(stop.depth),
+                "c" | "continue" => self.mode = Mode::Run,
+                "l" | "locals" => {
+                    self.write_locals(stop);
+                    continue;
+                }
+                "bt" | "backtrace" => {
+                    let _ = write!(self.output, "{}", stop.backtrace);
+                    continue;
+                }
+                "h" | "help" | "?" => {
+                    let _ = writeln!(self.output, "{}", HELP);
+                    continue;
+                }
+                "" => continue,
+                command => {
+                    let _ = writeln!(
+                        self.output,
+                        "unknown command {}, type help for commands",
+                        command
+                    );
+                    continue;
+                }
+            }
+            return;
+        }
+    }
+
+    fn write_locals(&mut self, stop: &Stop) {
+        // Source info is (location name arg ...).
+        let (mut names, free_vars) = match stop.procedure {
+            Object::Closure(closure) => match closure.name() {
+                Object::Pair(name) => (name.cdr, closure.free_vars.clone()),
+                _ => (Object::Nil, closure.free_vars.clone()),
+            },
+            _ => (Object::Nil, vec![]),
+        };
+        for (i, arg) in stop.args.iter().enumerate() {
+            let name = match names {
+                Object::Pair(pair) => {
+                    names = pair.cdr;
+                    pair.car.to_write_string()
+                }
+                _ => format!("local {}", i),
+            };
+            let _ = writeln!(self.output, "  {} = {}", name, arg.to_write_string());
+        }
+        for (i, value) in free_vars.iter().enumerate() {
+            let _ = writeln!(self.output, "  free {} = {}", i, value.to_write_string());
+        }
+    }
+}
+
+// The name in the source info of closure, or the name of a native procedure.
+pub(crate) fn procedure_name(procedure: Object) -> Option<String> {
+    match procedure {
+        Object::Closure(closure) => match closure.name() {
+            Object::Pair(name) => match name.car {
+                Object::Symbol(symbol) => Some(symbol.string.to_owned()),
+                _ => None,
+            },
+            _ => None,
+        },
+        Object::Procedure(procedure) => Some(procedure.name.to_owned()),
+        _ => None,
+    }
+}
+
+// The call such as (fib 10).
+fn call_string(stop: &Stop) -> String {
+    let mut s = "(".to_string();
+    s.push_str(&procedure_name(stop.procedure).unwrap_or_else(|| "<closure>".to_string()));
+    for arg in &stop.args {
+        s.push(' ');
+        s.push_str(&arg.to_write_string());
+    }
+    s.push(')');
+    s
+}
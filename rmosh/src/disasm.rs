@@ -0,0 +1,144 @@
+/// Disassembler.
+/// Compiled code is printed one instruction per line with its position.
+/// Jump destinations are shown as labels, constants in write form and free variables with their values when they are known.
+/// Bodies of closures created by the code are printed indented under their Closure instruction.
+use std::{collections::BTreeMap, fmt::Write, slice};
+
+use crate::{
+    gc::GcRef,
+    objects::{Closure, Object},
+    op::{Op, Operand},
+};
+
+// Disassemble the body of closure.
+pub fn disassemble_closure(closure: GcRef<Closure>) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        ";; {} args: {}{}, free variables: {}",
+        Object::Closure(closure).to_write_string(),
+        closure.argc,
+        if closure.is_optional_arg {
+            " with rest"
+        } else {
+            ""
+        },
+        closure.free_vars.len()
+    );
+    // ops points to the src operand of the Closure instruction and the body follows it.
+    if closure.ops_len > 1 {
+        let body = unsafe { slice::from_raw_parts(closure.ops.add(1), closure.ops_len - 1) };
+        out.push_str(&disassemble(body, &closure.free_vars));
+    }
+    out
+}
+
+// Disassemble code. free_vars are the values of the free variables the code refers, if known.
+pub fn disassemble(code: &[Object], free_vars: &[Object]) -> String {
+    let labels = collect_labels(code);
+    let mut out = String::new();
+    // End positions of the closure bodies we are in.
+    let mut closure_ends: Vec<usize> = vec![];
+    let mut i = 0;
+    while i < code.len() {
+        while closure_ends.last() == Some(&i) {
+            closure_ends.pop();
+        }
+        let indent = "  ".repeat(closure_ends.len());
+        if let Some(label) = labels.get(&i) {
+            let _ = writeln!(out, "{}L{}:", indent, label);
+        }
+        let op = match code[i] {
+            Object::Instruction(op) => op,
+            obj => {
+                let _ = writeln!(out, "{}{:5}  ?? {}", indent, i, obj.to_write_string());
+                i += 1;
+                continue;
+            }
+        };
+        let end = (i + 1 + op.operand_count()).min(code.len());
+        let operands = &code[(i + 1).min(end)..end];
+        let _ = write!(out, "{}{:5}  {}", indent, i, op);
+        if op == Op::Closure {
+            write_closure_operands(&mut out, operands);
+            if let Some(Object::Number(size)) = operands.first() {
+                closure_ends.push(i + 1 + *size as usize);
+            }
+        } else {
+            for (n, &operand) in operands.iter().enumerate() {
+                match (op.jump_operand(), operand) {
+                    (Some(m), Object::Number(offset)) if m == n + 1 => {
+                        let destination = (i + m) as isize + offset;
+                        match labels.get(&(destination as usize)) {
+                            Some(label) => {
+                                let _ = write!(out, " L{}", label);
+                            }
+                            None => {
+                                let _ = write!(out, " {:+}", offset);
+                            }
+                        }
+                    }
+                    _ => {
+                        let _ = write!(out, " {}", operand.to_write_string());
+                    }
+                }
+            }
+            // Values of free variables are known only at the top.
+            if closure_ends.is_empty() && op.operands().first() == Some(&Operand::Free) {
+                if let Some(&Object::Number(n)) = operands.first() {
+                    if let Some(value) = free_vars.get(n as usize) {
+                        let _ = write!(out, "  ; {}", value.to_write_string());
+                    }
+                }
+            }
+        }
+        out.push('\n');
+        i += op.operand_count() + 1;
+    }
+    out
+}
+
+// Number labels for all jump destinations in the order of their positions.
+fn collect_labels(code: &[Object]) -> BTreeMap<usize, usize> {
+    let mut destinations = BTreeMap::new();
+    let mut i = 0;
+    while i < code.len() {
+        match code[i] {
+            Object::Instruction(op) => {
+                if let Some(m) = op.jump_operand() {
+                    if let (false, Some(Object::Number(offset))) =
+                        (op == Op::Closure, code.get(i + m))
+                    {
+                        destinations.insert(((i + m) as isize + offset) as usize, 0);
+                    }
+                }
+                i += op.operand_count() + 1;
+            }
+            _ => i += 1,
+        }
+    }
+    for (n, label) in destinations.values_mut().enumerate() {
+        *label = n;
+    }
+    destinations
+}
+
+// Closure size argc optional? num-free-vars max-stack src.
+fn write_closure_operands(out: &mut String, operands: &[Object]) {
+    if let [_, argc, optional, num_free_vars, _, src] = operands {
+        let _ = write!(
+            out,
+            " args: {}{}, free variables: {}",
+            argc.to_write_string(),
+            if optional.is_false() {
+                ""
+            } else {
+                " with rest"
+            },
+            num_free_vars.to_write_string()
+        );
+        if !src.is_false() {
+            let _ = write!(out, "  ; {}", src.to_write_string());
+        }
+    }
+}
@@ -96,19 +96,13 @@ macro_rules! read_num3 {
 }
 
 impl Fasl<'_> {
-    pub fn read_all_sexp(&mut self, gc: &mut Gc) -> Vec<Object> {
+    // Read objects until the bytes run out. Broken or truncated data is an error.
+    pub fn read_all_sexp(&mut self, gc: &mut Gc) -> Result<Vec<Object>, io::Error> {
         let mut objects = vec![];
-        loop {
-            match self.read_sexp(gc) {
-                Ok(sexp) => {
-                    objects.push(sexp);
-                }
-                Err(_) => {
-                    break;
-                }
-            }
+        while !self.bytes.is_empty() {
+            objects.push(self.read_sexp(gc)?);
         }
-        objects
+        Ok(objects)
     }
 
     pub fn read_sexp(&mut self, gc: &mut Gc) -> Result<Object, io::Error> {
@@ -130,9 +124,10 @@ impl Fasl<'_> {
     fn read_compiler_insn(&mut self) -> Result<Object, io::Error> {
         let mut buf = [0; 1];
         self.bytes.read_exact(&mut buf)?;
-        Ok(Object::Instruction(
-            FromPrimitive::from_u8(buf[0]).expect("unknown Op"),
-        ))
+        match FromPrimitive::from_u8(buf[0]) {
+            Some(op) => Ok(Object::Instruction(op)),
+            None => Err(self.create_read_error("unknown Op")),
+        }
     }
 
     fn read_fixnum(&mut self) -> Result<Object, io::Error> {
@@ -204,22 +199,109 @@ impl Fasl<'_> {
     }
 
     fn create_read_error(&self, reason: &str) -> io::Error {
-        io::Error::new(io::ErrorKind::Other, reason)
+        io::Error::new(io::ErrorKind::InvalidData, reason)
     }
 
     fn read_tag(&mut self) -> Result<Tag, io::Error> {
         let mut buf = [0; 1];
         self.bytes.read_exact(&mut buf)?;
-        Ok(FromPrimitive::from_u8(buf[0]).expect("unknown tag"))
+        FromPrimitive::from_u8(buf[0]).ok_or_else(|| self.create_read_error("unknown tag"))
+    }
+}
+
+// S-expression serializer. The output can be read by Fasl.
+#[derive(Default)]
+pub struct FaslWriter {
+    pub bytes: Vec<u8>,
+}
+
+impl FaslWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Only objects the reader and the compiler can produce are supported.
+    pub fn write_sexp(&mut self, obj: Object) -> Result<(), io::Error> {
+        match obj {
+            Object::Number(n) => {
+                self.write_tag(Tag::Fixnum);
+                self.bytes.extend_from_slice(&n.to_le_bytes());
+            }
+            Object::True => self.write_tag(Tag::True),
+            Object::False => self.write_tag(Tag::False),
+            Object::Nil => self.write_tag(Tag::Nil),
+            Object::Char(c) => {
+                self.write_tag(Tag::Char);
+                self.bytes.extend_from_slice(&(c as u32).to_le_bytes());
+            }
+            Object::Symbol(symbol) => {
+                self.write_tag(Tag::Symbol);
+                self.write_chars(&symbol.string)?;
+            }
+            Object::String(s) => {
+                self.write_tag(Tag::String);
+                self.write_chars(&s.string)?;
+            }
+            Object::Pair(pair) => {
+                self.write_tag(Tag::Pair);
+                self.write_sexp(pair.car)?;
+                self.write_sexp(pair.cdr)?;
+            }
+            Object::Vector(v) => {
+                self.write_tag(Tag::Vector);
+                self.write_len(v.data.len())?;
+                for &obj in &v.data {
+                    self.write_sexp(obj)?;
+                }
+            }
+            Object::Instruction(op) => {
+                self.write_tag(Tag::CompilerInsn);
+                self.bytes.push(op as u8);
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("fasl: can't serialize {}", obj),
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    fn write_tag(&mut self, tag: Tag) {
+        self.bytes.push(tag as u8);
+    }
+
+    fn write_len(&mut self, len: usize) -> Result<(), io::Error> {
+        match u16::try_from(len) {
+            Ok(len) => {
+                self.bytes.extend_from_slice(&len.to_le_bytes());
+                Ok(())
+            }
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("fasl: length {} is too long", len),
+            )),
+        }
+    }
+
+    fn write_chars(&mut self, s: &str) -> Result<(), io::Error> {
+        self.write_len(s.chars().count())?;
+        for c in s.chars() {
+            self.bytes.extend_from_slice(&(c as u32).to_le_bytes());
+        }
+        Ok(())
     }
 }
 
 /// Tests.
 #[cfg(test)]
 pub mod tests {
-    use crate::{equal::Equal, gc::Gc, objects::Object};
+    use std::io;
 
-    use super::Fasl;
+    use crate::{equal::Equal, gc::Gc, objects::Object, op::Op};
+
+    use super::{Fasl, FaslWriter};
 
     #[macro_export]
     macro_rules! assert_equal {
@@ -314,4 +396,45 @@ pub mod tests {
         let obj = fasl.read_sexp(&mut gc).unwrap();
         assert_equal!(gc, expected, obj);
     }
+    #[test]
+    fn test_write_read() {
+        let mut gc = Box::new(Gc::new());
+        let hello = gc.symbol_intern("hello");
+        let s = gc.new_string("w\u{f6}rld");
+        let v = gc.new_vector(&vec![Object::Instruction(Op::Halt), Object::Char('a')]);
+        let expected = gc.list6(hello, s, v, Object::Number(-3), Object::True, Object::False);
+        let mut writer = FaslWriter::new();
+        writer.write_sexp(expected).unwrap();
+        let mut fasl = Fasl {
+            bytes: &writer.bytes,
+        };
+        let obj = fasl.read_sexp(&mut gc).unwrap();
+        assert_equal!(gc, expected, obj);
+        assert!(writer.write_sexp(Object::Unspecified).is_err());
+    }
+
+    #[test]
+    fn test_read_broken() {
+        let mut gc = Box::new(Gc::new());
+        // Unknown tag.
+        let bytes: &[u8] = &[1, 200];
+        assert_eq!(
+            Fasl { bytes }.read_all_sexp(&mut gc).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        // Unknown Op.
+        let bytes: &[u8] = &[9, 255];
+        assert_eq!(
+            Fasl { bytes }.read_all_sexp(&mut gc).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        // Truncated fixnum.
+        let bytes: &[u8] = &[1, 0, 3, 0];
+        assert!(Fasl { bytes }.read_all_sexp(&mut gc).is_err());
+        let bytes: &[u8] = &[1, 2];
+        assert_eq!(
+            Fasl { bytes }.read_all_sexp(&mut gc).unwrap(),
+            vec![Object::True, Object::False]
+        );
+    }
 }
@@ -1,21 +1,51 @@
 // GC implementation based on Loxido written by Manuel Cerón.
 // See https://github.com/ceronman/loxido.
+//
+// The heap has two generations.
+// New objects are allocated in the nursery, the young generation.
+// A minor collection traces only young objects and promotes the survivors to the old generation.
+// Old objects which may refer young objects are kept in the remembered set by write_barrier,
+// so every mutation which stores an object into an existing heap object must call it.
+// A major collection traces and sweeps both generations.
+//
+// Small objects which don't need drop, such as Pairs and Voxes, live in the pages of PageAllocator instead of the lists.
+// Their generation is only the old flag of their headers, and they are swept by the page bitmaps. See pages.rs.
+//
+// Weak boxes and weak hashtables don't mark what they refer weakly.
+// After tracing, their references to objects which weren't marked are cleared before the sweep frees those objects.
+// Guardians resurrect their unreachable objects before that, so weak references to those objects are kept.
+// The symbol table is weak too. Symbols which nothing else refers are removed from it and freed.
+//
+// In debug builds, each object has a generation unique to its allocation, and freeing it sets the generation to 0.
+// GcRef keeps the generation of its object, so dereferencing a GcRef of a freed object aborts even when the memory was reused.
+// The Gc never gives the memory of freed objects back to the allocator in these builds but reuses it for new objects,
+// so reading the generation of a freed object is safe.
+//
+// With the gc_stress feature, every safepoint after an allocation runs a major collection and freed objects are poisoned.
+// Marking a poisoned object panics, so a GcRef which a missing root left dangling is found at the next collection.
+// Gc::alloc doesn't collect in this mode either, so objects which a native procedure holds only in its locals
+// between two allocations are never checked. Those only need roots across calls back into Scheme code.
 
 // TODO
 // https://github.com/ceronman/loxido/issues/3
 //
 
+use std::alloc::{self, Layout};
 use std::collections::HashMap;
+use std::env;
 use std::fmt;
 use std::fmt::Display;
 use std::hash::{Hash, Hasher};
 use std::mem;
-use std::ptr::NonNull;
+use std::ptr::{self, NonNull};
+use std::time::{Duration, Instant};
 use std::{ops::Deref, ops::DerefMut, usize};
 
 use crate::objects::{
-    Closure, EqHashtable, Object, Pair, Procedure, SString, SimpleStruct, Symbol, Vector, Vox,
+    Closure, EqHashtable, Guardian, InputPort, Object, Pair, Procedure, SString, SimpleStruct,
+    Symbol, Vector, Vox, WeakBox, Weakness,
 };
+use crate::pages::PageAllocator;
 use crate::vm::Vm;
 
 // GcRef.
@@ -23,6 +53,32 @@ use crate::vm::Vm;
 #[derive(Debug)]
 pub struct GcRef<T> {
     pub pointer: NonNull<T>,
+    #[cfg(debug_assertions)]
+    generation: u64,
+}
+
+impl<T> GcRef<T> {
+    fn new(pointer: NonNull<T>) -> Self {
+        GcRef {
+            pointer,
+            #[cfg(debug_assertions)]
+            generation: unsafe { pointer.cast::<GcHeader>().as_ref().generation },
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    fn check_alive(&self) {
+        let generation = unsafe { self.pointer.cast::<GcHeader>().as_ref().generation };
+        if generation != self.generation {
+            // A panic could be caught and the freed object used again.
+            eprintln!(
+                "GcRef<{}> at {:?} refers an object which was freed",
+                std::any::type_name::<T>(),
+                self.pointer
+            );
+            std::process::abort();
+        }
+    }
 }
 
 impl<T> Display for GcRef<T> {
@@ -57,12 +113,16 @@ impl<T> Deref for GcRef<T> {
     type Target = T;
 
     fn deref(&self) -> &T {
+        #[cfg(debug_assertions)]
+        self.check_alive();
         unsafe { self.pointer.as_ref() }
     }
 }
 
 impl<T> DerefMut for GcRef<T> {
     fn deref_mut(&mut self) -> &mut T {
+        #[cfg(debug_assertions)]
+        self.check_alive();
         unsafe { self.pointer.as_mut() }
     }
 }
@@ -71,6 +131,7 @@ impl<T> DerefMut for GcRef<T> {
 pub enum ObjectType {
     Closure,
     EqHashtable,
+    Guardian,
     InputPort,
     Pair,
     Procedure,
@@ -79,50 +140,246 @@ pub enum ObjectType {
     Symbol,
     Vector,
     Vox,
+    WeakBox,
+}
+
+impl ObjectType {
+    pub const ALL: [ObjectType; 12] = [
+        ObjectType::Closure,
+        ObjectType::EqHashtable,
+        ObjectType::Guardian,
+        ObjectType::InputPort,
+        ObjectType::Pair,
+        ObjectType::Procedure,
+        ObjectType::SimpleStruct,
+        ObjectType::String,
+        ObjectType::Symbol,
+        ObjectType::Vector,
+        ObjectType::Vox,
+        ObjectType::WeakBox,
+    ];
+
+    // Name in Scheme.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ObjectType::Closure => "closure",
+            ObjectType::EqHashtable => "eq-hashtable",
+            ObjectType::Guardian => "guardian",
+            ObjectType::InputPort => "input-port",
+            ObjectType::Pair => "pair",
+            ObjectType::Procedure => "procedure",
+            ObjectType::SimpleStruct => "simple-struct",
+            ObjectType::String => "string",
+            ObjectType::Symbol => "symbol",
+            ObjectType::Vector => "vector",
+            ObjectType::Vox => "vox",
+            ObjectType::WeakBox => "weak-box",
+        }
+    }
 }
 
 #[repr(C)]
 #[derive(Debug)]
 pub struct GcHeader {
     marked: bool,
+    // Survived a collection.
+    old: bool,
+    // In the remembered set.
+    remembered: bool,
     next: Option<NonNull<GcHeader>>,
     obj_type: ObjectType,
+    // Set by Gc::alloc and 0 after the object is freed.
+    #[cfg(debug_assertions)]
+    generation: u64,
 }
 
 impl GcHeader {
     pub fn new(obj_type: ObjectType) -> Self {
         Self {
             marked: false,
+            old: false,
+            remembered: false,
             next: None,
             obj_type,
+            #[cfg(debug_assertions)]
+            generation: 0,
         }
     }
 }
 
-#[cfg(feature = "test_gc_size")]
+// Generation of the next object allocated by any Gc.
+#[cfg(debug_assertions)]
+static NEXT_GENERATION: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(1);
+
+// Byte written over freed objects in the gc_stress mode.
+#[cfg(feature = "gc_stress")]
+const POISON: u8 = 0xdb;
+
+#[cfg(feature = "debug_log_gc")]
 pub fn short_type_name<T: std::any::Any>() -> &'static str {
     let full_name = std::any::type_name::<T>();
     full_name.split("::").last().unwrap()
 }
 
+// Called with each object of a type when the sweep frees it, for example to release resources outside the heap.
+// The object is freed right after this, so it must not be kept.
+pub type Finalizer = fn(Object);
+
+// Heap sizes which trigger major collections.
+// Vm::new reads them from the environment variables RMOSH_GC_THRESHOLD (bytes) and RMOSH_GC_GROW_FACTOR.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct GcConfig {
+    // The first major collection runs when the old generation exceeds this.
+    pub initial_threshold: usize,
+    // After a major collection, the next one runs when the old generation grows to this times the live heap.
+    pub grow_factor: f64,
+    // Allocate small objects in pages. Otherwise every object is boxed.
+    pub use_pages: bool,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        GcConfig {
+            initial_threshold: 1024 * 1024,
+            grow_factor: 2.0,
+            use_pages: true,
+        }
+    }
+}
+
+impl GcConfig {
+    // The default config overridden by the environment variables which are set and valid.
+    pub fn from_env() -> Self {
+        let mut config = GcConfig::default();
+        if let Some(threshold) = env::var("RMOSH_GC_THRESHOLD")
+            .ok()
+            .and_then(|value| value.parse().ok())
+        {
+            config.initial_threshold = threshold;
+        }
+        if let Some(factor) = env::var("RMOSH_GC_GROW_FACTOR")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|&factor: &f64| factor >= 1.0)
+        {
+            config.grow_factor = factor;
+        }
+        config
+    }
+}
+
+// Statistics returned by Gc::stats.
+#[derive(Debug, Default, Clone)]
+pub struct GcStats {
+    pub num_collections: usize,
+    pub num_major_collections: usize,
+    pub total_pause: Duration,
+    pub last_pause: Duration,
+    pub heap_size: usize,
+    pub peak_heap_size: usize,
+    // Bytes and number of objects of each type, which are allocated and not freed yet.
+    pub live_bytes: Vec<(ObjectType, usize)>,
+    pub live_objects: Vec<(ObjectType, usize)>,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Collection {
+    // Collect the nursery.
+    Minor,
+    // Collect the whole heap.
+    Major,
+}
+
 pub struct Gc {
+    // A major collection runs when the old generation exceeds this.
     next_gc: usize,
-    first: Option<NonNull<GcHeader>>,
+    // Young objects.
+    nursery: Option<NonNull<GcHeader>>,
+    // Old objects.
+    old: Option<NonNull<GcHeader>>,
+    // Small objects of both generations. None when they are boxed too.
+    pages: Option<PageAllocator>,
+    // Old objects which may refer young objects.
+    remembered: Vec<NonNull<GcHeader>>,
+    // Kind of the running collection.
+    collection: Collection,
     marked_objects: Vec<NonNull<GcHeader>>,
+    // Weak boxes, weak hashtables and guardians traced by the running collection.
+    weak_objects: Vec<NonNull<GcHeader>>,
+    finalizers: Vec<(ObjectType, Finalizer)>,
+    // Boxed objects freed by the last collection in the gc_stress mode.
+    #[cfg(feature = "gc_stress")]
+    quarantine: Vec<(NonNull<u8>, Layout)>,
+    // Memory of freed boxed objects by layout, reused for new objects in debug builds.
+    #[cfg(debug_assertions)]
+    free_blocks: HashMap<Layout, Vec<NonNull<u8>>>,
+    // Interned symbols. They don't keep the symbols alive.
     pub symbols: HashMap<String, GcRef<Symbol>>,
+    // Symbols interned since the last collection. A minor collection looks only at them.
+    young_symbols: Vec<GcRef<Symbol>>,
+    gensym_prefix: String,
+    num_gensyms: usize,
     current_alloc_size: usize,
+    nursery_size: usize,
+    grow_factor: f64,
+    // Statistics. Bytes and number of objects are indexed by ObjectType.
+    live_bytes: [usize; ObjectType::ALL.len()],
+    live_objects: [usize; ObjectType::ALL.len()],
+    peak_heap_size: usize,
+    num_collections: usize,
+    num_major_collections: usize,
+    total_pause: Duration,
+    last_pause: Duration,
+    // When the running collection started.
+    collection_start: Option<Instant>,
 }
 
 impl Gc {
-    const HEAP_GROW_FACTOR: usize = 2;
+    // A minor collection runs when the nursery exceeds this.
+    #[cfg(not(feature = "test_gc_size"))]
+    const NURSERY_SIZE: usize = 256 * 1024;
+    // Small enough to collect often and find missing roots and write barriers.
+    #[cfg(feature = "test_gc_size")]
+    const NURSERY_SIZE: usize = 4 * 1024;
 
     pub fn new() -> Self {
+        Gc::with_config(GcConfig::default())
+    }
+
+    pub fn with_config(config: GcConfig) -> Self {
         Gc {
-            next_gc: 1024 * 1024,
-            first: None,
+            next_gc: config.initial_threshold,
+            nursery: None,
+            old: None,
+            pages: if config.use_pages {
+                Some(PageAllocator::default())
+            } else {
+                None
+            },
+            remembered: Vec::new(),
+            collection: Collection::Major,
             marked_objects: Vec::new(),
+            weak_objects: Vec::new(),
+            finalizers: Vec::new(),
+            #[cfg(feature = "gc_stress")]
+            quarantine: Vec::new(),
+            #[cfg(debug_assertions)]
+            free_blocks: HashMap::new(),
             symbols: HashMap::new(),
+            young_symbols: Vec::new(),
+            gensym_prefix: "a".to_string(),
+            num_gensyms: 0,
             current_alloc_size: 0,
+            nursery_size: 0,
+            grow_factor: config.grow_factor,
+            live_bytes: [0; ObjectType::ALL.len()],
+            live_objects: [0; ObjectType::ALL.len()],
+            peak_heap_size: 0,
+            num_collections: 0,
+            num_major_collections: 0,
+            total_pause: Duration::ZERO,
+            last_pause: Duration::ZERO,
+            collection_start: None,
         }
     }
 
@@ -231,6 +488,27 @@ impl Gc {
         Object::EqHashtable(obj)
     }
 
+    pub fn new_weak_eq_hashtable(&mut self, weakness: Weakness) -> Object {
+        let obj = self.alloc(EqHashtable::with_weakness(weakness));
+        Object::EqHashtable(obj)
+    }
+
+    pub fn new_weak_box(&mut self, value: Object) -> Object {
+        let obj = self.alloc(WeakBox::new(value));
+        Object::WeakBox(obj)
+    }
+
+    pub fn new_guardian(&mut self) -> Object {
+        let obj = self.alloc(Guardian::new());
+        Object::Guardian(obj)
+    }
+
+    // Call finalizer for each object of obj_type freed from now on.
+    pub fn set_finalizer(&mut self, obj_type: ObjectType, finalizer: Finalizer) {
+        self.finalizers.retain(|(t, _)| *t != obj_type);
+        self.finalizers.push((obj_type, finalizer));
+    }
+
     // append o (list or obj) to l.
     // if l is not list return o.
     // allocate new cons sell.
@@ -273,19 +551,6 @@ impl Gc {
         }
     }
 
-    #[cfg(not(feature = "test_gc_size"))]
-    pub fn alloc<T: Display + 'static>(&mut self, object: T) -> GcRef<T> {
-        unsafe {
-            let boxed = Box::new(object);
-            let pointer = NonNull::new_unchecked(Box::into_raw(boxed));
-            let mut header: NonNull<GcHeader> = mem::transmute(pointer.as_ref());
-            header.as_mut().next = self.first.take();
-            self.first = Some(header);
-            GcRef { pointer }
-        }
-    }
-
-    #[cfg(feature = "test_gc_size")]
     pub fn alloc<T: Display + 'static>(&mut self, object: T) -> GcRef<T> {
         unsafe {
             #[cfg(feature = "debug_log_gc")]
@@ -297,12 +562,29 @@ impl Gc {
 
             let alloc_size = std::mem::size_of_val(&object);
             self.current_alloc_size += alloc_size;
-
-            let boxed = Box::new(object);
-            let pointer = NonNull::new_unchecked(Box::into_raw(boxed));
-            let mut header: NonNull<GcHeader> = mem::transmute(pointer.as_ref());
-            header.as_mut().next = self.first.take();
-            self.first = Some(header);
+            self.nursery_size += alloc_size;
+            self.peak_heap_size = self.peak_heap_size.max(self.current_alloc_size);
+
+            let pointer = match &mut self.pages {
+                Some(pages) if PageAllocator::accepts::<T>() => {
+                    let pointer = pages.alloc(alloc_size).cast::<T>();
+                    pointer.as_ptr().write(object);
+                    pointer
+                }
+                _ => {
+                    let pointer = self.alloc_block(Layout::new::<T>()).cast::<T>();
+                    pointer.as_ptr().write(object);
+                    let mut header: NonNull<GcHeader> = pointer.cast();
+                    header.as_mut().next = self.nursery.take();
+                    self.nursery = Some(header);
+                    pointer
+                }
+            };
+            let header: NonNull<GcHeader> = pointer.cast();
+            Gc::stamp(header);
+            let index = header.as_ref().obj_type as usize;
+            self.live_bytes[index] += alloc_size;
+            self.live_objects[index] += 1;
 
             #[cfg(feature = "debug_log_gc")]
             println!(
@@ -315,7 +597,7 @@ impl Gc {
                 self.next_gc,
             );
 
-            GcRef { pointer }
+            GcRef::new(pointer)
         }
     }
 
@@ -325,22 +607,204 @@ impl Gc {
             None => {
                 let symbol = self.alloc(Symbol::new(s.to_owned()));
                 self.symbols.insert(s.to_string(), symbol);
+                self.young_symbols.push(symbol);
                 symbol
             }
         }
     }
 
+    // New uninterned symbol, which is not eq? to any other symbol.
+    // Its name is the gensym prefix and a number, followed by @ and base if given, and no interned symbol has it.
+    pub fn gensym(&mut self, base: Option<&str>) -> GcRef<Symbol> {
+        loop {
+            let mut name = format!("{}{:x}", self.gensym_prefix, self.num_gensyms);
+            self.num_gensyms += 1;
+            if let Some(base) = base {
+                name.push('@');
+                name.push_str(base);
+            }
+            if !self.symbols.contains_key(&name) {
+                return self.alloc(Symbol::new(name));
+            }
+        }
+    }
+
+    pub fn set_gensym_prefix(&mut self, prefix: &str) {
+        self.gensym_prefix = prefix.to_string();
+    }
+
+    // Turn all objects of this Gc into immortal ones and return its symbol table.
+    // Immortal objects stay marked, so no Gc traces or sweeps them and they can be shared between Gcs.
+    pub fn into_immortal(mut self) -> HashMap<String, GcRef<Symbol>> {
+        for list in [self.nursery.take(), self.old.take()] {
+            let mut current = list;
+            while let Some(mut header) = current {
+                unsafe {
+                    header.as_mut().marked = true;
+                    current = header.as_ref().next;
+                }
+            }
+        }
+        if let Some(pages) = self.pages.take() {
+            pages.for_each_object(|mut header| unsafe { header.as_mut().marked = true });
+            // The pages must outlive this Gc.
+            mem::forget(pages);
+        }
+        self.young_symbols.clear();
+        mem::take(&mut self.symbols)
+    }
+
     pub fn bytes_allocated(&self) -> usize {
         self.current_alloc_size
     }
 
+    pub fn stats(&self) -> GcStats {
+        GcStats {
+            num_collections: self.num_collections,
+            num_major_collections: self.num_major_collections,
+            total_pause: self.total_pause,
+            last_pause: self.last_pause,
+            heap_size: self.current_alloc_size,
+            peak_heap_size: self.peak_heap_size,
+            live_bytes: ObjectType::ALL
+                .iter()
+                .map(|&t| (t, self.live_bytes[t as usize]))
+                .collect(),
+            live_objects: ObjectType::ALL
+                .iter()
+                .map(|&t| (t, self.live_objects[t as usize]))
+                .collect(),
+        }
+    }
+
+    // Record that value was stored into owner.
+    // An old owner which now refers a young object is traced by the next minor collection.
+    pub fn write_barrier<T: 'static>(&mut self, owner: GcRef<T>, value: Object) {
+        unsafe {
+            let mut header: NonNull<GcHeader> = owner.pointer.cast();
+            if !header.as_ref().old || header.as_ref().remembered {
+                return;
+            }
+            match Gc::header_of(value) {
+                // Immortal objects stay marked.
+                Some(child) if !child.as_ref().old && !child.as_ref().marked => {
+                    header.as_mut().remembered = true;
+                    self.remembered.push(header);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn header_of(obj: Object) -> Option<NonNull<GcHeader>> {
+        match obj {
+            Object::Closure(r) => Some(r.pointer.cast()),
+            Object::EqHashtable(r) => Some(r.pointer.cast()),
+            Object::Guardian(r) => Some(r.pointer.cast()),
+            Object::InputPort(r) => Some(r.pointer.cast()),
+            Object::Pair(r) => Some(r.pointer.cast()),
+            Object::Procedure(r) => Some(r.pointer.cast()),
+            Object::SimpleStruct(r) => Some(r.pointer.cast()),
+            Object::String(r) => Some(r.pointer.cast()),
+            Object::Symbol(r) => Some(r.pointer.cast()),
+            Object::Vector(r) => Some(r.pointer.cast()),
+            Object::Vox(r) => Some(r.pointer.cast()),
+            Object::WeakBox(r) => Some(r.pointer.cast()),
+            _ => None,
+        }
+    }
+
+    // Type of the heap object obj, None for other objects such as numbers.
+    pub fn object_type(obj: Object) -> Option<ObjectType> {
+        Gc::header_of(obj).map(|header| unsafe { header.as_ref().obj_type })
+    }
+
+    // Immortal objects are shared between Gcs and never freed. See into_immortal.
+    // Only valid out of collections, which unmark all other objects.
+    pub fn is_immortal(obj: Object) -> bool {
+        match Gc::header_of(obj) {
+            Some(header) => unsafe { header.as_ref().marked },
+            None => false,
+        }
+    }
+
+    // Bytes an object of obj_type takes in the heap, as counted by bytes_allocated.
+    pub fn object_size(obj_type: ObjectType) -> usize {
+        match obj_type {
+            ObjectType::Closure => mem::size_of::<Closure>(),
+            ObjectType::EqHashtable => mem::size_of::<EqHashtable>(),
+            ObjectType::Guardian => mem::size_of::<Guardian>(),
+            ObjectType::InputPort => mem::size_of::<InputPort>(),
+            ObjectType::Pair => mem::size_of::<Pair>(),
+            ObjectType::Procedure => mem::size_of::<Procedure>(),
+            ObjectType::SimpleStruct => mem::size_of::<SimpleStruct>(),
+            ObjectType::String => mem::size_of::<SString>(),
+            ObjectType::Symbol => mem::size_of::<Symbol>(),
+            ObjectType::Vector => mem::size_of::<Vector>(),
+            ObjectType::Vox => mem::size_of::<Vox>(),
+            ObjectType::WeakBox => mem::size_of::<WeakBox>(),
+        }
+    }
+
+    // Objects which obj keeps alive. Values of ephemeron hashtables are included because the keys are alive after a collection.
+    pub fn references(obj: Object) -> Vec<Object> {
+        let mut refs = vec![];
+        Gc::for_each_reference(obj, true, |child| refs.push(child));
+        refs
+    }
+
+    // Call f with each object obj refers strongly. Used both by marking and by references.
+    // Keys of weak hashtables, the value of weak boxes and objects registered to guardians are weak.
+    // Values of ephemerons are alive only while their keys are, so they are skipped unless ephemeron_values is true.
+    fn for_each_reference(obj: Object, ephemeron_values: bool, mut f: impl FnMut(Object)) {
+        match obj {
+            Object::Closure(closure) => {
+                closure.free_vars.iter().for_each(|&obj| f(obj));
+                for i in 0..closure.ops_len {
+                    f(unsafe { *closure.ops.add(i) });
+                }
+                f(closure.prev);
+                f(closure.src);
+                f(closure.code);
+            }
+            Object::EqHashtable(hashtable) => {
+                if hashtable.weakness == Weakness::Strong {
+                    hashtable.hash_map.keys().for_each(|&key| f(key));
+                }
+                if hashtable.weakness != Weakness::Ephemeron || ephemeron_values {
+                    hashtable.hash_map.values().for_each(|&value| f(value));
+                }
+            }
+            Object::Guardian(guardian) => {
+                // Registered objects are weak, but their representatives must stay until they are returned.
+                for &(obj, representative) in &guardian.registered {
+                    if obj != representative {
+                        f(representative);
+                    }
+                }
+                guardian.resurrected.iter().for_each(|&obj| f(obj));
+            }
+            Object::Pair(pair) => {
+                f(pair.car);
+                f(pair.cdr);
+                f(pair.src);
+            }
+            Object::SimpleStruct(s) => s.data.iter().for_each(|&obj| f(obj)),
+            Object::Vector(vector) => vector.data.iter().for_each(|&obj| f(obj)),
+            Object::Vox(vox) => f(vox.value),
+            _ => {}
+        }
+    }
+
     // Mark Object as used and push it to marked_objects.
     pub fn mark_object(&mut self, obj: Object) {
         match obj {
             Object::Char(_) => {}
             Object::Eof => {}
             Object::False => {}
-            Object::InputPort(_) => {}
+            Object::InputPort(port) => {
+                self.mark_heap_object(port);
+            }
             Object::Nil => {}
             Object::Number(_) => {}
             Object::Instruction(_) => {}
@@ -351,9 +815,15 @@ impl Gc {
             Object::Vox(vox) => {
                 self.mark_heap_object(vox);
             }
+            Object::WeakBox(weak_box) => {
+                self.mark_heap_object(weak_box);
+            }
             Object::EqHashtable(hashtable) => {
                 self.mark_heap_object(hashtable);
             }
+            Object::Guardian(guardian) => {
+                self.mark_heap_object(guardian);
+            }
             Object::Procedure(procedure) => {
                 self.mark_heap_object(procedure);
             }
@@ -382,7 +852,19 @@ impl Gc {
     pub fn mark_heap_object<T: 'static>(&mut self, mut reference: GcRef<T>) {
         unsafe {
             let mut header: NonNull<GcHeader> = mem::transmute(reference.pointer.as_mut());
-            if header.as_mut().marked {
+            #[cfg(feature = "gc_stress")]
+            if Gc::is_poisoned(header) {
+                panic!(
+                    "gc: {} at {:?} is referenced after it was freed",
+                    std::any::type_name::<T>(),
+                    header
+                );
+            }
+            if header.as_ref().marked {
+                return;
+            }
+            // A minor collection treats old objects as alive and doesn't trace them.
+            if self.collection == Collection::Minor && header.as_ref().old {
                 return;
             }
             header.as_mut().marked = true;
@@ -397,24 +879,69 @@ impl Gc {
         }
     }
 
+    // Kind of the next collection.
+    // Objects are marked by mark_object after this and collected by collect_garbage.
+    pub fn start_collection(&mut self, collection: Collection) {
+        self.collection = collection;
+        self.collection_start = Some(Instant::now());
+    }
+
     // Collect garbage.
     // This traces all references starting from marked_objects.
     pub fn collect_garbage(&mut self) {
         #[cfg(feature = "debug_log_gc")]
         let before: isize = self.current_alloc_size as isize;
+        if self.collection == Collection::Minor {
+            // Young objects referred by remembered objects are alive.
+            for header in self.remembered.clone() {
+                self.mark_object_fields(header);
+            }
+        }
         self.trace_references();
-        self.sweep();
-
-        self.next_gc = self.current_alloc_size * Gc::HEAP_GROW_FACTOR;
+        loop {
+            self.trace_ephemerons();
+            if !self.resurrect_guarded() {
+                break;
+            }
+        }
+        self.clear_weak_references();
+        self.clear_dead_symbols();
+        // All young objects alive are promoted, so no old object refers young ones after this.
+        // Forget them before the sweep, which can free them.
+        for mut header in mem::take(&mut self.remembered) {
+            unsafe { header.as_mut().remembered = false }
+        }
+        #[cfg(feature = "gc_stress")]
+        self.release_quarantine();
+        if self.collection == Collection::Major {
+            let old = self.old.take();
+            self.old = self.sweep(old);
+        }
+        let nursery = self.nursery.take();
+        let promoted = self.sweep(nursery);
+        self.sweep_pages();
+        self.promote(promoted);
+
+        if self.collection == Collection::Major {
+            self.next_gc = (self.current_alloc_size as f64 * self.grow_factor) as usize;
+            self.num_major_collections += 1;
+        }
+        self.num_collections += 1;
+        if let Some(start) = self.collection_start.take() {
+            self.last_pause = start.elapsed();
+            self.total_pause += self.last_pause;
+        }
 
         #[cfg(feature = "debug_log_gc")]
         println!(
-            "collected(bytes:{} before:{} after:{} next:{})",
+            "collected(kind:{:?} bytes:{} before:{} after:{} next:{})",
+            self.collection,
             before - self.current_alloc_size as isize,
             before,
             self.current_alloc_size,
             self.next_gc
         );
+        self.collection = Collection::Major;
     }
 
     // Mark each object's fields.
@@ -425,61 +952,114 @@ impl Gc {
     }
 
     fn mark_object_fields(&mut self, pointer: NonNull<GcHeader>) {
-        let object_type = unsafe { &pointer.as_ref().obj_type };
-        match object_type {
-            ObjectType::Closure => {
-                let closure: &Closure = unsafe { mem::transmute(pointer.as_ref()) };
-                for i in 0..closure.free_vars.len() {
-                    let obj = closure.free_vars[i];
-                    self.mark_object(obj);
+        let obj = Gc::object_of(pointer);
+        let is_weak = match obj {
+            Object::EqHashtable(hashtable) => hashtable.weakness != Weakness::Strong,
+            Object::Guardian(_) | Object::WeakBox(_) => true,
+            _ => false,
+        };
+        // Weak references are cleared after tracing, and values of ephemerons are marked by trace_ephemerons.
+        if is_weak {
+            self.weak_objects.push(pointer);
+        }
+        Gc::for_each_reference(obj, false, |child| self.mark_object(child));
+    }
+
+    // Mark the values of ephemerons whose keys are alive.
+    // Marking a value can make other keys alive, so repeat until nothing new is marked.
+    fn trace_ephemerons(&mut self) {
+        loop {
+            for pointer in self.weak_objects.clone() {
+                if unsafe { pointer.as_ref().obj_type } != ObjectType::EqHashtable {
+                    continue;
                 }
-                for i in 0..closure.ops_len {
-                    let op = unsafe { *closure.ops.offset(i as isize) };
-                    self.mark_object(op);
+                let hashtable: &EqHashtable = unsafe { mem::transmute(pointer.as_ref()) };
+                if hashtable.weakness != Weakness::Ephemeron {
+                    continue;
                 }
-
-                if !closure.prev.is_unspecified() {
-                    self.mark_object(closure.prev);
+                for (&key, &value) in hashtable.hash_map.iter() {
+                    if self.is_alive(key) {
+                        self.mark_object(value);
+                    }
                 }
-
-                self.mark_object(closure.src);
             }
-            ObjectType::Vox => {
-                let vox: &Vox = unsafe { mem::transmute(pointer.as_ref()) };
-                self.mark_object(vox.value);
+            if self.marked_objects.is_empty() {
+                break;
             }
-            ObjectType::Pair => {
-                let pair: &Pair = unsafe { mem::transmute(pointer.as_ref()) };
-                self.mark_object(pair.car);
-                self.mark_object(pair.cdr);
-                self.mark_object(pair.src);                
+            self.trace_references();
+        }
+    }
+
+    // Queue the unreachable objects registered to guardians and mark them again.
+    // Objects only reachable from other unreachable ones are queued at the same time.
+    // Return true if anything was queued.
+    fn resurrect_guarded(&mut self) -> bool {
+        let mut resurrected = vec![];
+        for pointer in self.weak_objects.clone() {
+            if unsafe { pointer.as_ref().obj_type } != ObjectType::Guardian {
+                continue;
             }
-            ObjectType::Vector => {
-                let vector: &Vector = unsafe { mem::transmute(pointer.as_ref()) };
-                for i in 0..vector.data.len() {
-                    self.mark_object(vector.data[i]);
-                }
+            let guardian = unsafe { &mut *(pointer.as_ptr() as *mut Guardian) };
+            let (dead, alive): (Vec<_>, Vec<_>) = guardian
+                .registered
+                .iter()
+                .partition(|(obj, _)| !self.is_alive(*obj));
+            guardian.registered = alive;
+            for (_, representative) in dead {
+                guardian.resurrected.push_back(representative);
+                resurrected.push(representative);
             }
-            ObjectType::SimpleStruct => {
-                let s: &SimpleStruct = unsafe { mem::transmute(pointer.as_ref()) };
-                for i in 0..s.data.len() {
-                    self.mark_object(s.data[i]);
+        }
+        for &obj in &resurrected {
+            self.mark_object(obj);
+        }
+        !resurrected.is_empty()
+    }
+
+    // Clear weak references to objects which the sweep will free.
+    fn clear_weak_references(&mut self) {
+        for pointer in mem::take(&mut self.weak_objects) {
+            match unsafe { pointer.as_ref().obj_type } {
+                ObjectType::WeakBox => {
+                    let weak_box = unsafe { &mut *(pointer.as_ptr() as *mut WeakBox) };
+                    if !self.is_alive(weak_box.value) {
+                        weak_box.value = Object::False;
+                    }
+                }
+                ObjectType::EqHashtable => {
+                    let hashtable = unsafe { &mut *(pointer.as_ptr() as *mut EqHashtable) };
+                    hashtable.hash_map.retain(|&key, _| self.is_alive(key));
                 }
+                _ => {}
             }
-            ObjectType::EqHashtable => {
-                let hashtable: &EqHashtable = unsafe { mem::transmute(pointer.as_ref()) };
+        }
+    }
 
-                for &obj in hashtable.hash_map.values() {
-                    self.mark_object(obj);
-                }
-                for &obj in hashtable.hash_map.keys() {
-                    self.mark_object(obj);
+    // Remove symbols which the sweep will free from the symbol table.
+    // Only young symbols can die in a minor collection.
+    fn clear_dead_symbols(&mut self) {
+        let young_symbols = mem::take(&mut self.young_symbols);
+        if self.collection == Collection::Major {
+            let mut symbols = mem::take(&mut self.symbols);
+            symbols.retain(|_, symbol| self.is_alive(Object::Symbol(*symbol)));
+            self.symbols = symbols;
+        } else {
+            for symbol in young_symbols {
+                if !self.is_alive(Object::Symbol(symbol)) {
+                    self.symbols.remove(&symbol.string);
                 }
             }
-            ObjectType::InputPort => {}
-            ObjectType::String => {}
-            ObjectType::Symbol => {}
-            ObjectType::Procedure => {}
+        }
+    }
+
+    // Whether obj survives the running collection. This is valid after tracing.
+    fn is_alive(&self, obj: Object) -> bool {
+        match Gc::header_of(obj) {
+            Some(header) => unsafe {
+                let header = header.as_ref();
+                header.marked || (self.collection == Collection::Minor && header.old)
+            },
+            None => true,
         }
     }
 
@@ -493,86 +1073,251 @@ impl Gc {
         self.current_alloc_size > self.next_gc
     }
 
-    #[cfg(feature = "test_gc_size")]
-    fn free(&mut self, object_ptr: &mut GcHeader) {
-        use crate::objects::InputPort;
+    // Collection to run now if any.
+    pub fn collection_needed(&self) -> Option<Collection> {
+        let stress = cfg!(feature = "gc_stress") && self.nursery_size > 0;
+        if stress || self.current_alloc_size - self.nursery_size > self.next_gc {
+            Some(Collection::Major)
+        } else if self.nursery_size > Gc::NURSERY_SIZE {
+            Some(Collection::Minor)
+        } else {
+            None
+        }
+    }
 
-        let object_type = object_ptr.obj_type;
+    #[cfg(not(feature = "test_gc_size"))]
+    fn free_procedure(&mut self, header: NonNull<GcHeader>) -> usize {
+        unsafe { self.drop_object::<Procedure>(header) }
+    }
 
-        let header: &GcHeader = object_ptr;
+    #[cfg(feature = "test_gc_size")]
+    fn free_procedure(&mut self, _header: NonNull<GcHeader>) -> usize {
+        panic!("procedure should not be freed");
+    }
 
-        let free_size = match object_type {
-            ObjectType::Symbol => 0,
-            ObjectType::Procedure => {
-                panic!("procedure should not be freed");
-            }
-            ObjectType::String => {
-                let sstring: &SString = unsafe { mem::transmute(header) };
-                std::mem::size_of_val(sstring)
-            }
-            ObjectType::Closure => {
-                let closure: &Closure = unsafe { mem::transmute(header) };
-                std::mem::size_of_val(closure)
-            }
-            ObjectType::InputPort => {
-                let port: &InputPort = unsafe { mem::transmute(header) };
-                std::mem::size_of_val(port)
-            }
-            ObjectType::Vox => {
-                let vox: &Vox = unsafe { mem::transmute(header) };
-                std::mem::size_of_val(vox)
-            }
-            ObjectType::Pair => {
-                let pair: &Pair = unsafe { mem::transmute(header) };
-                std::mem::size_of_val(pair)
-            }
-            ObjectType::SimpleStruct => {
-                let s: &SimpleStruct = unsafe { mem::transmute(header) };
-                std::mem::size_of_val(s)
-            }
-            ObjectType::Vector => {
-                let v: &Vector = unsafe { mem::transmute(header) };
-                std::mem::size_of_val(v)
-            }
-            ObjectType::EqHashtable => {
-                let hashtable: &EqHashtable = unsafe { mem::transmute(header) };
-                std::mem::size_of_val(hashtable)
+    fn free(&mut self, header: NonNull<GcHeader>) {
+        let object_type = unsafe { header.as_ref().obj_type };
+        let is_old = unsafe { header.as_ref().old };
+        self.finalize(header);
+        Gc::bury(header);
+        let free_size = unsafe {
+            match object_type {
+                ObjectType::Closure => self.drop_object::<Closure>(header),
+                ObjectType::EqHashtable => self.drop_object::<EqHashtable>(header),
+                ObjectType::Guardian => self.drop_object::<Guardian>(header),
+                ObjectType::InputPort => self.drop_object::<InputPort>(header),
+                ObjectType::Pair => self.drop_object::<Pair>(header),
+                ObjectType::Procedure => self.free_procedure(header),
+                ObjectType::SimpleStruct => self.drop_object::<SimpleStruct>(header),
+                ObjectType::String => self.drop_object::<SString>(header),
+                ObjectType::Symbol => self.drop_object::<Symbol>(header),
+                ObjectType::Vector => self.drop_object::<Vector>(header),
+                ObjectType::Vox => self.drop_object::<Vox>(header),
+                ObjectType::WeakBox => self.drop_object::<WeakBox>(header),
             }
         };
         #[cfg(feature = "debug_log_gc")]
         println!(
             "free(adr:{:?}) type={:?} size={} ******* ",
-            object_ptr as *mut GcHeader, object_type, free_size
+            header, object_type, free_size
         );
+        self.count_free(object_type, is_old, free_size);
+    }
 
+    fn finalize(&self, header: NonNull<GcHeader>) {
+        let object_type = unsafe { header.as_ref().obj_type };
+        if let Some(&(_, finalizer)) = self.finalizers.iter().find(|(t, _)| *t == object_type) {
+            finalizer(Gc::object_of(header));
+        }
+    }
+
+    fn count_free(&mut self, object_type: ObjectType, is_old: bool, free_size: usize) {
         self.current_alloc_size -= free_size;
-        unsafe { drop(Box::from_raw(object_ptr)) }
+        self.live_bytes[object_type as usize] -= free_size;
+        self.live_objects[object_type as usize] -= 1;
+        if !is_old {
+            self.nursery_size -= free_size;
+        }
     }
 
-    #[cfg(not(feature = "test_gc_size"))]
-    fn free(&self, object_ptr: &mut GcHeader) {
-        unsafe { drop(Box::from_raw(object_ptr)) }
+    // Sweep the objects in pages. They need no drop, so freeing them only releases their slots.
+    // A minor collection sweeps only the pages which have young objects and promotes the survivors.
+    fn sweep_pages(&mut self) {
+        let mut pages = match self.pages.take() {
+            Some(pages) => pages,
+            None => return,
+        };
+        let is_major = self.collection == Collection::Major;
+        pages.sweep(is_major, |mut object| unsafe {
+            let header = object.as_mut();
+            if header.marked {
+                header.marked = false;
+                header.old = true;
+                true
+            } else if header.old && !is_major {
+                // A minor collection doesn't mark old objects.
+                true
+            } else {
+                self.finalize(object);
+                let object_type = header.obj_type;
+                let size = Gc::object_size(object_type);
+                self.count_free(object_type, header.old, size);
+                Gc::bury(object);
+                Gc::poison(object.cast(), size);
+                false
+            }
+        });
+        self.pages = Some(pages);
+    }
+
+    fn object_of(header: NonNull<GcHeader>) -> Object {
+        match unsafe { header.as_ref().obj_type } {
+            ObjectType::Closure => Object::Closure(Gc::gc_ref(header)),
+            ObjectType::EqHashtable => Object::EqHashtable(Gc::gc_ref(header)),
+            ObjectType::Guardian => Object::Guardian(Gc::gc_ref(header)),
+            ObjectType::InputPort => Object::InputPort(Gc::gc_ref(header)),
+            ObjectType::Pair => Object::Pair(Gc::gc_ref(header)),
+            ObjectType::Procedure => Object::Procedure(Gc::gc_ref(header)),
+            ObjectType::SimpleStruct => Object::SimpleStruct(Gc::gc_ref(header)),
+            ObjectType::String => Object::String(Gc::gc_ref(header)),
+            ObjectType::Symbol => Object::Symbol(Gc::gc_ref(header)),
+            ObjectType::Vector => Object::Vector(Gc::gc_ref(header)),
+            ObjectType::Vox => Object::Vox(Gc::gc_ref(header)),
+            ObjectType::WeakBox => Object::WeakBox(Gc::gc_ref(header)),
+        }
+    }
+
+    fn gc_ref<T>(header: NonNull<GcHeader>) -> GcRef<T> {
+        GcRef::new(header.cast())
+    }
+
+    // Drop the object of header as T and return its size.
+    unsafe fn drop_object<T>(&mut self, header: NonNull<GcHeader>) -> usize {
+        ptr::drop_in_place(header.as_ptr() as *mut T);
+        Gc::poison(header.cast(), mem::size_of::<T>());
+        self.dealloc(header.cast(), Layout::new::<T>());
+        mem::size_of::<T>()
+    }
+
+    // Memory for a boxed object.
+    #[cfg(not(debug_assertions))]
+    fn alloc_block(&mut self, layout: Layout) -> NonNull<u8> {
+        Gc::alloc_new_block(layout)
+    }
+
+    #[cfg(debug_assertions)]
+    fn alloc_block(&mut self, layout: Layout) -> NonNull<u8> {
+        let blocks = self.free_blocks.entry(layout).or_default();
+        blocks.pop().unwrap_or_else(|| Gc::alloc_new_block(layout))
+    }
+
+    fn alloc_new_block(layout: Layout) -> NonNull<u8> {
+        match NonNull::new(unsafe { alloc::alloc(layout) }) {
+            Some(block) => block,
+            None => alloc::handle_alloc_error(layout),
+        }
+    }
+
+    #[cfg(not(feature = "gc_stress"))]
+    fn dealloc(&mut self, object: NonNull<u8>, layout: Layout) {
+        self.release_block(object, layout);
+    }
+
+    // Keep the poisoned memory from the allocator until the next collection has marked.
+    #[cfg(feature = "gc_stress")]
+    fn dealloc(&mut self, object: NonNull<u8>, layout: Layout) {
+        self.quarantine.push((object, layout));
     }
 
-    fn sweep(&mut self) {
-        let mut previous: Option<NonNull<GcHeader>> = None;
-        let mut current: Option<NonNull<GcHeader>> = self.first;
+    #[cfg(feature = "gc_stress")]
+    fn release_quarantine(&mut self) {
+        for (object, layout) in mem::take(&mut self.quarantine) {
+            self.release_block(object, layout);
+        }
+    }
+
+    #[cfg(not(debug_assertions))]
+    fn release_block(&mut self, object: NonNull<u8>, layout: Layout) {
+        unsafe { alloc::dealloc(object.as_ptr(), layout) }
+    }
+
+    // Keep the memory for the next objects of the layout, so that GcRefs of the freed object can check its generation.
+    #[cfg(debug_assertions)]
+    fn release_block(&mut self, object: NonNull<u8>, layout: Layout) {
+        self.free_blocks.entry(layout).or_default().push(object);
+    }
+
+    // Give a new object its generation.
+    #[cfg(debug_assertions)]
+    fn stamp(mut header: NonNull<GcHeader>) {
+        let generation = NEXT_GENERATION.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        unsafe { header.as_mut().generation = generation }
+    }
+
+    #[cfg(not(debug_assertions))]
+    fn stamp(_header: NonNull<GcHeader>) {}
+
+    // Make GcRefs of the object invalid.
+    #[cfg(debug_assertions)]
+    fn bury(mut header: NonNull<GcHeader>) {
+        unsafe { header.as_mut().generation = 0 }
+    }
+
+    #[cfg(not(debug_assertions))]
+    fn bury(_header: NonNull<GcHeader>) {}
+
+    #[cfg(feature = "gc_stress")]
+    fn poison(object: NonNull<u8>, size: usize) {
+        unsafe { ptr::write_bytes(object.as_ptr(), POISON, size) }
+    }
+
+    #[cfg(not(feature = "gc_stress"))]
+    fn poison(_object: NonNull<u8>, _size: usize) {}
+
+    // The allocator may reuse the first word of freed memory, so look at the next one.
+    #[cfg(feature = "gc_stress")]
+    fn is_poisoned(header: NonNull<GcHeader>) -> bool {
+        unsafe {
+            let next = header
+                .cast::<u8>()
+                .as_ptr()
+                .add(mem::offset_of!(GcHeader, next));
+            (next as *const [u8; 8]).read_unaligned() == [POISON; 8]
+        }
+    }
+
+    // Free unmarked objects of list and return the list of the others, unmarked for the next collection.
+    fn sweep(&mut self, list: Option<NonNull<GcHeader>>) -> Option<NonNull<GcHeader>> {
+        let mut survivors = None;
+        let mut current = list;
         while let Some(mut object) = current {
             unsafe {
-                let object_ptr = object.as_mut();
-                current = object_ptr.next;
-                if object_ptr.marked {
-                    object_ptr.marked = false;
-                    previous = Some(object);
+                let header = object.as_mut();
+                current = header.next;
+                if header.marked {
+                    header.marked = false;
+                    header.next = survivors;
+                    survivors = Some(object);
                 } else {
-                    if let Some(mut previous) = previous {
-                        previous.as_mut().next = object_ptr.next
-                    } else {
-                        self.first = object_ptr.next
-                    }
-                    self.free(object_ptr);
+                    self.free(object);
                 }
             }
         }
+        survivors
+    }
+
+    // Move the survivors of the nursery to the old generation.
+    fn promote(&mut self, survivors: Option<NonNull<GcHeader>>) {
+        let mut current = survivors;
+        while let Some(mut object) = current {
+            unsafe {
+                let header = object.as_mut();
+                current = header.next;
+                header.old = true;
+                header.next = self.old.take();
+                self.old = Some(object);
+            }
+        }
+        self.nursery_size = 0;
     }
 }
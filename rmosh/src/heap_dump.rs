@@ -0,0 +1,277 @@
+/// Heap dump.
+/// Vm::heap_dump collects the whole heap and enumerates the objects reachable from the roots of Vm::for_each_root.
+/// Each object is found breadth first, so its retainers lead to a root by a shortest path.
+/// Immortal objects such as the compiler code are shared and never freed, so they are skipped.
+///
+/// HeapDump::write_json writes it as a JSON object:
+///
+///   {
+///     "heap_size": 125368,
+///     "roots": [{"root": "global foo", "objects": 3, "bytes": 216}, ...],
+///     "types": [{"type": "pair", "objects": 1024, "bytes": 73728}, ...],
+///     "objects": [
+///       {"id": 0, "type": "vector", "size": 48, "root": "global foo"},
+///       {"id": 1, "type": "pair", "size": 72, "retainer": 0},
+///       {"id": 2, "type": "symbol", "size": 48, "retainer": 1, "value": "bar"},
+///       ...
+///     ]
+///   }
+///
+/// heap_size is the bytes of the whole heap and "objects" are the live ones.
+/// An object refers the root which keeps it alive or the id of its retainer, which comes before it.
+/// Symbols and strings have their "value".
+/// "roots" has the objects and bytes kept alive by each root, largest first, to find what leaks.
+/// "types" has the totals per type, largest first.
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+};
+
+use crate::{
+    gc::{Gc, ObjectType},
+    objects::Object,
+};
+
+// What keeps an object alive.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Retainer {
+    // Name of a root, for example "global foo" or "stack 3".
+    Root(String),
+    // Index of the object which refers it in HeapDump::objects.
+    Object(usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct HeapObject {
+    pub address: usize,
+    pub obj_type: ObjectType,
+    pub size: usize,
+    pub retainer: Retainer,
+    // Name of symbols and contents of strings.
+    pub value: Option<String>,
+}
+
+pub struct HeapDump {
+    pub heap_size: usize,
+    // Live objects in the order they were found.
+    pub objects: Vec<HeapObject>,
+}
+
+impl HeapDump {
+    // roots are the roots the Gc marks, with their names.
+    pub fn new(roots: &[(String, Object)], heap_size: usize) -> Self {
+        let mut objects = vec![];
+        let mut ids: HashMap<Object, usize> = HashMap::new();
+        let mut queue = vec![];
+        for (name, obj) in roots {
+            HeapDump::add(&mut objects, &mut ids, &mut queue, *obj, || {
+                Retainer::Root(name.to_string())
+            });
+        }
+        let mut next = 0;
+        while next < queue.len() {
+            let obj = queue[next];
+            let id = ids[&obj];
+            for child in Gc::references(obj) {
+                HeapDump::add(&mut objects, &mut ids, &mut queue, child, || {
+                    Retainer::Object(id)
+                });
+            }
+            next += 1;
+        }
+        HeapDump { heap_size, objects }
+    }
+
+    fn add(
+        objects: &mut Vec<HeapObject>,
+        ids: &mut HashMap<Object, usize>,
+        queue: &mut Vec<Object>,
+        obj: Object,
+        retainer: impl FnOnce() -> Retainer,
+    ) {
+        let obj_type = match Gc::object_type(obj) {
+            Some(obj_type) if !Gc::is_immortal(obj) => obj_type,
+            _ => return,
+        };
+        if ids.contains_key(&obj) {
+            return;
+        }
+        let value = match obj {
+            Object::Symbol(symbol) => Some(symbol.string.to_owned()),
+            Object::String(s) => Some(s.string.to_owned()),
+            _ => None,
+        };
+        ids.insert(obj, objects.len());
+        queue.push(obj);
+        objects.push(HeapObject {
+            address: HeapDump::address(obj),
+            obj_type,
+            size: Gc::object_size(obj_type),
+            retainer: retainer(),
+            value,
+        });
+    }
+
+    fn address(obj: Object) -> usize {
+        match obj {
+            Object::Closure(r) => r.pointer.as_ptr() as usize,
+            Object::EqHashtable(r) => r.pointer.as_ptr() as usize,
+            Object::Guardian(r) => r.pointer.as_ptr() as usize,
+            Object::InputPort(r) => r.pointer.as_ptr() as usize,
+            Object::Pair(r) => r.pointer.as_ptr() as usize,
+            Object::Procedure(r) => r.pointer.as_ptr() as usize,
+            Object::SimpleStruct(r) => r.pointer.as_ptr() as usize,
+            Object::String(r) => r.pointer.as_ptr() as usize,
+            Object::Symbol(r) => r.pointer.as_ptr() as usize,
+            Object::Vector(r) => r.pointer.as_ptr() as usize,
+            Object::Vox(r) => r.pointer.as_ptr() as usize,
+            Object::WeakBox(r) => r.pointer.as_ptr() as usize,
+            _ => 0,
+        }
+    }
+
+    // Index of obj in objects if it is live.
+    pub fn id_of(&self, obj: Object) -> Option<usize> {
+        let address = HeapDump::address(obj);
+        self.objects.iter().position(|o| o.address == address)
+    }
+
+    // Ids from the object kept by a root to id, and the name of the root.
+    pub fn path(&self, id: usize) -> (String, Vec<usize>) {
+        let mut ids = vec![id];
+        let mut current = id;
+        loop {
+            match &self.objects[current].retainer {
+                Retainer::Root(name) => {
+                    ids.reverse();
+                    return (name.to_string(), ids);
+                }
+                Retainer::Object(retainer) => {
+                    current = *retainer;
+                    ids.push(current);
+                }
+            }
+        }
+    }
+
+    // Objects and bytes kept alive by each root, largest first.
+    pub fn root_totals(&self) -> Vec<(String, usize, usize)> {
+        let mut root_of: Vec<usize> = Vec::with_capacity(self.objects.len());
+        let mut totals: Vec<(String, usize, usize)> = vec![];
+        let mut indexes: HashMap<&str, usize> = HashMap::new();
+        for object in &self.objects {
+            // Retainers come first, so their roots are known.
+            let index = match &object.retainer {
+                Retainer::Root(name) => *indexes.entry(name).or_insert_with(|| {
+                    totals.push((name.to_string(), 0, 0));
+                    totals.len() - 1
+                }),
+                Retainer::Object(retainer) => root_of[*retainer],
+            };
+            root_of.push(index);
+            totals[index].1 += 1;
+            totals[index].2 += object.size;
+        }
+        totals.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+        totals
+    }
+
+    // Objects and bytes per type, largest first.
+    pub fn type_totals(&self) -> Vec<(ObjectType, usize, usize)> {
+        let mut totals: Vec<(ObjectType, usize, usize)> =
+            ObjectType::ALL.iter().map(|&t| (t, 0, 0)).collect();
+        for object in &self.objects {
+            let total = &mut totals[object.obj_type as usize];
+            total.1 += 1;
+            total.2 += object.size;
+        }
+        totals.retain(|total| total.1 > 0);
+        totals.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.name().cmp(b.0.name())));
+        totals
+    }
+
+    pub fn write_json(&self, w: &mut dyn Write) -> io::Result<()> {
+        writeln!(w, "{{")?;
+        writeln!(w, "  \"heap_size\": {},", self.heap_size)?;
+        let roots: Vec<String> = self
+            .root_totals()
+            .iter()
+            .map(|(name, objects, bytes)| {
+                format!(
+                    "{{\"root\": {}, \"objects\": {}, \"bytes\": {}}}",
+                    json_string(name),
+                    objects,
+                    bytes
+                )
+            })
+            .collect();
+        write_json_array(w, "roots", &roots, ",")?;
+        let types: Vec<String> = self
+            .type_totals()
+            .iter()
+            .map(|(obj_type, objects, bytes)| {
+                format!(
+                    "{{\"type\": \"{}\", \"objects\": {}, \"bytes\": {}}}",
+                    obj_type.name(),
+                    objects,
+                    bytes
+                )
+            })
+            .collect();
+        write_json_array(w, "types", &types, ",")?;
+        let objects: Vec<String> = self
+            .objects
+            .iter()
+            .enumerate()
+            .map(|(id, object)| {
+                let retainer = match &object.retainer {
+                    Retainer::Root(name) => format!("\"root\": {}", json_string(name)),
+                    Retainer::Object(retainer) => format!("\"retainer\": {}", retainer),
+                };
+                let value = match &object.value {
+                    Some(value) => format!(", \"value\": {}", json_string(value)),
+                    None => "".to_string(),
+                };
+                format!(
+                    "{{\"id\": {}, \"type\": \"{}\", \"size\": {}, {}{}}}",
+                    id,
+                    object.obj_type.name(),
+                    object.size,
+                    retainer,
+                    value
+                )
+            })
+            .collect();
+        write_json_array(w, "objects", &objects, "")?;
+        writeln!(w, "}}")
+    }
+}
+
+fn write_json_array(w: &mut dyn Write, key: &str, items: &[String], end: &str) -> io::Result<()> {
+    if items.is_empty() {
+        return writeln!(w, "  \"{}\": []{}", key, end);
+    }
+    writeln!(w, "  \"{}\": [", key)?;
+    for (i, item) in items.iter().enumerate() {
+        let comma = if i + 1 < items.len() { "," } else { "" };
+        writeln!(w, "    {}{}", item, comma)?;
+    }
+    writeln!(w, "  ]{}", end)
+}
+
+fn json_string(s: &str) -> String {
+    let mut ret = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            '\r' => ret.push_str("\\r"),
+            '\t' => ret.push_str("\\t"),
+            c if (c as u32) < 0x20 => ret.push_str(&format!("\\u{:04x}", c as u32)),
+            c => ret.push(c),
+        }
+    }
+    ret.push('"');
+    ret
+}
@@ -1,14 +1,25 @@
 #[derive(Clone, Debug, PartialEq)]
 pub enum Token {
     Character { value: char },
+    DatumComment,
     Dot,
     False,
     Identifier { value: String },
+    LeftBracket,
     LeftParen,
     Number10 { value: String },
+    Quasiquote,
+    Quasisyntax,
+    Quote,
+    RightBracket,
     RightParen,
     String { value: String },
+    Syntax,
     True,
+    Unquote,
+    UnquoteSplicing,
+    Unsyntax,
+    UnsyntaxSplicing,
     VectorStart,
 }
 
@@ -75,6 +86,29 @@ impl<'input> Lexer<'input> {
         }
     }
 
+    // Skip a #| ... |# comment after its #|. Block comments nest.
+    pub fn skip_block_comment(&mut self) {
+        let mut depth = 1;
+        while self.cursor + 1 < self.limit {
+            match (self.s[self.cursor], self.s[self.cursor + 1]) {
+                (b'|', b'#') => {
+                    self.cursor += 2;
+                    depth -= 1;
+                    if depth == 0 {
+                        return;
+                    }
+                }
+                (b'#', b'|') => {
+                    self.cursor += 2;
+                    depth += 1;
+                }
+                _ => self.cursor += 1,
+            }
+        }
+        // Unterminated comment runs to the end of the input.
+        self.cursor = self.limit;
+    }
+
     pub fn extract_string(&self) -> String {
         // Remove double quotes.
         match std::str::from_utf8(&self.s[self.tok + 1..self.cursor - 1]) {
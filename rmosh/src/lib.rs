@@ -1,15 +1,28 @@
 pub mod alloc;
+pub mod backtrace;
+pub mod compile_cache;
 pub mod compiler;
+pub mod debugger;
+pub mod disasm;
 pub mod equal;
 pub mod fasl;
 pub mod gc;
+pub mod heap_dump;
 pub mod objects;
 pub mod op;
+pub mod pages;
 pub mod procs;
+#[cfg(feature = "profiler")]
+pub mod profiler;
 pub mod vm;
 pub mod lexer_iter;
 pub mod lexer;
+pub mod library;
 pub mod read;
+pub mod repl;
+pub mod snapshot;
+pub mod trace;
+pub mod verify;
 #[macro_use] extern crate lalrpop_util;
 
 lalrpop_mod!(pub reader); // synthesized by LALRPOP
\ No newline at end of file
@@ -0,0 +1,632 @@
+/// R6RS library system.
+/// This is a native replacement of psyntax's library manager.
+/// Libraries are located through the load path and loaded once per Vm.
+/// There is no expander, so all libraries share the global environment.
+/// A library's definitions become globals, and import doesn't hide anything.
+/// So a library which defines a name like format replaces it for the program and the other libraries,
+/// and two libraries which define the same name overwrite each other.
+/// Libraries in lib/ which define macros, like (srfi :1) or (mosh test), or read flonums, like (rbtree), don't load yet.
+/// prefix and rename in import sets define aliases of the imported values.
+/// R7RS define-library and cond-expand are handled here as well.
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    gc::GcRef,
+    objects::{Object, Symbol},
+    read::read_all,
+    vm::Vm,
+};
+
+// Extensions tried in order when we look for a library file.
+const LIBRARY_EXTENSIONS: [&str; 6] = [".rmosh.sls", ".mosh.sls", ".sld", ".sls", ".ss", ".scm"];
+
+// Libraries whose bindings are built into the VM. Every library under rnrs and scheme is, like (rnrs lists (6)) or (scheme base).
+// Other libraries, like (mosh control) or (srfi :1), are loaded from the load path.
+const BUILTIN_LIBRARY_PREFIXES: [&str; 2] = ["rnrs", "scheme"];
+const BUILTIN_LIBRARIES: [&str; 3] = ["(mosh)", "(rmosh)", "(system)"];
+
+// Feature identifiers the Vm starts with. See Vm::features.
+pub fn default_features() -> Vec<String> {
+    let mut features = vec![
+        "r7rs",
+        "exact-closed",
+        "ratios",
+        "full-unicode",
+        "mosh",
+        "rmosh",
+    ];
+    if cfg!(target_os = "linux") {
+        features.push("linux");
+    } else if cfg!(target_os = "macos") {
+        features.push("darwin");
+    } else if cfg!(target_os = "windows") {
+        features.push("windows");
+    }
+    features.into_iter().map(String::from).collect()
+}
+
+#[derive(Default)]
+pub struct Libraries {
+    libraries: HashMap<String, Library>,
+    // Library files being loaded. include in define-library is relative to the last one.
+    loading_files: Vec<PathBuf>,
+}
+
+struct Library {
+    // Exported (internal, external) names.
+    exports: Vec<(GcRef<Symbol>, GcRef<Symbol>)>,
+    // False while the library is being loaded, so that we can detect circular imports.
+    is_loaded: bool,
+}
+
+// Bindings an import set makes visible as (internal, visible) names.
+struct Imports {
+    // Every global is visible under its own name in addition to names. True for builtin libraries.
+    all: bool,
+    names: Vec<(GcRef<Symbol>, GcRef<Symbol>)>,
+}
+
+impl Libraries {
+    // Forget libraries whose loading was aborted by an error, so that they can be imported again.
+    pub fn forget_incomplete(&mut self) {
+        self.libraries.retain(|_, library| library.is_loaded);
+        self.loading_files.clear();
+    }
+
+    // Symbols the libraries refer, which the Gc must keep.
+    pub fn symbols(&self) -> impl Iterator<Item = GcRef<Symbol>> + '_ {
+        self.libraries
+            .values()
+            .flat_map(|library| library.exports.iter())
+            .flat_map(|&(internal, external)| [internal, external])
+    }
+}
+
+impl Vm {
+    // Handle library, define-library, import and top-level cond-expand forms. Returns None for other forms.
+    pub(crate) fn eval_library_form(&mut self, sexp: Object) -> Option<Object> {
+        let keyword = match sexp {
+            Object::Pair(pair) => match pair.car {
+                Object::Symbol(symbol) => symbol.string.to_owned(),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        // The form is not on the VM stack while we evaluate other forms.
+        self.push_root(sexp);
+        let ret = match keyword.as_str() {
+            "library" => self.define_library(sexp),
+            "define-library" => self.define_r7rs_library(sexp),
+            // Top-level cond-expand can import and define, so we eval the forms one by one.
+            "cond-expand" => {
+                let mut ret = Object::Unspecified;
+                for form in self.cond_expand_body(sexp) {
+                    ret = self.eval(form);
+                }
+                ret
+            }
+            "import" => {
+                for spec in list_to_vec("import", sexp.to_pair().cdr) {
+                    self.import(spec);
+                }
+                Object::Unspecified
+            }
+            _ => {
+                self.pop_root();
+                return None;
+            }
+        };
+        self.pop_root();
+        Some(ret)
+    }
+
+    // (library name (export spec ...) (import spec ...) body ...)
+    fn define_library(&mut self, sexp: Object) -> Object {
+        let form = list_to_vec("library", sexp);
+        if form.len() < 4 {
+            panic!("library: malformed library {}", sexp);
+        }
+        let name = library_name(form[1]);
+        let exports = match list_to_vec("library", form[2]).split_first() {
+            Some((Object::Symbol(symbol), specs)) if symbol.string == "export" => {
+                export_names(specs)
+            }
+            _ => panic!("library: export clause required but got {}", form[2]),
+        };
+        let imports = match list_to_vec("library", form[3]).split_first() {
+            Some((Object::Symbol(symbol), specs)) if symbol.string == "import" => specs.to_vec(),
+            _ => panic!("library: import clause required but got {}", form[3]),
+        };
+        let key = library_key(&name);
+        self.libraries.libraries.insert(
+            key.to_owned(),
+            Library {
+                exports,
+                is_loaded: false,
+            },
+        );
+        for spec in imports {
+            self.import(spec);
+        }
+        for &body in &form[4..] {
+            self.eval(body);
+        }
+        if let Some(library) = self.libraries.libraries.get_mut(&key) {
+            library.is_loaded = true;
+        }
+        Object::Unspecified
+    }
+
+    fn import(&mut self, spec: Object) {
+        let imports = self.import_set(spec);
+        for (internal, visible) in imports.names {
+            if internal == visible {
+                continue;
+            }
+            let value = match self.global_value(internal) {
+                Some(value) => value,
+                // Builtin procedures are free variables, so we ask the compiler.
+                None => self.eval(Object::Symbol(internal)),
+            };
+            self.set_symbol_value(visible, value);
+        }
+    }
+
+    fn import_set(&mut self, spec: Object) -> Imports {
+        let form = list_to_vec("import", spec);
+        let keyword = match form.first() {
+            Some(Object::Symbol(symbol)) => symbol.string.as_str(),
+            _ => panic!("import: malformed import set {}", spec),
+        };
+        match (keyword, form.len()) {
+            ("only", n) if n >= 2 => {
+                let imports = self.import_set(form[1]);
+                let names = symbols("only", &form[2..])
+                    .into_iter()
+                    .map(|name| (visible_binding(&imports, name, spec), name))
+                    .collect();
+                Imports { all: false, names }
+            }
+            ("except", n) if n >= 2 => {
+                let mut imports = self.import_set(form[1]);
+                for name in symbols("except", &form[2..]) {
+                    visible_binding(&imports, name, spec);
+                    imports.names.retain(|&(_, visible)| visible != name);
+                }
+                imports
+            }
+            ("prefix", 3) => {
+                let mut imports = self.import_set(form[1]);
+                if imports.all {
+                    panic!(
+                        "import: prefix of builtin library is not supported {}",
+                        spec
+                    );
+                }
+                let prefix = symbols("prefix", &form[2..3])[0];
+                for binding in imports.names.iter_mut() {
+                    let name = format!("{}{}", prefix.string, binding.1.string);
+                    binding.1 = self.intern(&name);
+                }
+                imports
+            }
+            ("rename", n) if n >= 2 => {
+                let mut imports = self.import_set(form[1]);
+                for &rename in &form[2..] {
+                    let names = symbols("rename", &list_to_vec("rename", rename));
+                    if names.len() != 2 {
+                        panic!("import: malformed rename {}", rename);
+                    }
+                    let internal = visible_binding(&imports, names[0], spec);
+                    imports.names.retain(|&(_, visible)| visible != names[0]);
+                    imports.names.push((internal, names[1]));
+                }
+                imports
+            }
+            // We have only one phase.
+            ("for", n) if n >= 2 => self.import_set(form[1]),
+            ("library", 2) => self.import_library(form[1]),
+            _ => self.import_library(spec),
+        }
+    }
+
+    fn import_library(&mut self, name: Object) -> Imports {
+        let name = library_name(name);
+        if is_builtin_library(&name) {
+            return Imports {
+                all: true,
+                names: vec![],
+            };
+        }
+        let key = library_key(&name);
+        match self.libraries.libraries.get(&key) {
+            Some(library) if library.is_loaded => {}
+            Some(_) => panic!("import: circular import of library {}", key),
+            None => {
+                let path = match self.locate_library(&name) {
+                    Some(path) => path,
+                    None => panic!(
+                        "import: cannot locate library {} in load path {:?}",
+                        key, self.load_path
+                    ),
+                };
+                self.libraries.loading_files.push(PathBuf::from(&path));
+                self.load_file(&path);
+                self.libraries.loading_files.pop();
+                if !self.libraries.libraries.contains_key(&key) {
+                    panic!("import: {} doesn't define library {}", path, key);
+                }
+            }
+        }
+        let exports = &self.libraries.libraries[&key].exports;
+        Imports {
+            all: false,
+            names: exports.clone(),
+        }
+    }
+
+    // (define-library name declaration ...)
+    fn define_r7rs_library(&mut self, sexp: Object) -> Object {
+        let form = list_to_vec("define-library", sexp);
+        if form.len() < 2 {
+            panic!("define-library: malformed library {}", sexp);
+        }
+        let key = library_key(&library_name(form[1]));
+        self.libraries.libraries.insert(
+            key.to_owned(),
+            Library {
+                exports: vec![],
+                is_loaded: false,
+            },
+        );
+        for &declaration in &form[2..] {
+            self.library_declaration(&key, declaration);
+        }
+        if let Some(library) = self.libraries.libraries.get_mut(&key) {
+            library.is_loaded = true;
+        }
+        Object::Unspecified
+    }
+
+    fn library_declaration(&mut self, key: &str, declaration: Object) {
+        let form = list_to_vec("define-library", declaration);
+        let keyword = match form.first() {
+            Some(Object::Symbol(symbol)) => symbol.string.as_str(),
+            _ => panic!("define-library: malformed declaration {}", declaration),
+        };
+        match keyword {
+            "export" => {
+                let exports = r7rs_export_names(&form[1..]);
+                if let Some(library) = self.libraries.libraries.get_mut(key) {
+                    library.exports.extend(exports);
+                }
+            }
+            "import" => {
+                for &spec in &form[1..] {
+                    self.import(spec);
+                }
+            }
+            "begin" => {
+                for &body in &form[1..] {
+                    self.eval(body);
+                }
+            }
+            "include" | "include-ci" => {
+                for path in strings(keyword, &form[1..]) {
+                    let forms = self.read_included_file(&path, keyword == "include-ci");
+                    // Evaluating the forms can collect them.
+                    self.push_root(forms);
+                    for body in list_to_vec(keyword, forms) {
+                        self.eval(body);
+                    }
+                    self.pop_root();
+                }
+            }
+            "include-library-declarations" => {
+                for path in strings(keyword, &form[1..]) {
+                    let forms = self.read_included_file(&path, false);
+                    self.push_root(forms);
+                    for declaration in list_to_vec(keyword, forms) {
+                        self.library_declaration(key, declaration);
+                    }
+                    self.pop_root();
+                }
+            }
+            "cond-expand" => {
+                for declaration in self.cond_expand_body(declaration) {
+                    self.library_declaration(key, declaration);
+                }
+            }
+            _ => panic!("define-library: unknown declaration {}", declaration),
+        }
+    }
+
+    // List of all forms in an included file. Relative paths are relative to the library file.
+    fn read_included_file(&mut self, path: &str, fold_case: bool) -> Object {
+        let path = match self.libraries.loading_files.last() {
+            Some(library_file) if Path::new(path).is_relative() => match library_file.parent() {
+                Some(dir) => dir.join(path),
+                None => PathBuf::from(path),
+            },
+            _ => PathBuf::from(path),
+        };
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) => panic!("include: can't read {}: {}", path.display(), err),
+        };
+        let forms = match read_all(&mut self.gc, &text) {
+            Ok(forms) => forms,
+            Err(err) => panic!("include: can't read {}: {:?}", path.display(), err),
+        };
+        if fold_case {
+            self.fold_case(forms)
+        } else {
+            forms
+        }
+    }
+
+    // Copy of obj with all symbols downcased, as if it was read in #!fold-case mode.
+    fn fold_case(&mut self, obj: Object) -> Object {
+        match obj {
+            Object::Symbol(symbol) => {
+                let name = symbol.string.to_lowercase();
+                Object::Symbol(self.intern(&name))
+            }
+            Object::Pair(pair) => {
+                let car = self.fold_case(pair.car);
+                let cdr = self.fold_case(pair.cdr);
+                self.gc.cons(car, cdr)
+            }
+            Object::Vector(vector) => {
+                let data: Vec<Object> = vector.data.iter().map(|&e| self.fold_case(e)).collect();
+                self.gc.new_vector(&data)
+            }
+            _ => obj,
+        }
+    }
+
+    // Forms of the first cond-expand clause whose requirement is met.
+    fn cond_expand_body(&mut self, sexp: Object) -> Vec<Object> {
+        for clause in list_to_vec("cond-expand", sexp).into_iter().skip(1) {
+            let clause = list_to_vec("cond-expand", clause);
+            let requirement = match clause.first() {
+                Some(&requirement) => requirement,
+                None => panic!("cond-expand: malformed clause in {}", sexp),
+            };
+            if self.is_feature_requirement_met(requirement) {
+                return clause[1..].to_vec();
+            }
+        }
+        vec![]
+    }
+
+    // Rewrite cond-expand in sexp to begin of the matched clause, so that the compiler can handle it.
+    // Only sexp and the elements of lists are forms, so cond-expand in the tail of a list is left alone.
+    pub(crate) fn expand_cond_expand(&mut self, sexp: Object) -> Object {
+        let pair = match sexp {
+            Object::Pair(pair) => pair,
+            _ => return sexp,
+        };
+        match pair.car {
+            Object::Symbol(symbol) if symbol.string == "quote" || symbol.string == "quasiquote" => {
+                return sexp;
+            }
+            Object::Symbol(symbol) if symbol.string == "cond-expand" => {
+                let body = self.cond_expand_body(sexp);
+                if body.is_empty() {
+                    let if_symbol = self.gc.symbol_intern("if");
+                    return self.gc.list3(if_symbol, Object::False, Object::False);
+                }
+                let begin = self.gc.symbol_intern("begin");
+                let body = self.gc.listn(&body);
+                let expanded = self.gc.cons(begin, body);
+                return self.expand_cond_expand(expanded);
+            }
+            _ => {}
+        }
+        let mut elements = vec![];
+        let mut changed = false;
+        let mut tail = sexp;
+        while let Object::Pair(pair) = tail {
+            let element = self.expand_cond_expand(pair.car);
+            changed |= element != pair.car;
+            elements.push(element);
+            tail = pair.cdr;
+        }
+        if !changed {
+            return sexp;
+        }
+        for &element in elements.iter().rev() {
+            tail = self.gc.cons(element, tail);
+        }
+        tail
+    }
+
+    fn is_feature_requirement_met(&mut self, requirement: Object) -> bool {
+        match requirement {
+            Object::Symbol(symbol) => {
+                symbol.string == "else" || self.features.iter().any(|f| *f == symbol.string)
+            }
+            Object::Pair(pair) => {
+                let args = list_to_vec("cond-expand", pair.cdr);
+                let keyword = match pair.car {
+                    Object::Symbol(symbol) => symbol.string.to_owned(),
+                    _ => panic!("cond-expand: malformed requirement {}", requirement),
+                };
+                match (keyword.as_str(), args.len()) {
+                    ("and", _) => args.iter().all(|&req| self.is_feature_requirement_met(req)),
+                    ("or", _) => args.iter().any(|&req| self.is_feature_requirement_met(req)),
+                    ("not", 1) => !self.is_feature_requirement_met(args[0]),
+                    ("library", 1) => self.is_library_available(args[0]),
+                    _ => panic!("cond-expand: malformed requirement {}", requirement),
+                }
+            }
+            _ => panic!("cond-expand: malformed requirement {}", requirement),
+        }
+    }
+
+    fn is_library_available(&self, name: Object) -> bool {
+        let name = library_name(name);
+        is_builtin_library(&name)
+            || self.libraries.libraries.contains_key(&library_key(&name))
+            || self.locate_library(&name).is_some()
+    }
+
+    fn locate_library(&self, name: &[String]) -> Option<String> {
+        let file_name = library_file_name(name);
+        let current = ".".to_string();
+        for dir in self.load_path.iter().chain(std::iter::once(&current)) {
+            for extension in LIBRARY_EXTENSIONS {
+                let path = format!("{}{}{}", dir.trim_end_matches('/'), file_name, extension);
+                if Path::new(&path).is_file() {
+                    return Some(path);
+                }
+            }
+        }
+        None
+    }
+}
+
+// The internal name of the visible name in imports.
+fn visible_binding(imports: &Imports, name: GcRef<Symbol>, spec: Object) -> GcRef<Symbol> {
+    match imports.names.iter().find(|(_, visible)| *visible == name) {
+        Some(&(internal, _)) => internal,
+        None if imports.all => name,
+        None => panic!("import: {} is not exported in {}", name.string, spec),
+    }
+}
+
+// Library name without version, like ["rnrs", "base"] for (rnrs base (6)).
+fn library_name(name: Object) -> Vec<String> {
+    let mut parts = vec![];
+    for part in list_to_vec("library", name) {
+        match part {
+            Object::Symbol(symbol) => parts.push(symbol.string.to_owned()),
+            Object::Number(n) => parts.push(n.to_string()),
+            // Version reference.
+            Object::Pair(_) | Object::Nil => break,
+            _ => panic!("library: malformed library name {}", name),
+        }
+    }
+    if parts.is_empty() {
+        panic!("library: malformed library name {}", name);
+    }
+    parts
+}
+
+fn library_key(name: &[String]) -> String {
+    format!("({})", name.join(" "))
+}
+
+fn is_builtin_library(name: &[String]) -> bool {
+    BUILTIN_LIBRARY_PREFIXES.contains(&name[0].as_str())
+        || BUILTIN_LIBRARIES.contains(&library_key(name).as_str())
+}
+
+// Same as library-name->file-name in psyntax. (srfi :1) is "/srfi/%3a1".
+pub fn library_file_name(name: &[String]) -> String {
+    let mut file_name = String::new();
+    for part in name {
+        file_name.push('/');
+        for b in part.bytes() {
+            if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+                file_name.push(b as char);
+            } else {
+                file_name.push_str(&format!("%{:02x}", b));
+            }
+        }
+    }
+    file_name
+}
+
+// (export a (rename (b c))) exports a as a and b as c.
+fn export_names(specs: &[Object]) -> Vec<(GcRef<Symbol>, GcRef<Symbol>)> {
+    let mut exports = vec![];
+    for &spec in specs {
+        match spec {
+            Object::Symbol(symbol) => exports.push((symbol, symbol)),
+            Object::Pair(pair)
+                if pair.car.is_symbol() && pair.car.to_symbol().string == "rename" =>
+            {
+                for rename in list_to_vec("export", pair.cdr) {
+                    let names = symbols("export", &list_to_vec("export", rename));
+                    if names.len() != 2 {
+                        panic!("export: malformed rename {}", rename);
+                    }
+                    exports.push((names[0], names[1]));
+                }
+            }
+            _ => panic!("export: malformed export spec {}", spec),
+        }
+    }
+    exports
+}
+
+// (export a (rename b c)) exports a as a and b as c.
+fn r7rs_export_names(specs: &[Object]) -> Vec<(GcRef<Symbol>, GcRef<Symbol>)> {
+    let mut exports = vec![];
+    for &spec in specs {
+        match spec {
+            Object::Symbol(symbol) => exports.push((symbol, symbol)),
+            Object::Pair(_) => {
+                let names = symbols("export", &list_to_vec("export", spec));
+                if names.len() != 3 || names[0].string != "rename" {
+                    panic!("export: malformed export spec {}", spec);
+                }
+                exports.push((names[1], names[2]));
+            }
+            _ => panic!("export: malformed export spec {}", spec),
+        }
+    }
+    exports
+}
+
+fn strings(name: &str, objects: &[Object]) -> Vec<String> {
+    objects
+        .iter()
+        .map(|&obj| match obj {
+            Object::String(s) => s.string.to_owned(),
+            _ => panic!("{}: string required but got {}", name, obj),
+        })
+        .collect()
+}
+
+fn symbols(name: &str, objects: &[Object]) -> Vec<GcRef<Symbol>> {
+    objects
+        .iter()
+        .map(|&obj| match obj {
+            Object::Symbol(symbol) => symbol,
+            _ => panic!("{}: symbol required but got {}", name, obj),
+        })
+        .collect()
+}
+
+fn list_to_vec(name: &str, list: Object) -> Vec<Object> {
+    let mut objects = vec![];
+    let mut p = list;
+    while let Object::Pair(pair) = p {
+        objects.push(pair.car);
+        p = pair.cdr;
+    }
+    if !p.is_nil() {
+        panic!("{}: proper list required but got {}", name, list);
+    }
+    objects
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::library_file_name;
+
+    #[test]
+    fn test_library_file_name() {
+        let name = |parts: &[&str]| parts.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(library_file_name(&name(&["mosh", "test"])), "/mosh/test");
+        assert_eq!(library_file_name(&name(&["srfi", ":1"])), "/srfi/%3a1");
+        assert_eq!(library_file_name(&name(&["a-b", "c.d_e~"])), "/a-b/c.d_e~");
+    }
+}
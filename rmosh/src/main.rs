@@ -1,64 +1,215 @@
-use std::{env, fs};
+use std::{
+    env,
+    io::{self, Read, Write},
+    panic::{self, AssertUnwindSafe},
+    process,
+    time::Instant,
+};
 
-use rmosh::objects::Object;
-use rmosh::op::Op;
-use rmosh::read::read;
-use rmosh::vm::Vm;
+use rmosh::{
+    compile_cache::default_cache_dir,
+    vm::{Exit, Vm},
+};
 extern crate num_derive;
 #[macro_use]
 extern crate lalrpop_util;
 
 lalrpop_mod!(pub reader); // synthesized by LALRPOP
 pub mod alloc;
+pub mod backtrace;
+pub mod compile_cache;
 pub mod compiler;
+pub mod debugger;
+pub mod disasm;
 pub mod equal;
 pub mod fasl;
 pub mod gc;
+pub mod heap_dump;
 pub mod lexer;
 pub mod lexer_iter;
+pub mod library;
 pub mod objects;
 pub mod op;
+pub mod pages;
 pub mod procs;
+#[cfg(feature = "profiler")]
+pub mod profiler;
 pub mod read;
+pub mod repl;
+pub mod snapshot;
+pub mod trace;
+pub mod verify;
 pub mod vm;
 
+const USAGE: &str = "Usage: rmosh [options] [file [args ...]]
+
+Options:
+  -h, --help              show this help and exit
+  -v, --version           show the version and exit
+  -e, --eval <expr>       evaluate expr instead of starting the REPL, can be repeated
+  -l, --load <file>       load file before the program, can be repeated
+  -L, --loadpath <paths>  add colon separated directories to the load path
+  -b, --batch             read the program from stdin instead of starting the REPL
+  -t, --time              show the elapsed time on exit
+      --profile           profile the program and show the result on exit
+      --disable-acc       don't use the auto compile cache
+
+Arguments after file are available to the program via (command-line).";
+
+#[derive(Default)]
+struct Options {
+    help: bool,
+    version: bool,
+    evals: Vec<String>,
+    loads: Vec<String>,
+    load_path: Vec<String>,
+    batch: bool,
+    time: bool,
+    profile: bool,
+    disable_acc: bool,
+    // The script and its arguments.
+    script: Vec<String>,
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            options.script.extend(args.cloned());
+            break;
+        }
+        if !arg.starts_with('-') || arg == "-" {
+            options.script.push(arg.to_owned());
+            options.script.extend(args.cloned());
+            break;
+        }
+        // --name=value is the same as --name value.
+        let (name, inline_value) = match arg.split_once('=') {
+            Some((name, value)) if arg.starts_with("--") => (name, Some(value.to_owned())),
+            _ => (arg.as_str(), None),
+        };
+        let takes_value = matches!(
+            name,
+            "-e" | "--eval" | "-l" | "--load" | "-L" | "--loadpath"
+        );
+        let value = match (takes_value, inline_value) {
+            (true, Some(value)) => value,
+            (true, None) => match args.next() {
+                Some(value) => value.to_owned(),
+                None => return Err(format!("option {} requires an argument", name)),
+            },
+            (false, Some(_)) => return Err(format!("option {} doesn't take an argument", name)),
+            (false, None) => String::new(),
+        };
+        match name {
+            "-h" | "--help" => options.help = true,
+            "-v" | "--version" => options.version = true,
+            "-e" | "--eval" => options.evals.push(value),
+            "-l" | "--load" => options.loads.push(value),
+            "-L" | "--loadpath" => options.load_path.extend(
+                value
+                    .split(':')
+                    .filter(|dir| !dir.is_empty())
+                    .map(String::from),
+            ),
+            "-b" | "--batch" => options.batch = true,
+            "-t" | "--time" => options.time = true,
+            "--profile" if cfg!(feature = "profiler") => options.profile = true,
+            "--profile" => return Err("rmosh is built without the profiler feature".to_string()),
+            "--disable-acc" => options.disable_acc = true,
+            _ => return Err(format!("unknown option {}", arg)),
+        }
+    }
+    Ok(options)
+}
+
+// Run the program specified by options and return the exit status.
+fn run(vm: &mut Vm, options: &Options) -> i32 {
+    for path in &options.loads {
+        vm.load_file(path);
+    }
+    for expr in &options.evals {
+        vm.eval_string(expr);
+    }
+    if let Some(script) = options.script.first() {
+        vm.load_file(script);
+    } else if options.batch {
+        let mut text = String::new();
+        if let Err(err) = io::stdin().read_to_string(&mut text) {
+            panic!("can't read stdin: {}", err);
+        }
+        vm.eval_string(&text);
+    } else if options.evals.is_empty() {
+        return rmosh::repl::run(vm);
+    }
+    0
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() == 2 {
-        let mut vm = Vm::new();
-        vm.should_load_compiler = true;
+    let options = match parse_args(&args[1..]) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("rmosh: {}\n\n{}", message, USAGE);
+            process::exit(2);
+        }
+    };
+    if options.help {
+        println!("{}", USAGE);
+        return;
+    }
+    if options.version {
+        println!("rmosh {}", env!("CARGO_PKG_VERSION"));
+        return;
+    }
 
-        let text = fs::read_to_string(args[1].to_owned()).unwrap();
-        let text = "(".to_string() + &text;
-        let text = text + ")";
-        let mut sexps = read(&mut vm.gc, &text).unwrap();
-        loop {
-            if sexps.is_nil() {
-                break;
-            }
+    // Errors are panics. Report uncaught ones briefly, and (exit) is not an error.
+    panic::set_hook(Box::new(|info| {
+        let payload = info.payload();
+        if let Some(message) = payload.downcast_ref::<String>() {
+            eprintln!("error: {}", message);
+        } else if let Some(message) = payload.downcast_ref::<&str>() {
+            eprintln!("error: {}", message);
+        } else if !payload.is::<Exit>() {
+            eprintln!("error: unknown error");
+        }
+    }));
+
+    let start = Instant::now();
+    let mut vm = Vm::new();
+    vm.should_load_compiler = true;
+    vm.load_path = options.load_path.clone();
+    if !options.disable_acc {
+        vm.cache_dir = default_cache_dir();
+    }
+    vm.command_line = if options.script.is_empty() {
+        vec![args[0].to_owned()]
+    } else {
+        options.script.clone()
+    };
 
-            //println!("text={}", sexp);
-            let ops = vec![
-                Object::Instruction(Op::Frame),
-                Object::Number(8),
-                Object::Instruction(Op::Constant),
-                sexps.to_pair().car,
-                Object::Instruction(Op::Push),
-                Object::Instruction(Op::ReferGlobal),
-                vm.gc.symbol_intern("compile-no-optimize"),
-                Object::Instruction(Op::Call),
-                Object::Number(1),
-                Object::Instruction(Op::Halt),
-            ];
-            let ret = vm.run(ops.as_ptr(), ops.len());
-            //println!("code={}", ret);
-            match ret {
-                Object::Vector(v) => {
-                    vm.run(v.data.as_ptr(), v.data.len());
-                }
-                _ => {}
+    #[cfg(feature = "profiler")]
+    if options.profile {
+        vm.start_profile();
+    }
+    let status = match panic::catch_unwind(AssertUnwindSafe(|| run(&mut vm, &options))) {
+        Ok(status) => status,
+        Err(payload) => match payload.downcast_ref::<Exit>() {
+            Some(Exit(status)) => *status,
+            None => {
+                eprint!("{}", vm.backtrace());
+                1
             }
-            sexps = sexps.to_pair().cdr;
-        }
+        },
+    };
+    #[cfg(feature = "profiler")]
+    if let Some(profile) = vm.stop_profile() {
+        eprint!("{}", profile);
+    }
+    if options.time {
+        eprintln!(";; {:.6}s elapsed", start.elapsed().as_secs_f64());
     }
+    let _ = io::stdout().flush();
+    process::exit(status);
 }
@@ -3,7 +3,7 @@ use crate::gc::{GcHeader, ObjectType};
 use crate::op::Op;
 use crate::vm::Vm;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::fmt::{self, Debug, Display};
 use std::hash::Hash;
 
@@ -15,6 +15,7 @@ pub enum Object {
     Eof,
     EqHashtable(GcRef<EqHashtable>),
     False,
+    Guardian(GcRef<Guardian>),
     InputPort(GcRef<InputPort>),
     Instruction(Op),
     Nil,
@@ -30,6 +31,7 @@ pub enum Object {
     ProgramCounter(*const Object),
     Vector(GcRef<Vector>),
     Vox(GcRef<Vox>),
+    WeakBox(GcRef<WeakBox>),
 }
 
 impl Object {
@@ -142,6 +144,27 @@ impl Object {
             panic!("Not a Object::Vox")
         }
     }
+    pub fn to_weak_box(self) -> GcRef<WeakBox> {
+        if let Self::WeakBox(w) = self {
+            w
+        } else {
+            panic!("Not a Object::WeakBox")
+        }
+    }
+    pub fn to_eq_hashtable(self) -> GcRef<EqHashtable> {
+        if let Self::EqHashtable(h) = self {
+            h
+        } else {
+            panic!("Not a Object::EqHashtable")
+        }
+    }
+    pub fn to_guardian(self) -> GcRef<Guardian> {
+        if let Self::Guardian(g) = self {
+            g
+        } else {
+            panic!("Not a Object::Guardian")
+        }
+    }
 
     pub fn to_closure(self) -> GcRef<Closure> {
         if let Self::Closure(c) = self {
@@ -151,6 +174,84 @@ impl Object {
         }
     }
 
+    // Fallible versions of the to_ accessors for Rust code which handles objects of any type.
+    pub fn try_number(self) -> Result<isize, TypeError> {
+        match self {
+            Self::Number(n) => Ok(n),
+            _ => TypeError::mismatch("number", self),
+        }
+    }
+
+    pub fn try_pair(self) -> Result<GcRef<Pair>, TypeError> {
+        match self {
+            Self::Pair(p) => Ok(p),
+            _ => TypeError::mismatch("pair", self),
+        }
+    }
+
+    pub fn try_symbol(self) -> Result<GcRef<Symbol>, TypeError> {
+        match self {
+            Self::Symbol(s) => Ok(s),
+            _ => TypeError::mismatch("symbol", self),
+        }
+    }
+
+    pub fn try_string(self) -> Result<GcRef<SString>, TypeError> {
+        match self {
+            Self::String(s) => Ok(s),
+            _ => TypeError::mismatch("string", self),
+        }
+    }
+
+    pub fn try_vector(self) -> Result<GcRef<Vector>, TypeError> {
+        match self {
+            Self::Vector(v) => Ok(v),
+            _ => TypeError::mismatch("vector", self),
+        }
+    }
+
+    pub fn try_vox(self) -> Result<GcRef<Vox>, TypeError> {
+        match self {
+            Self::Vox(v) => Ok(v),
+            _ => TypeError::mismatch("vox", self),
+        }
+    }
+
+    pub fn try_closure(self) -> Result<GcRef<Closure>, TypeError> {
+        match self {
+            Self::Closure(c) => Ok(c),
+            _ => TypeError::mismatch("closure", self),
+        }
+    }
+
+    pub fn try_procedure(self) -> Result<GcRef<Procedure>, TypeError> {
+        match self {
+            Self::Procedure(p) => Ok(p),
+            _ => TypeError::mismatch("procedure", self),
+        }
+    }
+
+    pub fn try_eq_hashtable(self) -> Result<GcRef<EqHashtable>, TypeError> {
+        match self {
+            Self::EqHashtable(h) => Ok(h),
+            _ => TypeError::mismatch("eq-hashtable", self),
+        }
+    }
+
+    pub fn try_weak_box(self) -> Result<GcRef<WeakBox>, TypeError> {
+        match self {
+            Self::WeakBox(w) => Ok(w),
+            _ => TypeError::mismatch("weak-box", self),
+        }
+    }
+
+    pub fn try_guardian(self) -> Result<GcRef<Guardian>, TypeError> {
+        match self {
+            Self::Guardian(g) => Ok(g),
+            _ => TypeError::mismatch("guardian", self),
+        }
+    }
+
     // TODO: Implement eqv?
     pub fn eqv(&self, other: &Self) -> bool {
         match (self, other) {
@@ -162,11 +263,113 @@ impl Object {
             }
         }
     }
+
+    // External representation the reader can read back, like Scheme's write.
+    // Unlike Display strings and chars are escaped and vectors are printed as #(...).
+    pub fn to_write_string(&self) -> String {
+        let mut out = String::new();
+        write_object(&mut out, *self);
+        out
+    }
+}
+
+fn write_object(out: &mut String, obj: Object) {
+    match obj {
+        Object::Char(c) => {
+            out.push_str("#\\");
+            match c {
+                '\0' => out.push_str("null"),
+                '\x07' => out.push_str("alarm"),
+                '\x08' => out.push_str("backspace"),
+                '\t' => out.push_str("tab"),
+                '\n' => out.push_str("newline"),
+                '\r' => out.push_str("return"),
+                '\x1b' => out.push_str("escape"),
+                ' ' => out.push_str("space"),
+                '\x7f' => out.push_str("delete"),
+                _ if c.is_control() => out.push_str(&format!("x{:x}", c as u32)),
+                _ => out.push(c),
+            }
+        }
+        Object::String(s) => {
+            out.push('"');
+            for c in s.string.chars() {
+                match c {
+                    '"' => out.push_str("\\\""),
+                    '\\' => out.push_str("\\\\"),
+                    '\n' => out.push_str("\\n"),
+                    '\t' => out.push_str("\\t"),
+                    '\r' => out.push_str("\\r"),
+                    _ => out.push(c),
+                }
+            }
+            out.push('"');
+        }
+        Object::Pair(pair) => {
+            if let (Object::Symbol(symbol), Object::Pair(rest)) = (pair.car, pair.cdr) {
+                if symbol.string == "quote" && rest.cdr.is_nil() {
+                    out.push('\'');
+                    write_object(out, rest.car);
+                    return;
+                }
+            }
+            out.push('(');
+            write_object(out, pair.car);
+            let mut e = pair.cdr;
+            loop {
+                match e {
+                    Object::Pair(pair) => {
+                        out.push(' ');
+                        write_object(out, pair.car);
+                        e = pair.cdr;
+                    }
+                    Object::Nil => break,
+                    _ => {
+                        out.push_str(" . ");
+                        write_object(out, e);
+                        break;
+                    }
+                }
+            }
+            out.push(')');
+        }
+        Object::Vector(v) => {
+            out.push_str("#(");
+            for (i, &e) in v.data.iter().enumerate() {
+                if i != 0 {
+                    out.push(' ');
+                }
+                write_object(out, e);
+            }
+            out.push(')');
+        }
+        _ => out.push_str(&obj.to_string()),
+    }
 }
 
 // For HashMap<Object, Object>
 impl Eq for Object {}
 
+// Error of the try_ accessors of Object.
+#[derive(Debug, PartialEq)]
+pub struct TypeError {
+    // Type the accessor requires, for example "pair".
+    pub expected: &'static str,
+    pub actual: Object,
+}
+
+impl TypeError {
+    fn mismatch<T>(expected: &'static str, actual: Object) -> Result<T, TypeError> {
+        Err(TypeError { expected, actual })
+    }
+}
+
+impl Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "requires {} but got {}", self.expected, self.actual)
+    }
+}
+
 impl Debug for Object {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -185,12 +388,18 @@ impl Debug for Object {
             Object::Vox(obj) => {
                 write!(f, "#<vox {}>", obj.value)
             }
+            Object::WeakBox(_) => {
+                write!(f, "#<weak-box>")
+            }
             Object::Closure(closure) => {
                 write!(f, "#<closure {:?}>", closure.pointer.as_ptr())
             }
             Object::EqHashtable(table) => {
                 write!(f, "#<eq-hashtable {:?}>", table.pointer.as_ptr())
             }
+            Object::Guardian(guardian) => {
+                write!(f, "#<guardian {:?}>", guardian.pointer.as_ptr())
+            }
             Object::Pair(pair) => {
                 write!(f, "{}", unsafe { pair.pointer.as_ref() })
             }
@@ -252,12 +461,18 @@ impl Display for Object {
             Object::Vox(obj) => {
                 write!(f, "#<vox {}>", obj.value)
             }
+            Object::WeakBox(_) => {
+                write!(f, "#<weak-box>")
+            }
             Object::Closure(closure) => {
                 write!(f, "#<closure {:?}>", closure.pointer.as_ptr())
             }
             Object::EqHashtable(table) => {
                 write!(f, "#<eq-hashtable {:?}>", table.pointer.as_ptr())
             }
+            Object::Guardian(guardian) => {
+                write!(f, "#<guardian {:?}>", guardian.pointer.as_ptr())
+            }
             Object::Pair(pair) => {
                 write!(f, "{}", unsafe { pair.pointer.as_ref() })
             }
@@ -303,6 +518,7 @@ impl Display for Object {
 
 /// Vector
 #[derive(Debug)]
+#[repr(C)]
 pub struct Vector {
     pub header: GcHeader,
     pub data: Vec<Object>,
@@ -336,6 +552,7 @@ impl Display for Vector {
 
 /// SimpleStruct
 #[derive(Debug)]
+#[repr(C)]
 pub struct SimpleStruct {
     pub header: GcHeader,
     pub name: Object,
@@ -386,6 +603,7 @@ impl Display for SimpleStruct {
 
 /// Cons cell
 #[derive(Debug)]
+#[repr(C)]
 pub struct Pair {
     pub header: GcHeader,
     pub car: Object,
@@ -493,7 +711,7 @@ impl Pair {
         }
     }
 
-    fn last_pair(p: Object) -> Object {
+    pub fn last_pair(p: Object) -> Object {
         let mut o = p;
         loop {
             match o {
@@ -580,6 +798,7 @@ impl PartialEq for Pair {
 
 /// Vox
 #[derive(Debug)]
+#[repr(C)]
 pub struct Vox {
     pub header: GcHeader,
     pub value: Object,
@@ -600,8 +819,33 @@ impl Display for Vox {
     }
 }
 
+/// WeakBox
+/// The Gc doesn't keep value alive and replaces it with #f when it dies.
+#[derive(Debug)]
+#[repr(C)]
+pub struct WeakBox {
+    pub header: GcHeader,
+    pub value: Object,
+}
+
+impl WeakBox {
+    pub fn new(value: Object) -> Self {
+        WeakBox {
+            header: GcHeader::new(ObjectType::WeakBox),
+            value,
+        }
+    }
+}
+
+impl Display for WeakBox {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "WeakBox({})", self.value)
+    }
+}
+
 /// SString (Sceheme String)
 #[derive(Debug)]
+#[repr(C)]
 pub struct SString {
     pub header: GcHeader,
     pub string: String,
@@ -630,6 +874,7 @@ impl PartialEq for SString {
 
 /// Symbol
 #[derive(Debug)]
+#[repr(C)]
 pub struct Symbol {
     pub header: GcHeader,
     pub string: String,
@@ -651,6 +896,7 @@ impl Display for Symbol {
 }
 
 /// Procedures written in Rust.
+#[repr(C)]
 pub struct Procedure {
     pub header: GcHeader,
     pub func: fn(&mut Vm, &[Object]) -> Object,
@@ -681,6 +927,7 @@ impl Display for Procedure {
 
 /// Closure
 #[derive(Debug)]
+#[repr(C)]
 pub struct Closure {
     pub header: GcHeader,
     pub ops: *const Object,
@@ -690,6 +937,9 @@ pub struct Closure {
     pub free_vars: Vec<Object>,
     pub prev: Object,
     pub src: Object,
+    // The code vector ops points into, which the closure keeps alive.
+    // Unspecified when the code is kept alive by others, for example the base library.
+    pub code: Object,
 }
 
 impl Closure {
@@ -710,12 +960,38 @@ impl Closure {
             free_vars: free_vars,
             prev: Object::Unspecified,
             src: src,
+            code: Object::Unspecified,
         }
     }
 
     pub fn refer_free(&self, n: usize) -> Object {
         self.free_vars[n]
     }
+
+    // Name and arguments from the source info (location name . args), or #f.
+    // For example (fib n) for (define (fib n) ...).
+    pub fn name(&self) -> Object {
+        match self.src {
+            Object::Pair(src) if src.cdr.is_pair() => src.cdr,
+            _ => Object::False,
+        }
+    }
+
+    // "file:line" where the closure is defined, if the source info has it.
+    pub fn location(&self) -> Option<String> {
+        match self.src {
+            Object::Pair(src) => match src.car {
+                Object::Pair(location) => match (location.car, location.cdr) {
+                    (Object::String(file), Object::Pair(line)) => {
+                        Some(format!("{}:{}", file.string, line.car))
+                    }
+                    _ => None,
+                },
+                _ => None,
+            },
+            _ => None,
+        }
+    }
 }
 
 impl Display for Closure {
@@ -724,20 +1000,38 @@ impl Display for Closure {
     }
 }
 
+/// Which references of a hashtable keep objects alive.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Weakness {
+    // Keys and values.
+    Strong,
+    // Values only. Entries are removed when their keys die.
+    WeakKey,
+    // Values while their keys are alive. Entries are removed when their keys die.
+    Ephemeron,
+}
+
 /// EqHashtable
 #[derive(Debug)]
+#[repr(C)]
 pub struct EqHashtable {
     pub header: GcHeader,
     pub hash_map: HashMap<Object, Object>,
     pub is_mutable: bool,
+    pub weakness: Weakness,
 }
 
 impl EqHashtable {
     pub fn new() -> Self {
+        EqHashtable::with_weakness(Weakness::Strong)
+    }
+
+    pub fn with_weakness(weakness: Weakness) -> Self {
         EqHashtable {
             header: GcHeader::new(ObjectType::EqHashtable),
             hash_map: HashMap::new(),
             is_mutable: true,
+            weakness,
         }
     }
 
@@ -786,8 +1080,52 @@ impl Display for EqHashtable {
     }
 }
 
+/// Guardian
+/// Objects registered to a guardian aren't freed when they become unreachable.
+/// The Gc queues them instead, and calling the guardian with no arguments returns them one by one.
+#[derive(Debug)]
+#[repr(C)]
+pub struct Guardian {
+    pub header: GcHeader,
+    // Registered objects and the representatives returned for them.
+    pub registered: Vec<(Object, Object)>,
+    // Representatives of registered objects which became unreachable, oldest first.
+    pub resurrected: VecDeque<Object>,
+}
+
+impl Guardian {
+    pub fn new() -> Self {
+        Guardian {
+            header: GcHeader::new(ObjectType::Guardian),
+            registered: Vec::new(),
+            resurrected: VecDeque::new(),
+        }
+    }
+
+    pub fn register(&mut self, obj: Object, representative: Object) {
+        self.registered.push((obj, representative));
+    }
+
+    pub fn pop(&mut self) -> Option<Object> {
+        self.resurrected.pop_front()
+    }
+}
+
+impl Default for Guardian {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Display for Guardian {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#<guardian>")
+    }
+}
+
 /// InputPort
 #[derive(Debug)]
+#[repr(C)]
 pub struct InputPort {
     pub header: GcHeader,
     source: String,
@@ -876,6 +1214,25 @@ pub mod tests {
         }
     }
 
+    #[test]
+    fn test_try_accessors() {
+        let mut gc = Gc::new();
+        let symbol = gc.symbol_intern("hello");
+        assert_eq!(
+            symbol.try_symbol().map(|s| s.string.to_owned()),
+            Ok("hello".to_owned())
+        );
+        assert_eq!(Object::Number(3).try_number(), Ok(3));
+        let error = symbol.try_pair().unwrap_err();
+        assert_eq!(error.expected, "pair");
+        assert_eq!(error.actual, symbol);
+        assert_eq!(error.to_string(), "requires pair but got hello");
+        assert_eq!(
+            Object::Nil.try_weak_box().unwrap_err().to_string(),
+            "requires weak-box but got ()"
+        );
+    }
+
     #[test]
     fn test_simple_to_string() {
         assert_eq!("101", Object::Number(101).to_string());
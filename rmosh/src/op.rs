@@ -3,7 +3,7 @@ use std::fmt::{self, Display};
 use num_derive::FromPrimitive;
 
 #[repr(u8)]
-#[derive(Clone, Copy, Debug, FromPrimitive, Hash, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, FromPrimitive, Hash, PartialEq)]
 pub enum Op {
     CompileError = 0,
     BranchNotLe = 1,
@@ -118,3 +118,93 @@ impl Display for Op {
         write!(f, "{:?}", self)
     }
 }
+
+// Kinds of operands which follow instructions.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operand {
+    // A fixnum such as an argument count or a stack depth.
+    Number,
+    // Index of a local variable.
+    Local,
+    // Index of a free variable.
+    Free,
+    // Jump offset. The destination is the position of the operand plus the offset.
+    Offset,
+    Symbol,
+    Bool,
+    // Any object.
+    Constant,
+}
+
+impl Op {
+    // Operands which follow the instruction in compiled code.
+    pub fn operands(self) -> &'static [Operand] {
+        use Operand::*;
+        match self {
+            // The offset points to the end of the closure body.
+            // The others are argc, optional argument?, number of free variables, max stack and source info.
+            Op::Closure => &[Offset, Number, Bool, Number, Number, Constant],
+            Op::CompileError => &[Constant, Constant, Constant],
+            Op::Shiftj | Op::ShiftCall => &[Number, Number, Number],
+            Op::ReferLocalPushConstantBranchNotLe
+            | Op::ReferLocalPushConstantBranchNotGe
+            | Op::ReferLocalPushConstantBranchNotNumberEqual => &[Local, Constant, Offset],
+            Op::Shift | Op::Receive | Op::TailCall | Op::LocalTailCall => &[Number, Number],
+            Op::ReferGlobalCall => &[Symbol, Number],
+            Op::ReferLocalPushConstant => &[Local, Constant],
+            Op::ReferLocalBranchNotNull | Op::ReferLocalBranchNotLt => &[Local, Offset],
+            Op::ReferFreeCall => &[Free, Number],
+            Op::ReferLocalCall => &[Local, Number],
+            Op::BranchNotLe
+            | Op::BranchNotGe
+            | Op::BranchNotLt
+            | Op::BranchNotGt
+            | Op::BranchNotNull
+            | Op::BranchNotNumberEqual
+            | Op::BranchNotEq
+            | Op::BranchNotEqv
+            | Op::BranchNotEqual
+            | Op::Frame
+            | Op::PushFrame
+            | Op::LocalJmp
+            | Op::UnfixedJump
+            | Op::Test
+            | Op::NotTest => &[Offset],
+            Op::Call
+            | Op::Box
+            | Op::Display
+            | Op::Enter
+            | Op::Leave
+            | Op::LetFrame
+            | Op::List
+            | Op::MakeContinuation
+            | Op::Reduce
+            | Op::RestoreContinuation
+            | Op::Return
+            | Op::Values
+            | Op::PushEnter
+            | Op::LocalCall
+            | Op::Vector => &[Number],
+            Op::AssignFree | Op::ReferFree | Op::ReferFreePush => &[Free],
+            Op::AssignLocal | Op::ReferLocal | Op::ReferLocalPush => &[Local],
+            Op::AssignGlobal | Op::DefineGlobal | Op::ReferGlobal | Op::ReferGlobalPush => {
+                &[Symbol]
+            }
+            Op::Constant | Op::ConstantPush | Op::PushConstant => &[Constant],
+            _ => &[],
+        }
+    }
+
+    // Number of operands which follow the instruction in compiled code.
+    pub fn operand_count(self) -> usize {
+        self.operands().len()
+    }
+
+    // Position of the operand which holds a jump offset, counted from 1.
+    pub fn jump_operand(self) -> Option<usize> {
+        self.operands()
+            .iter()
+            .position(|&operand| operand == Operand::Offset)
+            .map(|i| i + 1)
+    }
+}
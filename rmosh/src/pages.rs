@@ -0,0 +1,235 @@
+/// Size-classed page allocator for small objects.
+/// Objects which don't need drop, such as Pairs and Voxes, are allocated in slots of fixed-size pages
+/// instead of each in its own Box. A page holds slots of one size, and its bitmap tells which slots are used.
+/// The Gc sweeps these objects by walking the bitmaps instead of the object lists, and freeing one only clears its bit.
+/// Pages which become empty are released at once after the sweep, except in debug builds.
+use std::{
+    alloc::{self, Layout},
+    mem,
+    ptr::NonNull,
+};
+
+use crate::gc::GcHeader;
+
+const PAGE_SIZE: usize = 32 * 1024;
+const PAGE_ALIGN: usize = 16;
+// Larger objects are boxed.
+const MAX_SLOT_SIZE: usize = 256;
+// Empty pages kept per size class for the next allocations.
+const NUM_SPARE_PAGES: usize = 1;
+
+struct Page {
+    memory: NonNull<u8>,
+    slot_size: usize,
+    num_slots: usize,
+    num_used: usize,
+    // Bit i is set when slot i holds an object. Bits after the last slot are always set.
+    used: Vec<u64>,
+    // Objects were allocated here since the last sweep.
+    has_young: bool,
+}
+
+impl Page {
+    fn new(slot_size: usize) -> Self {
+        let memory = unsafe { alloc::alloc(Page::layout()) };
+        let memory = match NonNull::new(memory) {
+            Some(memory) => memory,
+            None => alloc::handle_alloc_error(Page::layout()),
+        };
+        let num_slots = PAGE_SIZE / slot_size;
+        let mut used = vec![0; num_slots.div_ceil(64)];
+        if !num_slots.is_multiple_of(64) {
+            *used.last_mut().unwrap() = !0 << (num_slots % 64);
+        }
+        Page {
+            memory,
+            slot_size,
+            num_slots,
+            num_used: 0,
+            used,
+            has_young: false,
+        }
+    }
+
+    fn layout() -> Layout {
+        Layout::from_size_align(PAGE_SIZE, PAGE_ALIGN).unwrap()
+    }
+
+    fn is_full(&self) -> bool {
+        self.num_used == self.num_slots
+    }
+
+    fn slot(&self, index: usize) -> NonNull<u8> {
+        unsafe { NonNull::new_unchecked(self.memory.as_ptr().add(index * self.slot_size)) }
+    }
+
+    // Take the first free slot.
+    fn alloc_slot(&mut self) -> Option<NonNull<u8>> {
+        for (i, word) in self.used.iter_mut().enumerate() {
+            if *word != !0 {
+                let bit = (!*word).trailing_zeros() as usize;
+                *word |= 1 << bit;
+                self.num_used += 1;
+                self.has_young = true;
+                return Some(self.slot(i * 64 + bit));
+            }
+        }
+        None
+    }
+
+    // Call keep for each object and free the slots of the ones it returns false for.
+    fn sweep(&mut self, keep: &mut dyn FnMut(NonNull<GcHeader>) -> bool) {
+        for i in 0..self.used.len() {
+            let mut bits = self.used[i];
+            while bits != 0 {
+                let bit = bits.trailing_zeros() as usize;
+                bits &= bits - 1;
+                let index = i * 64 + bit;
+                if index >= self.num_slots {
+                    break;
+                }
+                if !keep(self.slot(index).cast()) {
+                    self.used[i] &= !(1 << bit);
+                    self.num_used -= 1;
+                }
+            }
+        }
+        self.has_young = false;
+    }
+
+    fn for_each_object(&self, f: &mut dyn FnMut(NonNull<GcHeader>)) {
+        for (i, &word) in self.used.iter().enumerate() {
+            let mut bits = word;
+            while bits != 0 {
+                let index = i * 64 + bits.trailing_zeros() as usize;
+                bits &= bits - 1;
+                if index >= self.num_slots {
+                    break;
+                }
+                f(self.slot(index).cast());
+            }
+        }
+    }
+}
+
+impl Drop for Page {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.memory.as_ptr(), Page::layout()) }
+    }
+}
+
+// Pages of one slot size.
+struct SizeClass {
+    slot_size: usize,
+    pages: Vec<Page>,
+    // Indexes of the pages with free slots. Allocation takes from the last one.
+    free_pages: Vec<usize>,
+}
+
+impl SizeClass {
+    // Release empty pages but the spare ones, and find the pages with free slots again.
+    // Debug builds keep all of them, so that GcRefs of freed objects still refer memory of the pages. See gc.rs.
+    fn compact(&mut self) {
+        let mut num_spare = 0;
+        self.pages.retain(|page| {
+            if page.num_used > 0 {
+                true
+            } else {
+                num_spare += 1;
+                num_spare <= NUM_SPARE_PAGES || cfg!(debug_assertions)
+            }
+        });
+        // Fill the fullest pages first for locality.
+        self.free_pages = (0..self.pages.len())
+            .filter(|&i| !self.pages[i].is_full())
+            .collect();
+        let pages = &self.pages;
+        self.free_pages
+            .sort_by_key(|&i| std::cmp::Reverse(pages[i].num_slots - pages[i].num_used));
+    }
+}
+
+#[derive(Default)]
+pub struct PageAllocator {
+    classes: Vec<SizeClass>,
+    // Pages allocated in since the last sweep, by class and page index.
+    young_pages: Vec<(usize, usize)>,
+}
+
+impl PageAllocator {
+    // Whether objects of T are allocated in pages.
+    pub fn accepts<T>() -> bool {
+        !mem::needs_drop::<T>()
+            && mem::size_of::<T>() <= MAX_SLOT_SIZE
+            && mem::align_of::<T>() <= PAGE_ALIGN
+    }
+
+    // Memory for an object of size bytes. The caller writes the object there.
+    pub fn alloc(&mut self, size: usize) -> NonNull<u8> {
+        let class_index = match self.classes.iter().position(|c| c.slot_size == size) {
+            Some(index) => index,
+            None => {
+                self.classes.push(SizeClass {
+                    slot_size: size,
+                    pages: vec![],
+                    free_pages: vec![],
+                });
+                self.classes.len() - 1
+            }
+        };
+        let class = &mut self.classes[class_index];
+        loop {
+            let page_index = match class.free_pages.last() {
+                Some(&index) => index,
+                None => {
+                    class.pages.push(Page::new(size));
+                    class.free_pages.push(class.pages.len() - 1);
+                    class.pages.len() - 1
+                }
+            };
+            let page = &mut class.pages[page_index];
+            let had_young = page.has_young;
+            if let Some(slot) = page.alloc_slot() {
+                if !had_young {
+                    self.young_pages.push((class_index, page_index));
+                }
+                return slot;
+            }
+            class.free_pages.pop();
+        }
+    }
+
+    // Call keep for each object and free the ones it returns false for.
+    // Only the pages allocated in since the last sweep are swept unless all is true,
+    // and only the classes of the swept pages are compacted.
+    pub fn sweep(&mut self, all: bool, mut keep: impl FnMut(NonNull<GcHeader>) -> bool) {
+        if all {
+            for class in &mut self.classes {
+                for page in &mut class.pages {
+                    page.sweep(&mut keep);
+                }
+                class.compact();
+            }
+        } else {
+            let mut swept = vec![false; self.classes.len()];
+            for &(class_index, page_index) in &self.young_pages {
+                self.classes[class_index].pages[page_index].sweep(&mut keep);
+                swept[class_index] = true;
+            }
+            for (class, swept) in self.classes.iter_mut().zip(swept) {
+                if swept {
+                    class.compact();
+                }
+            }
+        }
+        self.young_pages.clear();
+    }
+
+    pub fn for_each_object(&self, mut f: impl FnMut(NonNull<GcHeader>)) {
+        for class in &self.classes {
+            for page in &class.pages {
+                page.for_each_object(&mut f);
+            }
+        }
+    }
+}
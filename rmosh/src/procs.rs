@@ -1,9 +1,18 @@
 /// Scheme procedures written in Rust.
 /// The procedures will be exposed to the VM via free vars.
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    panic,
+};
+
 use crate::{
-    gc::Gc,
-    objects::{EqHashtable, InputPort, Object, Pair, SimpleStruct},
-    vm::Vm,
+    disasm::disassemble_closure,
+    gc::{Collection, Gc, GcRef},
+    objects::{EqHashtable, Guardian, InputPort, Object, Pair, SimpleStruct, Vector, Weakness},
+    op::Op,
+    vm::{Exit, Vm},
 };
 
 use num_traits::FromPrimitive;
@@ -771,6 +780,149 @@ pub fn default_free_vars(gc: &mut Gc) -> Vec<Object> {
     ]
 }
 
+// Procedures which are not free variables of the compiler.
+// They are registered as global variables after the base library is loaded.
+pub fn default_global_procs(gc: &mut Gc) -> Vec<(&'static str, Object)> {
+    vec![
+        ("features", gc.new_procedure(features, "features")),
+        ("profile", gc.new_procedure(profile, "profile")),
+        ("backtrace", gc.new_procedure(backtrace, "backtrace")),
+        ("break", gc.new_procedure(break_at, "break")),
+        ("unbreak", gc.new_procedure(unbreak, "unbreak")),
+        ("trace", gc.new_procedure(trace, "trace")),
+        ("untrace", gc.new_procedure(untrace, "untrace")),
+        (
+            "make-weak-eq-hashtable",
+            gc.new_procedure(make_weak_eq_hashtable, "make-weak-eq-hashtable"),
+        ),
+        (
+            "make-ephemeron-eq-hashtable",
+            gc.new_procedure(make_ephemeron_eq_hashtable, "make-ephemeron-eq-hashtable"),
+        ),
+        (
+            "make-weak-box",
+            gc.new_procedure(make_weak_box, "make-weak-box"),
+        ),
+        ("weak-box?", gc.new_procedure(is_weak_box, "weak-box?")),
+        (
+            "weak-box-ref",
+            gc.new_procedure(weak_box_ref, "weak-box-ref"),
+        ),
+        (
+            "make-guardian",
+            gc.new_procedure(make_guardian, "make-guardian"),
+        ),
+        ("gc", gc.new_procedure(collect_garbage, "gc")),
+        ("gc-stats", gc.new_procedure(gc_stats, "gc-stats")),
+        ("heap-dump", gc.new_procedure(heap_dump, "heap-dump")),
+        ("map", gc.new_procedure(map, "map")),
+        ("for-each", gc.new_procedure(for_each, "for-each")),
+        ("vector-map", gc.new_procedure(vector_map, "vector-map")),
+        (
+            "hashtable-walk",
+            gc.new_procedure(hashtable_walk, "hashtable-walk"),
+        ),
+        ("list-sort", gc.new_procedure(list_sort, "list-sort")),
+    ]
+}
+
+// Native versions of the compiler procedures.
+// The embedded compiler defines them in Scheme as global variables, so they replace the globals after the compiler is loaded.
+pub fn compiler_procs(gc: &mut Gc) -> Vec<(&'static str, Object)> {
+    vec![
+        (
+            "pass3/find-free",
+            gc.new_procedure(pass3_find_free, "pass3/find-free"),
+        ),
+        (
+            "pass3/find-sets",
+            gc.new_procedure(pass3_find_sets, "pass3/find-sets"),
+        ),
+        (
+            "pass4/fixup-labels",
+            gc.new_procedure(pass4_fixup_labels, "pass4/fixup-labels"),
+        ),
+        (
+            "make-code-builder",
+            gc.new_procedure(make_code_builder, "make-code-builder"),
+        ),
+        (
+            "code-builder-put-extra1!",
+            gc.new_procedure(
+                code_builder_put_extra1_destructive,
+                "code-builder-put-extra1!",
+            ),
+        ),
+        (
+            "code-builder-put-extra2!",
+            gc.new_procedure(
+                code_builder_put_extra2_destructive,
+                "code-builder-put-extra2!",
+            ),
+        ),
+        (
+            "code-builder-put-extra3!",
+            gc.new_procedure(
+                code_builder_put_extra3_destructive,
+                "code-builder-put-extra3!",
+            ),
+        ),
+        (
+            "code-builder-put-extra4!",
+            gc.new_procedure(
+                code_builder_put_extra4_destructive,
+                "code-builder-put-extra4!",
+            ),
+        ),
+        (
+            "code-builder-put-extra5!",
+            gc.new_procedure(
+                code_builder_put_extra5_destructive,
+                "code-builder-put-extra5!",
+            ),
+        ),
+        (
+            "code-builder-append!",
+            gc.new_procedure(code_builder_append_destructive, "code-builder-append!"),
+        ),
+        (
+            "code-builder-emit",
+            gc.new_procedure(code_builder_emit, "code-builder-emit"),
+        ),
+        (
+            "code-builder-put-insn-arg0!",
+            gc.new_procedure(
+                code_builder_put_insn_arg0_destructive,
+                "code-builder-put-insn-arg0!",
+            ),
+        ),
+        (
+            "code-builder-put-insn-arg1!",
+            gc.new_procedure(
+                code_builder_put_insn_arg1_destructive,
+                "code-builder-put-insn-arg1!",
+            ),
+        ),
+        (
+            "code-builder-put-insn-arg2!",
+            gc.new_procedure(
+                code_builder_put_insn_arg2_destructive,
+                "code-builder-put-insn-arg2!",
+            ),
+        ),
+        (
+            "pass3/compile-refer",
+            gc.new_procedure(pass3_compile_refer, "pass3/compile-refer"),
+        ),
+        (
+            "pass1/find-symbol-in-lvars",
+            gc.new_procedure(pass1_find_symbol_in_lvars, "pass1/find-symbol-in-lvars"),
+        ),
+        ("$label", gc.new_procedure(label, "$label")),
+        ("$local-ref", gc.new_procedure(local_ref, "$local-ref")),
+    ]
+}
+
 #[macro_export]
 macro_rules! check_argc {
     ($name:ident, $args:ident, $argc:expr) => {{
@@ -814,6 +966,20 @@ macro_rules! check_argc_between {
     }};
 }
 
+#[macro_export]
+macro_rules! check_argc_max {
+    ($name:ident, $args:ident, $max:expr) => {{
+        if $args.len() > $max {
+            panic!(
+                "{}: at most {} arguments required but got {}",
+                $name,
+                $max,
+                $args.len()
+            );
+        }
+    }};
+}
+
 fn is_number(_vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "number?";
     check_argc!(name, args, 1);
@@ -1221,11 +1387,22 @@ fn is_char(_vm: &mut Vm, args: &[Object]) -> Object {
 }
 fn write(_vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "write";
-    panic!("{}({}) not implemented", name, args.len());
+    // There are no output ports yet, so we always write to stdout.
+    check_argc!(name, args, 1);
+    print!("{}", args[0].to_write_string());
+    Object::Unspecified
 }
-fn gensym(_vm: &mut Vm, args: &[Object]) -> Object {
+fn gensym(vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "gensym";
-    panic!("{}({}) not implemented", name, args.len());
+    check_argc_max!(name, args, 1);
+    let symbol = match args {
+        [] => vm.gc.gensym(None),
+        [Object::Symbol(base)] => vm.gc.gensym(Some(&base.string)),
+        [Object::String(base)] => vm.gc.gensym(Some(&base.string)),
+        [obj] => panic!("{}: symbol or string required but got {}", name, obj),
+        _ => unreachable!(),
+    };
+    Object::Symbol(symbol)
 }
 fn is_stringequal(_vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "string=?";
@@ -1298,16 +1475,27 @@ fn cadadr(_vm: &mut Vm, args: &[Object]) -> Object {
     panic!("{}({}) not implemented", name, args.len());
 }
 fn cadar(_vm: &mut Vm, args: &[Object]) -> Object {
-    let name: &str = "cadar";
-    panic!("{}({}) not implemented", name, args.len());
+    cxr("cadar", args)
 }
 fn caddar(_vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "caddar";
     panic!("{}({}) not implemented", name, args.len());
 }
+// Apply car and cdr as the letters of the c[ad]+r name say, from right to left.
+fn cxr(name: &str, args: &[Object]) -> Object {
+    check_argc!(name, args, 1);
+    let mut obj = args[0];
+    for c in name[1..name.len() - 1].chars().rev() {
+        obj = match obj {
+            Object::Pair(pair) if c == 'a' => pair.car,
+            Object::Pair(pair) => pair.cdr,
+            _ => panic!("{}: pair required but got {}", name, args[0]),
+        };
+    }
+    obj
+}
 fn cadddr(_vm: &mut Vm, args: &[Object]) -> Object {
-    let name: &str = "cadddr";
-    panic!("{}({}) not implemented", name, args.len());
+    cxr("cadddr", args)
 }
 fn caddr(_vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "caddr";
@@ -1403,16 +1591,14 @@ fn cddadr(_vm: &mut Vm, args: &[Object]) -> Object {
     panic!("{}({}) not implemented", name, args.len());
 }
 fn cddar(_vm: &mut Vm, args: &[Object]) -> Object {
-    let name: &str = "cddar";
-    panic!("{}({}) not implemented", name, args.len());
+    cxr("cddar", args)
 }
 fn cdddar(_vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "cdddar";
     panic!("{}({}) not implemented", name, args.len());
 }
 fn cddddr(_vm: &mut Vm, args: &[Object]) -> Object {
-    let name: &str = "cddddr";
-    panic!("{}({}) not implemented", name, args.len());
+    cxr("cddddr", args)
 }
 fn cdddr(_vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "cdddr";
@@ -1549,11 +1735,15 @@ fn make_eqv_hashtable(_vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "make-eqv-hashtable";
     panic!("{}({}) not implemented", name, args.len());
 }
-fn hashtable_set_destructive(_vm: &mut Vm, args: &[Object]) -> Object {
+fn hashtable_set_destructive(vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "hashtable-set!";
     check_argc!(name, args, 3);
     match args[0] {
-        Object::EqHashtable(mut hashtable) => hashtable.set(args[1], args[2]),
+        Object::EqHashtable(mut hashtable) => {
+            hashtable.set(args[1], args[2]);
+            vm.gc.write_barrier(hashtable, args[1]);
+            vm.gc.write_barrier(hashtable, args[2]);
+        }
         _ => {
             panic!("{}: hashtable required but got {:?}", name, args)
         }
@@ -1601,17 +1791,86 @@ fn string_ci_hash(_vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "string-ci-hash";
     panic!("{}({}) not implemented", name, args.len());
 }
+// Hash of the name, so that a symbol collected and interned again has the same hash.
 fn symbol_hash(_vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "symbol-hash";
-    panic!("{}({}) not implemented", name, args.len());
+    check_argc!(name, args, 1);
+    match args[0] {
+        Object::Symbol(symbol) => {
+            let hash = symbol.string.chars().fold(0usize, |hash, c| {
+                hash.wrapping_mul(31).wrapping_add(c as usize)
+            });
+            Object::Number((hash & isize::MAX as usize) as isize)
+        }
+        obj => panic!("{}: symbol required but got {}", name, obj),
+    }
 }
 fn equal_hash(_vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "equal-hash";
     panic!("{}({}) not implemented", name, args.len());
 }
-fn eq_hashtable_copy(_vm: &mut Vm, args: &[Object]) -> Object {
+fn eq_hashtable_copy(vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "eq-hashtable-copy";
-    panic!("{}({}) not implemented", name, args.len());
+    check_argc!(name, args, 1);
+    // The copy is mutable.
+    hashtable_copy(vm, &[args[0], Object::True])
+}
+// Entries are removed when their keys are collected.
+fn make_weak_eq_hashtable(vm: &mut Vm, args: &[Object]) -> Object {
+    let name: &str = "make-weak-eq-hashtable";
+    check_argc_max!(name, args, 1);
+    vm.gc.new_weak_eq_hashtable(Weakness::WeakKey)
+}
+// Like make-weak-eq-hashtable but a value doesn't keep its own key alive.
+fn make_ephemeron_eq_hashtable(vm: &mut Vm, args: &[Object]) -> Object {
+    let name: &str = "make-ephemeron-eq-hashtable";
+    check_argc_max!(name, args, 1);
+    vm.gc.new_weak_eq_hashtable(Weakness::Ephemeron)
+}
+fn make_weak_box(vm: &mut Vm, args: &[Object]) -> Object {
+    let name: &str = "make-weak-box";
+    check_argc!(name, args, 1);
+    vm.gc.new_weak_box(args[0])
+}
+fn is_weak_box(_vm: &mut Vm, args: &[Object]) -> Object {
+    let name: &str = "weak-box?";
+    check_argc!(name, args, 1);
+    Object::make_bool(matches!(args[0], Object::WeakBox(_)))
+}
+fn make_guardian(vm: &mut Vm, args: &[Object]) -> Object {
+    let name: &str = "make-guardian";
+    check_argc!(name, args, 0);
+    vm.gc.new_guardian()
+}
+// Guardians are called like procedures.
+// (guardian obj [representative]) registers obj, and (guardian) returns the representative of an unreachable one or #f.
+pub fn call_guardian(vm: &mut Vm, mut guardian: GcRef<Guardian>, args: &[Object]) -> Object {
+    let name: &str = "guardian";
+    check_argc_max!(name, args, 2);
+    match args {
+        [] => guardian.pop().unwrap_or(Object::False),
+        [obj] => {
+            guardian.register(*obj, *obj);
+            vm.gc.write_barrier(guardian, *obj);
+            Object::Unspecified
+        }
+        [obj, representative] => {
+            guardian.register(*obj, *representative);
+            vm.gc.write_barrier(guardian, *obj);
+            vm.gc.write_barrier(guardian, *representative);
+            Object::Unspecified
+        }
+        _ => unreachable!(),
+    }
+}
+// #f once the value is collected.
+fn weak_box_ref(_vm: &mut Vm, args: &[Object]) -> Object {
+    let name: &str = "weak-box-ref";
+    check_argc!(name, args, 1);
+    match args[0] {
+        Object::WeakBox(weak_box) => weak_box.value,
+        obj => panic!("{}: weak-box required but got {}", name, obj),
+    }
 }
 fn current_error_port(_vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "current-error-port";
@@ -1620,9 +1879,19 @@ fn current_error_port(_vm: &mut Vm, args: &[Object]) -> Object {
 fn values(vm: &mut Vm, args: &[Object]) -> Object {
     vm.values(args)
 }
-fn vm_apply(_vm: &mut Vm, args: &[Object]) -> Object {
+fn vm_apply(vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "vm/apply";
-    panic!("{}({}) not implemented", name, args.len());
+    check_argc!(name, args, 2);
+    let mut call_args = vec![];
+    let mut p = args[1];
+    while let Object::Pair(pair) = p {
+        call_args.push(pair.car);
+        p = pair.cdr;
+    }
+    if !p.is_nil() {
+        panic!("{}: proper list required but got {}", name, args[1]);
+    }
+    vm.call_closure(args[0], &call_args)
 }
 fn is_pair(_vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "pair?";
@@ -1787,19 +2056,168 @@ fn source_info(_vm: &mut Vm, args: &[Object]) -> Object {
         }
     }
 }
-fn eval(_vm: &mut Vm, args: &[Object]) -> Object {
+fn eval(vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "eval";
-    panic!("{}({}) not implemented", name, args.len());
+    // The environment argument is accepted but we have only one environment.
+    check_argc_between!(name, args, 1, 2);
+    vm.eval(args[0])
 }
 fn eval_compiled(_vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "eval-compiled";
     panic!("{}({}) not implemented", name, args.len());
 }
 
-// We make apply public so that Vm can access.
-pub fn apply(_vm: &mut Vm, _args: &[Object]) -> Object {
+fn apply(vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "apply";
-    panic!("{} should not be called. It is handled in call in vm", name);
+    check_argc_at_least!(name, args, 2);
+    let argc = args.len();
+    // (apply proc arg1 arg2 ... args-as-list)
+    // The last argument is flatten and the call is done by the VM as tail call.
+    let mut last_pair = args[argc - 1];
+    if !last_pair.is_list() {
+        panic!(
+            "{}: last argument should be a proper list but got {}",
+            name, last_pair
+        );
+    }
+    let mut call_args = args[1..argc - 1].to_vec();
+    while let Object::Pair(pair) = last_pair {
+        call_args.push(pair.car);
+        last_pair = pair.cdr;
+    }
+    vm.tail_call(args[0], &call_args)
+}
+
+// Take the cars of lists and advance them to their cdrs. Returns None when one of them reached its end.
+fn next_cars(name: &str, lists: &mut [Object]) -> Option<Vec<Object>> {
+    let mut cars = vec![];
+    for list in lists.iter_mut() {
+        match *list {
+            Object::Pair(pair) => {
+                cars.push(pair.car);
+                *list = pair.cdr;
+            }
+            Object::Nil => return None,
+            obj => panic!("{}: proper list required but got {}", name, obj),
+        }
+    }
+    Some(cars)
+}
+
+// Procedures below call back into Scheme with call_closure, which can collect.
+// Their arguments stay on the VM stack while they run, but the values they build have to be rooted.
+fn pop_roots(vm: &mut Vm, n: usize) {
+    for _ in 0..n {
+        vm.pop_root();
+    }
+}
+fn map(vm: &mut Vm, args: &[Object]) -> Object {
+    let name: &str = "map";
+    check_argc_at_least!(name, args, 2);
+    let mut lists = args[1..].to_vec();
+    let mut values = vec![];
+    while let Some(cars) = next_cars(name, &mut lists) {
+        let value = vm.call_closure(args[0], &cars);
+        vm.push_root(value);
+        values.push(value);
+    }
+    pop_roots(vm, values.len());
+    vm.gc.listn(&values)
+}
+fn for_each(vm: &mut Vm, args: &[Object]) -> Object {
+    let name: &str = "for-each";
+    check_argc_at_least!(name, args, 2);
+    let mut lists = args[1..].to_vec();
+    while let Some(cars) = next_cars(name, &mut lists) {
+        vm.call_closure(args[0], &cars);
+    }
+    Object::Unspecified
+}
+fn vector_map(vm: &mut Vm, args: &[Object]) -> Object {
+    let name: &str = "vector-map";
+    check_argc_at_least!(name, args, 2);
+    let mut vectors = vec![];
+    for &arg in &args[1..] {
+        match arg {
+            Object::Vector(v) => vectors.push(v),
+            obj => panic!("{}: vector required but got {}", name, obj),
+        }
+    }
+    let len = vectors.iter().map(|v| v.len()).min().unwrap_or(0);
+    let mut values = vec![];
+    for i in 0..len {
+        let elements: Vec<Object> = vectors.iter().map(|v| v.data[i]).collect();
+        let value = vm.call_closure(args[0], &elements);
+        vm.push_root(value);
+        values.push(value);
+    }
+    pop_roots(vm, values.len());
+    vm.gc.new_vector(&values)
+}
+// (hashtable-walk hashtable proc) calls (proc key value) for each entry.
+fn hashtable_walk(vm: &mut Vm, args: &[Object]) -> Object {
+    let name: &str = "hashtable-walk";
+    check_argc!(name, args, 2);
+    let hashtable = match args[0] {
+        Object::EqHashtable(hashtable) => hashtable,
+        obj => panic!("{}: hashtable required but got {}", name, obj),
+    };
+    // proc may modify the hashtable, so we walk a snapshot of it.
+    let mut entries = vec![];
+    for (&key, &value) in &hashtable.hash_map {
+        entries.push(key);
+        entries.push(value);
+    }
+    let snapshot = vm.gc.new_vector(&entries);
+    vm.push_root(snapshot);
+    for entry in entries.chunks(2) {
+        vm.call_closure(args[1], entry);
+    }
+    vm.pop_root();
+    Object::Unspecified
+}
+// (list-sort proc list) is a stable merge sort. proc returns true when its first argument is less than the second.
+fn list_sort(vm: &mut Vm, args: &[Object]) -> Object {
+    let name: &str = "list-sort";
+    check_argc!(name, args, 2);
+    if !args[1].is_list() {
+        panic!("{}: proper list required but got {}", name, args[1]);
+    }
+    let mut elements = vec![];
+    let mut obj = args[1];
+    while let Object::Pair(pair) = obj {
+        elements.push(pair.car);
+        obj = pair.cdr;
+    }
+    // Keep the elements alive even if proc modifies the list.
+    let copy = vm.gc.new_vector(&elements);
+    vm.push_root(copy);
+    let mut width = 1;
+    while width < elements.len() {
+        let mut merged = Vec::with_capacity(elements.len());
+        for start in (0..elements.len()).step_by(width * 2) {
+            let mid = (start + width).min(elements.len());
+            let end = (start + width * 2).min(elements.len());
+            let (mut i, mut j) = (start, mid);
+            while i < mid && j < end {
+                // Take from the right run only when it is strictly less, so equal elements keep their order.
+                let is_less = vm.call_closure(args[0], &[elements[j], elements[i]]);
+                if is_less.is_false() {
+                    merged.push(elements[i]);
+                    i += 1;
+                } else {
+                    merged.push(elements[j]);
+                    j += 1;
+                }
+            }
+            merged.extend_from_slice(&elements[i..mid]);
+            merged.extend_from_slice(&elements[j..end]);
+        }
+        elements = merged;
+        width *= 2;
+    }
+    vm.pop_root();
+    vm.gc.listn(&elements)
 }
 fn assq(_vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "assq";
@@ -1841,7 +2259,200 @@ fn assv(_vm: &mut Vm, args: &[Object]) -> Object {
 }
 fn exit(_vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "exit";
-    panic!("{}({}) not implemented", name, args.len());
+    let status = match args {
+        [] | [Object::True] => 0,
+        [Object::False] => 1,
+        [Object::Number(n)] => *n as i32,
+        _ => panic!("{}: exit status required but got {:?}", name, args),
+    };
+    // Flush what display and write printed before leaving.
+    let _ = io::stdout().flush();
+    panic::panic_any(Exit(status));
+}
+fn features(vm: &mut Vm, args: &[Object]) -> Object {
+    let name: &str = "features";
+    check_argc!(name, args, 0);
+    let features = vm.features.clone();
+    let features: Vec<Object> = features
+        .iter()
+        .map(|feature| vm.gc.symbol_intern(feature))
+        .collect();
+    vm.gc.listn(&features)
+}
+// Active frames as a list of strings, innermost first.
+fn backtrace(vm: &mut Vm, args: &[Object]) -> Object {
+    let name: &str = "backtrace";
+    check_argc!(name, args, 0);
+    let frames: Vec<String> = vm
+        .backtrace()
+        .frames
+        .iter()
+        .map(|frame| frame.to_string())
+        .collect();
+    let frames: Vec<Object> = frames.iter().map(|frame| vm.gc.new_string(frame)).collect();
+    vm.gc.listn(&frames)
+}
+// (break 'name ...) stops the VM and enters the debugger when a procedure of the names is called.
+fn break_at(vm: &mut Vm, args: &[Object]) -> Object {
+    let name: &str = "break";
+    check_argc_at_least!(name, args, 1);
+    for &arg in args {
+        match arg {
+            Object::Symbol(symbol) => vm.debugger().add_breakpoint(&symbol.string),
+            obj => panic!("{}: symbol required but got {}", name, obj),
+        }
+    }
+    Object::Unspecified
+}
+// (unbreak 'name ...) removes the breakpoints, (unbreak) removes all.
+fn unbreak(vm: &mut Vm, args: &[Object]) -> Object {
+    let name: &str = "unbreak";
+    if args.is_empty() {
+        vm.debugger().clear_breakpoints();
+    }
+    for &arg in args {
+        match arg {
+            Object::Symbol(symbol) => vm.debugger().remove_breakpoint(&symbol.string),
+            obj => panic!("{}: symbol required but got {}", name, obj),
+        }
+    }
+    Object::Unspecified
+}
+// (trace proc ...) logs calls and returns of the procedures. (trace) returns the traced procedures.
+fn trace(vm: &mut Vm, args: &[Object]) -> Object {
+    let name: &str = "trace";
+    if args.is_empty() {
+        let procedures = vm.tracer.procedures().to_vec();
+        return vm.gc.listn(&procedures);
+    }
+    for &arg in args {
+        match arg {
+            Object::Closure(_) | Object::Procedure(_) => vm.tracer.trace(arg),
+            obj => panic!("{}: procedure required but got {}", name, obj),
+        }
+    }
+    Object::Unspecified
+}
+// (untrace proc ...) stops tracing the procedures, (untrace) stops tracing all.
+fn untrace(vm: &mut Vm, args: &[Object]) -> Object {
+    let name: &str = "untrace";
+    if args.is_empty() {
+        vm.tracer.untrace_all();
+    }
+    for &arg in args {
+        match arg {
+            Object::Closure(_) | Object::Procedure(_) => vm.tracer.untrace(arg),
+            obj => panic!("{}: procedure required but got {}", name, obj),
+        }
+    }
+    Object::Unspecified
+}
+// Collect the whole heap.
+fn collect_garbage(vm: &mut Vm, args: &[Object]) -> Object {
+    let name: &str = "gc";
+    check_argc!(name, args, 0);
+    vm.collect_garbage(Collection::Major);
+    Object::Unspecified
+}
+// Alist of the Gc statistics. Pause times are in microseconds.
+// live-bytes and live-objects are alists by type of the objects not freed yet.
+fn gc_stats(vm: &mut Vm, args: &[Object]) -> Object {
+    let name: &str = "gc-stats";
+    check_argc!(name, args, 0);
+    let stats = vm.gc.stats();
+    let mut live_bytes = Object::Nil;
+    let mut live_objects = Object::Nil;
+    for i in (0..stats.live_bytes.len()).rev() {
+        let (obj_type, bytes) = stats.live_bytes[i];
+        let (_, count) = stats.live_objects[i];
+        let type_name = vm.gc.symbol_intern(obj_type.name());
+        let entry = vm.gc.cons(type_name, Object::Number(bytes as isize));
+        live_bytes = vm.gc.cons(entry, live_bytes);
+        let entry = vm.gc.cons(type_name, Object::Number(count as isize));
+        live_objects = vm.gc.cons(entry, live_objects);
+    }
+    let entries = [
+        (
+            "collections",
+            Object::Number(stats.num_collections as isize),
+        ),
+        (
+            "major-collections",
+            Object::Number(stats.num_major_collections as isize),
+        ),
+        (
+            "total-pause",
+            Object::Number(stats.total_pause.as_micros() as isize),
+        ),
+        (
+            "last-pause",
+            Object::Number(stats.last_pause.as_micros() as isize),
+        ),
+        ("heap-size", Object::Number(stats.heap_size as isize)),
+        (
+            "peak-heap-size",
+            Object::Number(stats.peak_heap_size as isize),
+        ),
+        ("live-bytes", live_bytes),
+        ("live-objects", live_objects),
+    ];
+    let mut ret = Object::Nil;
+    for (key, value) in entries.iter().rev() {
+        let key = vm.gc.symbol_intern(key);
+        let entry = vm.gc.cons(key, *value);
+        ret = vm.gc.cons(entry, ret);
+    }
+    ret
+}
+// Write the live objects and what keep them alive to the file as JSON. See heap_dump.rs for the format.
+fn heap_dump(vm: &mut Vm, args: &[Object]) -> Object {
+    let name: &str = "heap-dump";
+    check_argc!(name, args, 1);
+    let path = match args[0] {
+        Object::String(s) => s.string.to_owned(),
+        obj => panic!("{}: string required but got {}", name, obj),
+    };
+    let dump = vm.heap_dump();
+    let written = fs::File::create(&path).and_then(|file| {
+        let mut writer = io::BufWriter::new(file);
+        dump.write_json(&mut writer)?;
+        writer.flush()
+    });
+    if let Err(err) = written {
+        panic!("{}: can't write {}: {}", name, path, err);
+    }
+    Object::Unspecified
+}
+// Call thunk with the profiler on, print the profile and return what thunk returns.
+// When the Vm is already profiling, for example with --profile, thunk is just a part of that profile.
+#[cfg(feature = "profiler")]
+fn profile(vm: &mut Vm, args: &[Object]) -> Object {
+    let name: &str = "profile";
+    check_argc!(name, args, 1);
+    let thunk = args[0];
+    if !matches!(thunk, Object::Closure(_) | Object::Procedure(_)) {
+        panic!("{}: procedure required but got {}", name, thunk);
+    }
+    if vm.is_profiling() {
+        return vm.call_closure(thunk, &[]);
+    }
+    vm.start_profile();
+    // Stop the profiler even when thunk raises an error.
+    let ret = panic::catch_unwind(panic::AssertUnwindSafe(|| vm.call_closure(thunk, &[])));
+    let profile = vm.stop_profile();
+    match ret {
+        Ok(ret) => {
+            if let Some(profile) = profile {
+                print!("{}", profile);
+            }
+            ret
+        }
+        Err(payload) => panic::resume_unwind(payload),
+    }
+}
+#[cfg(not(feature = "profiler"))]
+fn profile(_vm: &mut Vm, _args: &[Object]) -> Object {
+    panic!("profile: rmosh is built without the profiler feature");
 }
 fn macroexpand_1(_vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "macroexpand-1";
@@ -1855,13 +2466,17 @@ fn is_procedure(_vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "procedure?";
     check_argc!(name, args, 1);
     match args[0] {
-        Object::Procedure(_) | Object::Closure(_) => Object::True,
+        Object::Procedure(_) | Object::Closure(_) | Object::Guardian(_) => Object::True,
         _ => Object::False,
     }
 }
-fn load(_vm: &mut Vm, args: &[Object]) -> Object {
+fn load(vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "load";
-    panic!("{}({}) not implemented", name, args.len());
+    check_argc!(name, args, 1);
+    match args[0] {
+        Object::String(path) => vm.load_file(&path.string),
+        obj => panic!("{}: string required but got {}", name, obj),
+    }
 }
 fn is_symbol(_vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "symbol?";
@@ -1990,16 +2605,18 @@ fn vector_to_list(vm: &mut Vm, args: &[Object]) -> Object {
         }
     }
 }
-fn set_source_info_destructive(_vm: &mut Vm, args: &[Object]) -> Object {
+fn set_source_info_destructive(vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "set-source-info!";
     check_argc!(name, args, 2);
     match args[0] {
         Object::Pair(mut p) => {
             p.src = args[1];
+            vm.gc.write_barrier(p, args[1]);
             args[0]
         }
         Object::Closure(mut c) => {
             c.src = args[1];
+            vm.gc.write_barrier(c, args[1]);
             args[0]
         }        
         obj => {
@@ -2025,7 +2642,12 @@ fn start_process(_vm: &mut Vm, args: &[Object]) -> Object {
 }
 fn get_closure_name(_vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "%get-closure-name";
-    panic!("{}({}) not implemented", name, args.len());
+    check_argc!(name, args, 1);
+    match args[0] {
+        Object::Closure(closure) => closure.name(),
+        Object::Procedure(_) => Object::False,
+        obj => panic!("{}: procedure required but got {}", name, obj),
+    }
 }
 fn append(vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "append";
@@ -2051,7 +2673,7 @@ fn append2(_vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "append2";
     panic!("{}({}) not implemented", name, args.len());
 }
-fn append_destructive(_vm: &mut Vm, args: &[Object]) -> Object {
+fn append_destructive(vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "append!";
     match args {
         &[] => Object::Nil,
@@ -2065,6 +2687,9 @@ fn append_destructive(_vm: &mut Vm, args: &[Object]) -> Object {
                 if !args[i as usize].is_list() {
                     panic!("{}: list required but got {}", name, args[i as usize]);
                 }
+                if let Object::Pair(last) = Pair::last_pair(args[i as usize]) {
+                    vm.gc.write_barrier(last, ret);
+                }
                 ret = Pair::append_destructive(args[i as usize], ret);
                 i = i - 1;
             }
@@ -2072,63 +2697,463 @@ fn append_destructive(_vm: &mut Vm, args: &[Object]) -> Object {
         }
     }
 }
-fn pass3_find_free(_vm: &mut Vm, args: &[Object]) -> Object {
+// The compiler procedures below work on the data structures of boot/compiler.scm.
+// IForms are vectors tagged by their first element, and a code builder is (array data length).
+const TAG_CONST: isize = 0;
+const TAG_LET: isize = 2;
+const TAG_SEQ: isize = 3;
+const TAG_LAMBDA: isize = 4;
+const TAG_LOCAL_REF: isize = 5;
+const TAG_LOCAL_ASSIGN: isize = 6;
+const TAG_GLOBAL_REF: isize = 7;
+const TAG_GLOBAL_ASSIGN: isize = 8;
+const TAG_UNDEF: isize = 9;
+const TAG_IF: isize = 10;
+const TAG_ASM: isize = 11;
+const TAG_DEFINE: isize = 12;
+const TAG_CALL_CC: isize = 13;
+const TAG_CALL: isize = 14;
+const TAG_LABEL: isize = 15;
+const TAG_LIST: isize = 16;
+const TAG_IT: isize = 17;
+const TAG_RECEIVE: isize = 18;
+
+fn iform_vector(name: &str, iform: Object) -> (GcRef<Vector>, isize) {
+    match iform {
+        Object::Vector(v) => match v.data.first() {
+            Some(Object::Number(tag)) => (v, *tag),
+            _ => panic!("{}: unknown iform {}", name, iform),
+        },
+        _ => panic!("{}: iform required but got {}", name, iform),
+    }
+}
+
+fn is_label(obj: Object) -> bool {
+    match obj {
+        Object::Vector(v) => v.data.first() == Some(&Object::Number(TAG_LABEL)),
+        _ => false,
+    }
+}
+
+fn memq_list(obj: Object, list: Object) -> bool {
+    let mut list = list;
+    while let Object::Pair(p) = list {
+        if p.car == obj {
+            return true;
+        }
+        list = p.cdr;
+    }
+    false
+}
+
+// Same as uniq in boot/compiler.scm, which returns the unique objects in reverse order.
+fn uniq(gc: &mut Gc, objects: &[Object]) -> Object {
+    let mut seen: Vec<Object> = vec![];
+    let mut ret = Object::Nil;
+    for &obj in objects {
+        if !seen.contains(&obj) {
+            seen.push(obj);
+            ret = gc.cons(obj, ret);
+        }
+    }
+    ret
+}
+
+fn exists_in_can_frees(sym: Object, can_frees: Object) -> bool {
+    let mut can_frees = can_frees;
+    while let Object::Pair(p) = can_frees {
+        if memq_list(sym, p.car) {
+            return true;
+        }
+        can_frees = p.cdr;
+    }
+    false
+}
+
+fn find_free_rec_list(
+    list: Object,
+    locals: Object,
+    can_frees: Object,
+    labels_seen: &mut Vec<Object>,
+    frees: &mut Vec<Object>,
+) {
+    let mut list = list;
+    while let Object::Pair(p) = list {
+        find_free_rec(p.car, locals, can_frees, labels_seen, frees);
+        list = p.cdr;
+    }
+}
+
+// Collect free variable symbols in the order the Scheme version appends them.
+fn find_free_rec(
+    iform: Object,
+    locals: Object,
+    can_frees: Object,
+    labels_seen: &mut Vec<Object>,
+    frees: &mut Vec<Object>,
+) {
+    let (v, tag) = iform_vector("pass3/find-free", iform);
+    match tag {
+        TAG_CONST | TAG_UNDEF | TAG_IT => {}
+        TAG_LET => {
+            find_free_rec_list(v.data[3], locals, can_frees, labels_seen, frees);
+            find_free_rec(v.data[4], v.data[2], can_frees, labels_seen, frees);
+        }
+        TAG_RECEIVE => {
+            find_free_rec(v.data[4], locals, can_frees, labels_seen, frees);
+            find_free_rec(v.data[5], v.data[1], can_frees, labels_seen, frees);
+        }
+        TAG_SEQ => find_free_rec_list(v.data[1], locals, can_frees, labels_seen, frees),
+        TAG_LAMBDA => find_free_rec(v.data[6], v.data[5], can_frees, labels_seen, frees),
+        TAG_LOCAL_ASSIGN => {
+            let (lvar, _) = iform_vector("pass3/find-free", v.data[1]);
+            let sym = lvar.data[1];
+            if exists_in_can_frees(sym, can_frees) {
+                frees.push(sym);
+            }
+            find_free_rec(v.data[2], locals, can_frees, labels_seen, frees);
+        }
+        TAG_LOCAL_REF => {
+            let (lvar, _) = iform_vector("pass3/find-free", v.data[1]);
+            let sym = lvar.data[1];
+            if !memq_list(sym, locals) && exists_in_can_frees(sym, can_frees) {
+                frees.push(sym);
+            }
+        }
+        TAG_GLOBAL_REF => {
+            let sym = v.data[1];
+            if exists_in_can_frees(sym, can_frees) {
+                frees.push(sym);
+            }
+        }
+        TAG_IF => {
+            for &branch in &v.data[1..4] {
+                find_free_rec(branch, locals, can_frees, labels_seen, frees);
+            }
+        }
+        TAG_ASM => find_free_rec_list(v.data[2], locals, can_frees, labels_seen, frees),
+        TAG_DEFINE | TAG_GLOBAL_ASSIGN => {
+            find_free_rec(v.data[2], locals, can_frees, labels_seen, frees)
+        }
+        TAG_CALL => {
+            // Arguments are evaluated before the procedure.
+            find_free_rec_list(v.data[2], locals, can_frees, labels_seen, frees);
+            find_free_rec(v.data[1], locals, can_frees, labels_seen, frees);
+        }
+        TAG_CALL_CC => find_free_rec(v.data[1], locals, can_frees, labels_seen, frees),
+        TAG_LIST => find_free_rec_list(v.data[1], locals, can_frees, labels_seen, frees),
+        TAG_LABEL => {
+            if !labels_seen.contains(&iform) {
+                labels_seen.push(iform);
+                find_free_rec(v.data[1], locals, can_frees, labels_seen, frees);
+                labels_seen.pop();
+            }
+        }
+        _ => panic!("pass3/find-free: unknown iform {}", tag),
+    }
+}
+
+fn find_sets_rec_list(
+    list: Object,
+    lvars: Object,
+    labels_seen: &mut Vec<Object>,
+    sets: &mut Vec<Object>,
+) {
+    let mut list = list;
+    while let Object::Pair(p) = list {
+        find_sets_rec(p.car, lvars, labels_seen, sets);
+        list = p.cdr;
+    }
+}
+
+// Collect assigned lvars in the order the Scheme version appends them.
+fn find_sets_rec(
+    iform: Object,
+    lvars: Object,
+    labels_seen: &mut Vec<Object>,
+    sets: &mut Vec<Object>,
+) {
+    let (v, tag) = iform_vector("pass3/find-sets", iform);
+    match tag {
+        TAG_CONST | TAG_LOCAL_REF | TAG_GLOBAL_REF | TAG_UNDEF | TAG_IT => {}
+        TAG_LET => {
+            find_sets_rec_list(v.data[3], lvars, labels_seen, sets);
+            find_sets_rec(v.data[4], lvars, labels_seen, sets);
+        }
+        TAG_RECEIVE => {
+            find_sets_rec(v.data[4], lvars, labels_seen, sets);
+            find_sets_rec(v.data[5], lvars, labels_seen, sets);
+        }
+        TAG_SEQ => find_sets_rec_list(v.data[1], lvars, labels_seen, sets),
+        TAG_LAMBDA => find_sets_rec(v.data[6], lvars, labels_seen, sets),
+        TAG_LOCAL_ASSIGN => {
+            let lvar = v.data[1];
+            if memq_list(lvar, lvars) {
+                sets.push(lvar);
+            }
+            find_sets_rec(v.data[2], lvars, labels_seen, sets);
+        }
+        TAG_IF => {
+            for &branch in &v.data[1..4] {
+                find_sets_rec(branch, lvars, labels_seen, sets);
+            }
+        }
+        TAG_ASM => find_sets_rec_list(v.data[2], lvars, labels_seen, sets),
+        TAG_DEFINE | TAG_GLOBAL_ASSIGN => find_sets_rec(v.data[2], lvars, labels_seen, sets),
+        TAG_CALL => {
+            find_sets_rec_list(v.data[2], lvars, labels_seen, sets);
+            find_sets_rec(v.data[1], lvars, labels_seen, sets);
+        }
+        TAG_CALL_CC => find_sets_rec(v.data[1], lvars, labels_seen, sets),
+        TAG_LIST => find_sets_rec_list(v.data[1], lvars, labels_seen, sets),
+        TAG_LABEL => {
+            if !labels_seen.contains(&iform) {
+                labels_seen.push(iform);
+                find_sets_rec(v.data[1], lvars, labels_seen, sets);
+                labels_seen.pop();
+            }
+        }
+        _ => panic!("pass3/find-sets: unknown iform {}", tag),
+    }
+}
+
+fn pass3_find_free(vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "pass3/find-free";
-    panic!("{}({}) not implemented", name, args.len());
+    check_argc!(name, args, 3);
+    let mut frees = vec![];
+    find_free_rec(args[0], args[1], args[2], &mut vec![], &mut frees);
+    uniq(&mut vm.gc, &frees)
 }
-fn pass3_find_sets(_vm: &mut Vm, args: &[Object]) -> Object {
+fn pass3_find_sets(vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "pass3/find-sets";
-    panic!("{}({}) not implemented", name, args.len());
+    check_argc!(name, args, 2);
+    let mut sets = vec![];
+    find_sets_rec(args[0], args[1], &mut vec![], &mut sets);
+    uniq(&mut vm.gc, &sets)
 }
-fn pass4_fixup_labels(_vm: &mut Vm, args: &[Object]) -> Object {
+// Replace labels with relative jump offsets and thread jumps, as pass4/fixup-labels in boot/compiler.scm does.
+fn pass4_fixup_labels(vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "pass4/fixup-labels";
-    panic!("{}({}) not implemented", name, args.len());
+    check_argc!(name, args, 1);
+    let code = match args[0] {
+        Object::Vector(v) => v,
+        obj => panic!("{}: vector required but got {}", name, obj),
+    };
+
+    // Remove labels, remembering where they were.
+    let len = code.data.len();
+    let mut ret = vec![Object::Instruction(Op::Nop); len];
+    let mut labels: HashMap<Object, isize> = HashMap::new();
+    let mut i = 0;
+    let mut j = 0;
+    while i < len {
+        match code.data[i] {
+            Object::Instruction(op) => {
+                let end = i + op.operand_count() + 1;
+                if end > len {
+                    panic!("{}: {} lacks operands", name, op);
+                }
+                ret[j..j + end - i].copy_from_slice(&code.data[i..end]);
+                j += end - i;
+                i = end;
+            }
+            label if is_label(label) => {
+                labels.insert(label, j as isize);
+                i += 1;
+            }
+            insn => panic!("{}: instruction required but got {}", name, insn),
+        }
+    }
+
+    // Jump instructions refer to labels. Replace them with offsets.
+    let mut i = 0;
+    while i < len {
+        let (op, operand_index) = match ret[i] {
+            Object::Instruction(Op::UnfixedJump) => (Op::LocalJmp, 1),
+            Object::Instruction(
+                op @ (Op::Closure
+                | Op::Test
+                | Op::BranchNotNumberEqual
+                | Op::BranchNotNull
+                | Op::BranchNotLe
+                | Op::BranchNotGe
+                | Op::BranchNotLt
+                | Op::BranchNotGt
+                | Op::BranchNotEq
+                | Op::BranchNotEqv
+                | Op::BranchNotEqual
+                | Op::NotTest
+                | Op::Frame
+                | Op::PushFrame),
+            ) => (op, 1),
+            Object::Instruction(op @ (Op::ReferLocalBranchNotNull | Op::ReferLocalBranchNotLt)) => {
+                (op, 2)
+            }
+            Object::Instruction(
+                op @ (Op::ReferLocalPushConstantBranchNotLe
+                | Op::ReferLocalPushConstantBranchNotGe
+                | Op::ReferLocalPushConstantBranchNotNumberEqual),
+            ) => (op, 3),
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+        match ret
+            .get(i + operand_index)
+            .and_then(|label| labels.get(label))
+        {
+            Some(&label) => {
+                ret[i] = Object::Instruction(op);
+                ret[i + operand_index] = Object::Number(label - (i + operand_index) as isize);
+                i += operand_index + 1;
+            }
+            None => i += 1,
+        }
+    }
+    peephole_optimization(name, &mut ret);
+    vm.gc.new_vector(&ret)
+}
+
+// Jump to jump is replaced with one jump, and jump to return is replaced with return.
+fn peephole_optimization(name: &str, code: &mut [Object]) {
+    let destination = |code: &[Object], i: usize| -> (isize, usize) {
+        let offset = code[i + 1].to_number() + 1;
+        (offset, (i as isize + offset) as usize)
+    };
+    let mut i = 0;
+    while i < code.len() {
+        match code[i] {
+            Object::Instruction(op)
+                if op == Op::LocalJmp
+                    || (op == Op::Frame && matches!(code[i + 1], Object::Number(_))) =>
+            {
+                let (offset, dest) = destination(code, i);
+                match code[dest] {
+                    Object::Instruction(Op::LocalJmp) => {
+                        code[i + 1] = Object::Number(offset + code[dest + 1].to_number());
+                    }
+                    Object::Instruction(Op::Return) => {
+                        code[i] = Object::Instruction(Op::Return);
+                        code[i + 1] = code[dest + 1];
+                    }
+                    _ => {}
+                }
+                i += 2;
+            }
+            Object::Instruction(
+                Op::Test
+                | Op::BranchNotGe
+                | Op::BranchNotGt
+                | Op::BranchNotLt
+                | Op::BranchNotLe
+                | Op::BranchNotNumberEqual
+                | Op::BranchNotNull,
+            ) if matches!(code[i + 1], Object::Number(_)) => {
+                let (offset, dest) = destination(code, i);
+                if let Object::Instruction(Op::Test | Op::LocalJmp) = code[dest] {
+                    code[i + 1] = Object::Number(offset + code[dest + 1].to_number());
+                }
+                i += 2;
+            }
+            Object::Instruction(op) => i += op.operand_count() + 1,
+            insn => panic!("{}: instruction required but got {}", name, insn),
+        }
+    }
+}
+
+// Returns the data vector and the length of a code builder.
+fn code_builder_parts(name: &str, cb: Object) -> (GcRef<Vector>, GcRef<Pair>) {
+    if let Object::Pair(array) = cb {
+        if let Object::Pair(data) = array.cdr {
+            if let (Object::Vector(v), Object::Pair(length)) = (data.car, data.cdr) {
+                return (v, length);
+            }
+        }
+    }
+    panic!("{}: code builder required but got {}", name, cb)
+}
+
+fn code_builder_put(vm: &mut Vm, name: &str, cb: Object, objects: &[Object]) -> Object {
+    let (mut data, mut length) = code_builder_parts(name, cb);
+    let mut len = length.car.to_number() as usize;
+    for &obj in objects {
+        data.data[len] = obj;
+        vm.gc.write_barrier(data, obj);
+        len += 1;
+        // Keep a free slot as the Scheme version does.
+        if len >= data.data.len() {
+            data.data.resize(len * 2, Object::Unspecified);
+        }
+    }
+    length.car = Object::Number(len as isize);
+    Object::Unspecified
+}
+
+fn code_builder_to_vec(name: &str, cb: Object) -> Vec<Object> {
+    let (data, length) = code_builder_parts(name, cb);
+    data.data[..length.car.to_number() as usize].to_vec()
 }
-fn make_code_builder(_vm: &mut Vm, args: &[Object]) -> Object {
+
+fn make_code_builder(vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "make-code-builder";
-    println!("{}({}) not implemented", name, args.len());
-    return Object::False;
+    check_argc!(name, args, 0);
+    let array = vm.gc.symbol_intern("array");
+    let data = vm.gc.new_vector(&vec![Object::Unspecified; 2]);
+    vm.gc.list3(array, data, Object::Number(0))
 }
-fn code_builder_put_extra1_destructive(_vm: &mut Vm, args: &[Object]) -> Object {
+fn code_builder_put_extra1_destructive(vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "code-builder-put-extra1!";
-    panic!("{}({}) not implemented", name, args.len());
+    check_argc!(name, args, 2);
+    code_builder_put(vm, name, args[0], &args[1..])
 }
-fn code_builder_put_extra2_destructive(_vm: &mut Vm, args: &[Object]) -> Object {
+fn code_builder_put_extra2_destructive(vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "code-builder-put-extra2!";
-    panic!("{}({}) not implemented", name, args.len());
+    check_argc!(name, args, 3);
+    code_builder_put(vm, name, args[0], &args[1..])
 }
-fn code_builder_put_extra3_destructive(_vm: &mut Vm, args: &[Object]) -> Object {
+fn code_builder_put_extra3_destructive(vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "code-builder-put-extra3!";
-    panic!("{}({}) not implemented", name, args.len());
+    check_argc!(name, args, 4);
+    code_builder_put(vm, name, args[0], &args[1..])
 }
-fn code_builder_put_extra4_destructive(_vm: &mut Vm, args: &[Object]) -> Object {
+fn code_builder_put_extra4_destructive(vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "code-builder-put-extra4!";
-    panic!("{}({}) not implemented", name, args.len());
+    check_argc!(name, args, 5);
+    code_builder_put(vm, name, args[0], &args[1..])
 }
-fn code_builder_put_extra5_destructive(_vm: &mut Vm, args: &[Object]) -> Object {
+fn code_builder_put_extra5_destructive(vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "code-builder-put-extra5!";
-    panic!("{}({}) not implemented", name, args.len());
+    check_argc!(name, args, 6);
+    code_builder_put(vm, name, args[0], &args[1..])
 }
-fn code_builder_append_destructive(_vm: &mut Vm, args: &[Object]) -> Object {
+fn code_builder_append_destructive(vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "code-builder-append!";
-    panic!("{}({}) not implemented", name, args.len());
+    check_argc!(name, args, 2);
+    let code = code_builder_to_vec(name, args[1]);
+    code_builder_put(vm, name, args[0], &code);
+    Object::Nil
 }
-fn code_builder_emit(_vm: &mut Vm, args: &[Object]) -> Object {
+fn code_builder_emit(vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "code-builder-emit";
-    panic!("{}({}) not implemented", name, args.len());
+    check_argc!(name, args, 1);
+    let code = code_builder_to_vec(name, args[0]);
+    vm.gc.listn(&code)
 }
-fn code_builder_put_insn_arg0_destructive(_vm: &mut Vm, args: &[Object]) -> Object {
+fn code_builder_put_insn_arg0_destructive(vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "code-builder-put-insn-arg0!";
-    panic!("{}({}) not implemented", name, args.len());
+    check_argc!(name, args, 2);
+    code_builder_put(vm, name, args[0], &args[1..])
 }
-fn code_builder_put_insn_arg1_destructive(_vm: &mut Vm, args: &[Object]) -> Object {
+fn code_builder_put_insn_arg1_destructive(vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "code-builder-put-insn-arg1!";
-    println!("arg1={} {} {}", args[0], args[1], args[2]);
-    panic!("{}({}) not implemented", name, args.len());
+    check_argc!(name, args, 3);
+    code_builder_put(vm, name, args[0], &args[1..])
 }
-fn code_builder_put_insn_arg2_destructive(_vm: &mut Vm, args: &[Object]) -> Object {
+fn code_builder_put_insn_arg2_destructive(vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "code-builder-put-insn-arg2!";
-    panic!("{}({}) not implemented", name, args.len());
+    check_argc!(name, args, 4);
+    code_builder_put(vm, name, args[0], &args[1..])
 }
 fn length(_vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "length";
@@ -2171,25 +3196,83 @@ fn list_to_vector(vm: &mut Vm, args: &[Object]) -> Object {
     }
     vm.gc.new_vector(&v)
 }
-fn pass3_compile_refer(_vm: &mut Vm, args: &[Object]) -> Object {
+fn pass3_compile_refer(vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "pass3/compile-refer";
-    panic!("{}({}) not implemented", name, args.len());
+    check_argc!(name, args, 4);
+    let (cb, var) = (args[0], args[1]);
+    for (op, vars) in [(Op::ReferLocal, args[2]), (Op::ReferFree, args[3])] {
+        let mut vars = vars;
+        let mut index = 0;
+        while let Object::Pair(p) = vars {
+            if p.car == var {
+                code_builder_put(
+                    vm,
+                    name,
+                    cb,
+                    &[Object::Instruction(op), Object::Number(index)],
+                );
+                return Object::Number(0);
+            }
+            vars = p.cdr;
+            index += 1;
+        }
+    }
+    panic!("{}: bug? Unknown lvar {}", name, var)
 }
 fn pass1_find_symbol_in_lvars(_vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "pass1/find-symbol-in-lvars";
-    panic!("{}({}) not implemented", name, args.len());
+    check_argc!(name, args, 2);
+    let mut lvars = args[1];
+    while let Object::Pair(p) = lvars {
+        let (lvar, _) = iform_vector(name, p.car);
+        if lvar.data[1] == args[0] {
+            return p.car;
+        }
+        lvars = p.cdr;
+    }
+    Object::False
 }
-fn label(_vm: &mut Vm, args: &[Object]) -> Object {
+fn label(vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "$label";
-    panic!("{}({}) not implemented", name, args.len());
+    check_argc!(name, args, 1);
+    vm.gc
+        .new_vector(&vec![Object::Number(TAG_LABEL), args[0], Object::False])
 }
-fn local_ref(_vm: &mut Vm, args: &[Object]) -> Object {
+fn local_ref(vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "$local-ref";
-    panic!("{}({}) not implemented", name, args.len());
-}
-fn list_transposeadd(_vm: &mut Vm, args: &[Object]) -> Object {
+    check_argc!(name, args, 1);
+    let (mut lvar, _) = iform_vector(name, args[0]);
+    // Increment the ref-count of the lvar.
+    lvar.data[3] = Object::Number(lvar.data[3].to_number() + 1);
+    vm.gc
+        .new_vector(&vec![Object::Number(TAG_LOCAL_REF), args[0]])
+}
+// (list-transpose+ '(1 2) '(3 4)) => ((1 3) (2 4)). Returns #f unless all arguments are lists of the same length.
+fn list_transposeadd(vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "list-transpose+";
-    panic!("{}({}) not implemented", name, args.len());
+    check_argc_at_least!(name, args, 1);
+    let mut lists: Vec<Vec<Object>> = vec![];
+    for &arg in args {
+        if !arg.is_list() {
+            return Object::False;
+        }
+        let mut elements = vec![];
+        let mut obj = arg;
+        while let Object::Pair(p) = obj {
+            elements.push(p.car);
+            obj = p.cdr;
+        }
+        if !lists.is_empty() && elements.len() != lists[0].len() {
+            return Object::False;
+        }
+        lists.push(elements);
+    }
+    let mut rows = vec![];
+    for i in 0..lists[0].len() {
+        let row: Vec<Object> = lists.iter().map(|elements| elements[i]).collect();
+        rows.push(vm.gc.listn(&row));
+    }
+    vm.gc.listn(&rows)
 }
 fn symbol_value(_vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "symbol-value";
@@ -2256,7 +3339,7 @@ fn hashtable_copy(vm: &mut Vm, args: &[Object]) -> Object {
     check_argc_between!(name, args, 1, 2);
     match args[0] {
         Object::EqHashtable(hashtable) => {
-            let mut ret = vm.gc.alloc(EqHashtable::new());
+            let mut ret = vm.gc.alloc(EqHashtable::with_weakness(hashtable.weakness));
             for (key, value) in &hashtable.hash_map {
                 ret.set(*key, *value);
             }
@@ -3220,17 +4303,38 @@ fn vector_fill_destructive(_vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "vector-fill!";
     panic!("{}({}) not implemented", name, args.len());
 }
-fn ungensym(_vm: &mut Vm, args: &[Object]) -> Object {
+// The symbol whose name gensym took for a gensym, or the symbol itself.
+fn ungensym(vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "ungensym";
-    panic!("{}({}) not implemented", name, args.len());
+    check_argc!(name, args, 1);
+    match args[0] {
+        Object::Symbol(symbol) => match symbol.string.split_once('@') {
+            Some((_, base)) => vm.gc.symbol_intern(base),
+            None => args[0],
+        },
+        obj => panic!("{}: symbol required but got {}", name, obj),
+    }
 }
 fn disasm(_vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "disasm";
-    panic!("{}({}) not implemented", name, args.len());
+    check_argc!(name, args, 1);
+    match args[0] {
+        Object::Closure(closure) => {
+            print!("{}", disassemble_closure(closure));
+            Object::Unspecified
+        }
+        obj => panic!("{}: closure required but got {}", name, obj),
+    }
 }
-fn print_stack(_vm: &mut Vm, args: &[Object]) -> Object {
+// Print the active frames, innermost first, each with the code of its closure.
+fn print_stack(vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "print-stack";
-    panic!("{}({}) not implemented", name, args.len());
+    check_argc!(name, args, 0);
+    for (i, frame) in vm.backtrace().frames.iter().enumerate() {
+        println!("{:3}  {}", i, frame);
+        print!("{}", disassemble_closure(frame.closure));
+    }
+    Object::Unspecified
 }
 fn is_fast_equal(_vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "fast-equal?";
@@ -3855,9 +4959,14 @@ fn join_wraps(_vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "join-wraps";
     panic!("{}({}) not implemented", name, args.len());
 }
-fn gensym_prefix_set_destructive(_vm: &mut Vm, args: &[Object]) -> Object {
+fn gensym_prefix_set_destructive(vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "gensym-prefix-set!";
-    panic!("{}({}) not implemented", name, args.len());
+    check_argc!(name, args, 1);
+    match args[0] {
+        Object::Symbol(prefix) => vm.gc.set_gensym_prefix(&prefix.string),
+        obj => panic!("{}: symbol required but got {}", name, obj),
+    }
+    Object::Unspecified
 }
 fn current_dynamic_winders(_vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "current-dynamic-winders";
@@ -3965,12 +5074,13 @@ fn get_annotation(_vm: &mut Vm, args: &[Object]) -> Object {
         }
     }
 }
-fn set_annotation_destructive(_vm: &mut Vm, args: &[Object]) -> Object {
+fn set_annotation_destructive(vm: &mut Vm, args: &[Object]) -> Object {
     let name: &str = "set-annotation!";
     check_argc!(name, args, 2);
     match args[0] {
         Object::Pair(mut p) => {
             p.src = args[1];
+            vm.gc.write_barrier(p, args[1]);
             Object::Unspecified
         }
         obj => {
@@ -0,0 +1,361 @@
+/// Profiler, enabled by the profiler feature.
+/// While it is running, the VM counts executed instructions and calls per closure,
+/// and a timer thread asks the VM to sample the current call stack every SAMPLE_INTERVAL.
+/// Closures are identified by their code, so all closures of the same lambda are counted together.
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    gc::GcRef,
+    objects::{Closure, Object},
+    op::Op,
+};
+
+pub const SAMPLE_INTERVAL: Duration = Duration::from_millis(1);
+
+const TOP_LEVEL: &str = "<top-level>";
+
+// Code of a closure body. Null for the top-level.
+type CodeKey = *const Object;
+
+#[derive(Default)]
+struct Entry {
+    name: String,
+    location: String,
+    calls: u64,
+    instructions: u64,
+}
+
+pub struct Profiler {
+    // Set by the timer thread when the VM should take a sample.
+    sample_flag: Arc<AtomicBool>,
+    stop_flag: Arc<AtomicBool>,
+    timer: Option<JoinHandle<()>>,
+    start: Instant,
+    entries: HashMap<CodeKey, Entry>,
+    // Number of samples of each call stack, outermost first.
+    stacks: HashMap<Vec<CodeKey>, u64>,
+    op_counts: HashMap<Op, u64>,
+    // The display closure of the last instruction and its code, to skip looking it up again.
+    last_dc: Object,
+    last_key: CodeKey,
+}
+
+impl Profiler {
+    pub fn start() -> Self {
+        let sample_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let timer = {
+            let sample_flag = sample_flag.clone();
+            let stop_flag = stop_flag.clone();
+            thread::spawn(move || {
+                while !stop_flag.load(Ordering::Relaxed) {
+                    thread::sleep(SAMPLE_INTERVAL);
+                    sample_flag.store(true, Ordering::Relaxed);
+                }
+            })
+        };
+        Profiler {
+            sample_flag,
+            stop_flag,
+            timer: Some(timer),
+            start: Instant::now(),
+            entries: HashMap::new(),
+            stacks: HashMap::new(),
+            op_counts: HashMap::new(),
+            last_dc: Object::Unspecified,
+            last_key: std::ptr::null(),
+        }
+    }
+
+    // Count an instruction executed in dc.
+    pub fn count_op(&mut self, op: Op, dc: Object) {
+        if dc != self.last_dc {
+            self.last_dc = dc;
+            self.last_key = self.key(dc);
+        }
+        let key = self.last_key;
+        self.entries.entry(key).or_default().instructions += 1;
+        *self.op_counts.entry(op).or_insert(0) += 1;
+    }
+
+    pub fn count_call(&mut self, closure: GcRef<Closure>) {
+        let key = self.key(Object::Closure(closure));
+        self.entries.entry(key).or_default().calls += 1;
+    }
+
+    // The profiler keeps the last display closure to compare with the next one, so it must not be freed.
+    pub fn last_dc(&self) -> Object {
+        self.last_dc
+    }
+
+    // True once per SAMPLE_INTERVAL.
+    pub fn should_sample(&self) -> bool {
+        self.sample_flag.swap(false, Ordering::Relaxed)
+    }
+
+    // Record a sample. callers are the display closures of the frames on the stack, outermost first.
+    pub fn sample(&mut self, callers: &[Object], dc: Object) {
+        let mut stack: Vec<CodeKey> = callers.iter().map(|&caller| self.key(caller)).collect();
+        stack.push(self.key(dc));
+        *self.stacks.entry(stack).or_insert(0) += 1;
+    }
+
+    pub fn stop(mut self) -> Profile {
+        self.stop_flag.store(true, Ordering::Relaxed);
+        if let Some(timer) = self.timer.take() {
+            let _ = timer.join();
+        }
+        let elapsed = self.start.elapsed();
+        let mut functions: HashMap<CodeKey, Function> = self
+            .entries
+            .drain()
+            .map(|(key, entry)| (key, Function::new(entry)))
+            .collect();
+        let mut samples = 0;
+        for (stack, &count) in &self.stacks {
+            samples += count;
+            if let Some(&innermost) = stack.last() {
+                functions.entry(innermost).or_default().self_samples += count;
+            }
+            // Recursive calls count once per sample.
+            let mut seen = vec![];
+            let mut seen_edges = vec![];
+            for (i, &key) in stack.iter().enumerate() {
+                if !seen.contains(&key) {
+                    seen.push(key);
+                    functions.entry(key).or_default().total_samples += count;
+                }
+                if let Some(&callee) = stack.get(i + 1) {
+                    if !seen_edges.contains(&(key, callee)) {
+                        seen_edges.push((key, callee));
+                        *functions
+                            .entry(key)
+                            .or_default()
+                            .callees
+                            .entry(callee)
+                            .or_insert(0) += count;
+                        *functions
+                            .entry(callee)
+                            .or_default()
+                            .callers
+                            .entry(key)
+                            .or_insert(0) += count;
+                    }
+                }
+            }
+        }
+        let names: HashMap<CodeKey, String> = functions
+            .iter()
+            .map(|(&key, function)| (key, function.name.clone()))
+            .collect();
+        let mut functions: Vec<Function> = functions
+            .into_values()
+            .map(|mut function| {
+                function.resolve_names(&names);
+                function
+            })
+            .collect();
+        functions.sort_by(|a, b| {
+            (b.self_samples, b.instructions, &a.name).cmp(&(
+                a.self_samples,
+                a.instructions,
+                &b.name,
+            ))
+        });
+        let mut op_counts: Vec<(Op, u64)> = self.op_counts.drain().collect();
+        op_counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.to_string().cmp(&b.0.to_string())));
+        Profile {
+            elapsed,
+            samples,
+            functions,
+            op_counts,
+        }
+    }
+
+    // Code of the closure dc belongs to. Display closures of let refer the closure by prev.
+    fn key(&mut self, dc: Object) -> CodeKey {
+        let mut obj = dc;
+        while let Object::Closure(closure) = obj {
+            if closure.ops_len > 0 {
+                let key = closure.ops;
+                self.entries.entry(key).or_insert_with(|| describe(closure));
+                return key;
+            }
+            obj = closure.prev;
+        }
+        let key = std::ptr::null();
+        self.entries.entry(key).or_insert_with(|| Entry {
+            name: TOP_LEVEL.to_string(),
+            ..Entry::default()
+        });
+        key
+    }
+}
+
+// Name and location of a closure body.
+fn describe(closure: GcRef<Closure>) -> Entry {
+    match (closure.name(), closure.src) {
+        (name, Object::Pair(_)) => Entry {
+            name: if name.is_false() {
+                Object::Closure(closure).to_write_string()
+            } else {
+                name.to_write_string()
+            },
+            location: closure.location().unwrap_or_default(),
+            ..Entry::default()
+        },
+        // Top-level code runs in a display closure without source info.
+        _ => Entry {
+            name: TOP_LEVEL.to_string(),
+            ..Entry::default()
+        },
+    }
+}
+
+#[derive(Default)]
+struct Function {
+    name: String,
+    location: String,
+    calls: u64,
+    instructions: u64,
+    self_samples: u64,
+    total_samples: u64,
+    callers: HashMap<CodeKey, u64>,
+    callees: HashMap<CodeKey, u64>,
+    // Names of callers and callees with their samples, most samples first.
+    caller_names: Vec<(String, u64)>,
+    callee_names: Vec<(String, u64)>,
+}
+
+impl Function {
+    fn new(entry: Entry) -> Self {
+        Function {
+            name: entry.name,
+            location: entry.location,
+            calls: entry.calls,
+            instructions: entry.instructions,
+            ..Function::default()
+        }
+    }
+
+    fn resolve_names(&mut self, names: &HashMap<CodeKey, String>) {
+        let resolve = |edges: &HashMap<CodeKey, u64>| {
+            let mut named: Vec<(String, u64)> = edges
+                .iter()
+                .map(|(key, &count)| (names.get(key).cloned().unwrap_or_default(), count))
+                .collect();
+            named.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+            named
+        };
+        self.caller_names = resolve(&self.callers);
+        self.callee_names = resolve(&self.callees);
+    }
+}
+
+// Result of profiling. Display shows the flat profile, the call graph and the instruction counts.
+pub struct Profile {
+    elapsed: Duration,
+    samples: u64,
+    functions: Vec<Function>,
+    op_counts: Vec<(Op, u64)>,
+}
+
+impl Profile {
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    // Names of profiled closures, most sampled first.
+    pub fn names(&self) -> Vec<&str> {
+        self.functions.iter().map(|f| f.name.as_str()).collect()
+    }
+
+    // Number of calls of the closure named name.
+    pub fn calls(&self, name: &str) -> Option<u64> {
+        self.functions
+            .iter()
+            .find(|f| f.name == name)
+            .map(|f| f.calls)
+    }
+}
+
+impl Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let percent = |n: u64| {
+            if self.samples == 0 {
+                0.0
+            } else {
+                n as f64 * 100.0 / self.samples as f64
+            }
+        };
+        let msec = SAMPLE_INTERVAL.as_secs_f64() * 1000.0;
+        writeln!(
+            f,
+            ";; flat profile: {} samples every {}ms in {:.3}s",
+            self.samples,
+            msec,
+            self.elapsed.as_secs_f64()
+        )?;
+        writeln!(
+            f,
+            " time%      msec      calls  instructions  location                  name"
+        )?;
+        for function in &self.functions {
+            writeln!(
+                f,
+                "{:6.1} {:9.0} {:10} {:13}  {:24}  {}",
+                percent(function.self_samples),
+                function.self_samples as f64 * msec,
+                function.calls,
+                function.instructions,
+                function.location,
+                function.name
+            )?;
+        }
+
+        writeln!(
+            f,
+            "\n;; call graph: total% self% name, then its callers and callees"
+        )?;
+        let mut by_total: Vec<&Function> = self
+            .functions
+            .iter()
+            .filter(|f| f.total_samples > 0)
+            .collect();
+        by_total.sort_by(|a, b| {
+            b.total_samples
+                .cmp(&a.total_samples)
+                .then(a.name.cmp(&b.name))
+        });
+        for function in by_total {
+            writeln!(
+                f,
+                "{:6.1} {:6.1}  {}",
+                percent(function.total_samples),
+                percent(function.self_samples),
+                function.name
+            )?;
+            for (name, count) in &function.caller_names {
+                writeln!(f, "{:15}<- {:5.1}  {}", "", percent(*count), name)?;
+            }
+            for (name, count) in &function.callee_names {
+                writeln!(f, "{:15}-> {:5.1}  {}", "", percent(*count), name)?;
+            }
+        }
+
+        writeln!(f, "\n;; instructions")?;
+        for (op, count) in &self.op_counts {
+            writeln!(f, "{:12}  {}", count, op)?;
+        }
+        Ok(())
+    }
+}
@@ -15,3 +15,9 @@ pub fn read(gc: &mut Box<Gc>, s: &str) -> Result<Object, ReadError> {
     s.push('\0');
     DatumParser::new().parse(gc, lexer::Lexer::new(s.as_bytes()))
 }
+
+// Read all data in s, like the contents of a file, as a list.
+pub fn read_all(gc: &mut Box<Gc>, s: &str) -> Result<Object, ReadError> {
+    // The newline ends a comment on the last line.
+    read(gc, &format!("({}\n)", s))
+}
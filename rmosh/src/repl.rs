@@ -0,0 +1,221 @@
+use std::{
+    env,
+    panic::{self, AssertUnwindSafe},
+    path::PathBuf,
+    time::Instant,
+};
+
+use rustyline::{error::ReadlineError, Editor};
+
+use crate::{
+    objects::Object,
+    read::read_all,
+    vm::{Exit, Vm},
+};
+
+const PROMPT: &str = "rmosh> ";
+const CONTINUATION_PROMPT: &str = "... ";
+
+const HELP: &str = ",load <file>   load a Scheme file
+,time <expr>   evaluate expr and show the elapsed time
+,disasm <expr> disassemble the procedure expr evaluates to
+,help          show this help
+,quit          exit the REPL";
+
+/// Interactive read-eval-print loop. Returns the exit status.
+pub fn run(vm: &mut Vm) -> i32 {
+    let mut editor = Editor::<()>::new();
+    let history = history_path();
+    if let Some(path) = &history {
+        // It's fine if there is no history yet.
+        let _ = editor.load_history(path);
+    }
+
+    // Errors are reported by the REPL, so we don't want the default panic message.
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+
+    let mut input = String::new();
+    let mut status = 0;
+    loop {
+        let prompt = if input.is_empty() {
+            PROMPT
+        } else {
+            CONTINUATION_PROMPT
+        };
+        match editor.readline(prompt) {
+            Ok(line) => {
+                if !input.is_empty() {
+                    input.push('\n');
+                }
+                input.push_str(&line);
+                if !is_complete(&input) {
+                    continue;
+                }
+                let text = std::mem::take(&mut input);
+                let text = text.trim();
+                if text.is_empty() {
+                    continue;
+                }
+                editor.add_history_entry(text);
+                if let Some(exit_status) = eval_print(vm, text) {
+                    status = exit_status;
+                    break;
+                }
+            }
+            // Ctrl-C discards the input being edited.
+            Err(ReadlineError::Interrupted) => input.clear(),
+            Err(ReadlineError::Eof) => break,
+            Err(err) => {
+                eprintln!("error: {}", err);
+                status = 1;
+                break;
+            }
+        }
+    }
+
+    panic::set_hook(default_hook);
+    if let Some(path) = &history {
+        if let Err(err) = editor.save_history(path) {
+            eprintln!("warning: can't save history: {}", err);
+        }
+    }
+    status
+}
+
+// Evaluate the input and print the results. Returns the exit status when the REPL should exit.
+fn eval_print(vm: &mut Vm, text: &str) -> Option<i32> {
+    if let Some(command) = text.strip_prefix(',') {
+        let (name, arg) = match command.split_once(char::is_whitespace) {
+            Some((name, arg)) => (name, arg.trim()),
+            None => (command, ""),
+        };
+        match name {
+            "load" => {
+                let path = arg.trim_matches('"');
+                guard(vm, |vm| {
+                    vm.load_file(path);
+                })
+            }
+            "time" => {
+                let start = Instant::now();
+                let status = guard(vm, |vm| eval_forms(vm, arg));
+                println!(";; {:.6}s elapsed", start.elapsed().as_secs_f64());
+                status
+            }
+            "disasm" => guard(vm, |vm| eval_forms(vm, &format!("(disasm {})", arg))),
+            "help" => {
+                println!("{}", HELP);
+                None
+            }
+            "quit" | "q" | "exit" => Some(0),
+            _ => {
+                eprintln!("error: unknown command ,{}. Type ,help for commands.", name);
+                None
+            }
+        }
+    } else {
+        guard(vm, |vm| eval_forms(vm, text))
+    }
+}
+
+fn eval_forms(vm: &mut Vm, text: &str) {
+    vm.load_compiler();
+    let mut sexps = match read_all(&mut vm.gc, text) {
+        Ok(sexps) => sexps,
+        Err(err) => panic!("read: {:?}", err),
+    };
+    vm.push_root(sexps);
+    while let Object::Pair(pair) = sexps {
+        vm.eval(pair.car);
+        for value in vm.return_values() {
+            if !value.is_unspecified() {
+                println!("{}", value.to_write_string());
+            }
+        }
+        sexps = pair.cdr;
+    }
+    vm.pop_root();
+}
+
+// Run f and report the error without exiting the REPL.
+// Returns the exit status if f called (exit).
+fn guard<F: FnOnce(&mut Vm)>(vm: &mut Vm, f: F) -> Option<i32> {
+    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| f(vm))) {
+        if let Some(Exit(status)) = payload.downcast_ref::<Exit>() {
+            return Some(*status);
+        }
+        let message = if let Some(message) = payload.downcast_ref::<String>() {
+            message.as_str()
+        } else if let Some(message) = payload.downcast_ref::<&str>() {
+            message
+        } else {
+            "unknown error"
+        };
+        eprintln!("error: {}", message);
+        eprint!("{}", vm.backtrace());
+        vm.reset();
+    }
+    None
+}
+
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".rmosh_history"))
+}
+
+/// Returns true when parens and brackets in the input are balanced and strings are closed.
+/// Parens in strings, comments, |symbols| and character literals are ignored.
+pub fn is_complete(input: &str) -> bool {
+    let mut depth = 0;
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth -= 1,
+            '"' | '|' => loop {
+                match chars.next() {
+                    Some('\\') => {
+                        chars.next();
+                    }
+                    Some(e) if e == c => break,
+                    Some(_) => {}
+                    None => return false,
+                }
+            },
+            ';' => {
+                for e in chars.by_ref() {
+                    if e == '\n' {
+                        break;
+                    }
+                }
+            }
+            '#' if chars.peek() == Some(&'\\') => {
+                chars.next();
+                chars.next();
+            }
+            _ => {}
+        }
+    }
+    depth <= 0
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::is_complete;
+
+    #[test]
+    fn test_is_complete() {
+        assert!(is_complete("(define a 3)"));
+        assert!(is_complete("a"));
+        assert!(!is_complete("(define (f)"));
+        assert!(is_complete("(define (f)\n  3)"));
+        assert!(!is_complete("(display \"abc)"));
+        assert!(is_complete("(display \"a(b\\\"c\")"));
+        assert!(is_complete("(list #\\( #\\))"));
+        assert!(!is_complete("(a ; )"));
+        assert!(is_complete("(a ; )\n)"));
+        assert!(is_complete("(quote |a(b|)"));
+        assert!(!is_complete("(let ([a 1]"));
+        assert!(is_complete("(let ([a 1]) a)"));
+    }
+}
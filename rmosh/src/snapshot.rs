@@ -0,0 +1,104 @@
+/// Compiler snapshot.
+/// Decoding the embedded compiler from FASL is the slowest part of booting a Vm.
+/// The snapshot decodes it only once per process into immortal objects, and every Vm shares the decoded code.
+/// A Vm which already interned some of the compiler's symbols can't share it, because symbols must be unique in a Vm.
+/// Such a Vm gets a copy of the snapshot instead, which is still much faster than decoding.
+use std::{collections::HashMap, sync::OnceLock};
+
+use crate::{
+    compiler,
+    fasl::Fasl,
+    gc::{Gc, GcRef},
+    objects::{Object, Symbol},
+};
+
+pub struct CompilerSnapshot {
+    ops: Vec<Object>,
+    symbols: HashMap<String, GcRef<Symbol>>,
+}
+
+// The snapshot consists of immortal objects which are never written after it is built.
+// GC never marks or sweeps immortal objects, so sharing them between threads is safe.
+unsafe impl Send for CompilerSnapshot {}
+unsafe impl Sync for CompilerSnapshot {}
+
+// The snapshot of the embedded compiler, built on the first call.
+pub fn compiler_snapshot() -> &'static CompilerSnapshot {
+    static SNAPSHOT: OnceLock<CompilerSnapshot> = OnceLock::new();
+    SNAPSHOT.get_or_init(|| {
+        let mut gc = Gc::new();
+        let mut fasl = Fasl {
+            bytes: compiler::BIN_COMPILER,
+        };
+        let ops = fasl
+            .read_all_sexp(&mut gc)
+            .expect("the embedded compiler is broken");
+        let symbols = gc.into_immortal();
+        CompilerSnapshot { ops, symbols }
+    })
+}
+
+impl CompilerSnapshot {
+    // The top-level code of the compiler.
+    pub fn ops(&'static self) -> &'static [Object] {
+        &self.ops
+    }
+
+    // Make the compiler's symbols the symbols of gc so that gc can run the shared code.
+    // Returns false and leaves gc untouched if gc already has other symbols of the same names.
+    pub fn share_with(&self, gc: &mut Gc) -> bool {
+        let conflicts = self
+            .symbols
+            .iter()
+            .any(|(name, symbol)| matches!(gc.symbols.get(name), Some(s) if s != symbol));
+        if conflicts {
+            return false;
+        }
+        for (name, &symbol) in &self.symbols {
+            gc.symbols.insert(name.to_owned(), symbol);
+        }
+        true
+    }
+
+    // Copy the compiler code into gc.
+    pub fn relocate(&self, gc: &mut Gc) -> Vec<Object> {
+        let mut symbols = HashMap::new();
+        self.ops
+            .iter()
+            .map(|&obj| relocate_object(gc, &mut symbols, obj))
+            .collect()
+    }
+}
+
+fn relocate_object(
+    gc: &mut Gc,
+    symbols: &mut HashMap<GcRef<Symbol>, Object>,
+    obj: Object,
+) -> Object {
+    match obj {
+        Object::Symbol(symbol) => *symbols
+            .entry(symbol)
+            .or_insert_with(|| gc.symbol_intern(&symbol.string)),
+        Object::String(s) => gc.new_string(&s.string),
+        Object::Vector(v) => {
+            let data = v
+                .data
+                .iter()
+                .map(|&obj| relocate_object(gc, symbols, obj))
+                .collect();
+            gc.new_vector(&data)
+        }
+        Object::Pair(_) => {
+            // Copy lists without recursion on cdr, as they can be long.
+            let mut cars = vec![];
+            let mut rest = obj;
+            while let Object::Pair(pair) = rest {
+                cars.push(relocate_object(gc, symbols, pair.car));
+                rest = pair.cdr;
+            }
+            let last = relocate_object(gc, symbols, rest);
+            gc.dot_pair(&cars, last)
+        }
+        _ => obj,
+    }
+}
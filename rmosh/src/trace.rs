@@ -0,0 +1,93 @@
+/// Tracing of procedure calls and returns.
+/// (trace f) logs each call of f with its arguments and each return with its value, indented by the depth of the call.
+/// Setting the environment variable RMOSH_TRACE to anything but 0 traces all calls except the ones of the compiler.
+/// Returns of closures are not logged when they tail call, because their frames are gone.
+use std::{
+    env,
+    io::{self, Write},
+};
+
+use crate::{debugger::procedure_name, objects::Object};
+
+pub struct Tracer {
+    // Traced procedures.
+    procedures: Vec<Object>,
+    // Trace all procedures.
+    pub all: bool,
+    // True while the compiler runs, so that tracing all doesn't show it.
+    pub suspended: bool,
+    output: Box<dyn Write>,
+}
+
+impl Default for Tracer {
+    fn default() -> Self {
+        let all = matches!(env::var("RMOSH_TRACE"), Ok(value) if !value.is_empty() && value != "0");
+        Tracer {
+            procedures: vec![],
+            all,
+            suspended: false,
+            output: Box::new(io::stderr()),
+        }
+    }
+}
+
+impl Tracer {
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    pub fn trace(&mut self, procedure: Object) {
+        if !self.procedures.contains(&procedure) {
+            self.procedures.push(procedure);
+        }
+    }
+
+    pub fn untrace(&mut self, procedure: Object) {
+        self.procedures.retain(|&p| p != procedure);
+    }
+
+    pub fn untrace_all(&mut self) {
+        self.procedures.clear();
+    }
+
+    pub fn procedures(&self) -> &[Object] {
+        &self.procedures
+    }
+
+    // True when no call is traced, so the VM can skip looking at them.
+    pub fn is_idle(&self) -> bool {
+        self.suspended || (self.procedures.is_empty() && !self.all)
+    }
+
+    pub fn is_traced(&self, procedure: Object) -> bool {
+        !self.suspended && (self.all || self.procedures.contains(&procedure))
+    }
+
+    // depth is the number of call frames including the one of the call.
+    pub fn call(&mut self, procedure: Object, args: &[Object], depth: usize) {
+        let mut s = format!("{}-> ({}", indent(depth), name(procedure));
+        for arg in args {
+            s.push(' ');
+            s.push_str(&arg.to_write_string());
+        }
+        let _ = writeln!(self.output, "{})", s);
+    }
+
+    pub fn ret(&mut self, procedure: Object, value: Object, depth: usize) {
+        let _ = writeln!(
+            self.output,
+            "{}<- {} returns {}",
+            indent(depth),
+            name(procedure),
+            value.to_write_string()
+        );
+    }
+}
+
+fn indent(depth: usize) -> String {
+    "  ".repeat(depth.saturating_sub(1))
+}
+
+fn name(procedure: Object) -> String {
+    procedure_name(procedure).unwrap_or_else(|| "<closure>".to_string())
+}
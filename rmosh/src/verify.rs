@@ -0,0 +1,269 @@
+/// Bytecode verifier.
+/// Broken code, for example hand-built code or a corrupted cache, can make the VM read past the end of the code or corrupt the stack.
+/// The verifier rejects such code before it runs. It checks that
+///   - every instruction has all its operands and they have the kinds Op::operands says,
+///   - jumps land on instructions in the same closure body,
+///   - the stack depth is the same on every path to an instruction and never goes below the bottom.
+use std::fmt::{self, Display};
+
+use crate::{
+    objects::Object,
+    op::{Op, Operand},
+};
+
+#[derive(Debug, PartialEq)]
+pub struct VerifyError {
+    // Position of the instruction in the code.
+    pub position: usize,
+    pub message: String,
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid code at {}: {}", self.position, self.message)
+    }
+}
+
+fn error<T>(position: usize, message: String) -> Result<T, VerifyError> {
+    Err(VerifyError { position, message })
+}
+
+// A closure body or the top-level code.
+struct Body {
+    start: usize,
+    end: usize,
+    // Arguments on the stack when the body starts.
+    depth: isize,
+}
+
+// Verify top-level code such as the code compile returns.
+pub fn verify(code: &[Object]) -> Result<(), VerifyError> {
+    let mut bodies = vec![Body {
+        start: 0,
+        end: code.len(),
+        depth: 0,
+    }];
+    let is_insn = check_operands(code, &mut bodies)?;
+    for body in &bodies {
+        check_stack(code, &is_insn, body)?;
+    }
+    Ok(())
+}
+
+// Check operands of all instructions and collect closure bodies.
+// Returns which positions are instructions.
+fn check_operands(code: &[Object], bodies: &mut Vec<Body>) -> Result<Vec<bool>, VerifyError> {
+    let mut is_insn = vec![false; code.len() + 1];
+    let mut i = 0;
+    while i < code.len() {
+        is_insn[i] = true;
+        let op = match code[i] {
+            Object::Instruction(op) => op,
+            obj => return error(i, format!("instruction expected but got {}", obj)),
+        };
+        let operands = op.operands();
+        if i + operands.len() >= code.len() {
+            return error(i, format!("{} lacks operands", op));
+        }
+        for (n, &kind) in operands.iter().enumerate() {
+            let operand = code[i + n + 1];
+            let is_valid = match (kind, operand) {
+                (Operand::Number | Operand::Offset, Object::Number(_)) => true,
+                // Display instructions change free variables, so only the sign is checked.
+                (Operand::Local | Operand::Free, Object::Number(index)) => index >= 0,
+                (Operand::Symbol, Object::Symbol(_)) => true,
+                (Operand::Bool, Object::True | Object::False) => true,
+                (Operand::Constant, _) => true,
+                _ => false,
+            };
+            if !is_valid {
+                return error(i, format!("invalid operand {} for {}", operand, op));
+            }
+        }
+        if op == Op::Closure {
+            let (size, argc, num_free_vars) = (code[i + 1], code[i + 2], code[i + 4]);
+            let end = i as isize + 1 + size.to_number();
+            let start = i + operands.len() + 1;
+            if end < start as isize || end > code.len() as isize {
+                return error(i, format!("closure size {} out of code", size));
+            }
+            // argc includes the rest argument.
+            let depth = argc.to_number();
+            if depth < 0 || num_free_vars.to_number() < 0 {
+                return error(i, "negative closure arguments".to_string());
+            }
+            bodies.push(Body {
+                start,
+                end: end as usize,
+                depth,
+            });
+        }
+        i += operands.len() + 1;
+    }
+    is_insn[code.len()] = true;
+    for body in bodies.iter() {
+        if !is_insn[body.end] {
+            return error(
+                body.start,
+                "closure body ends in the middle of an instruction".to_string(),
+            );
+        }
+    }
+    Ok(is_insn)
+}
+
+// Follow all paths in body and check the stack depth.
+fn check_stack(code: &[Object], is_insn: &[bool], body: &Body) -> Result<(), VerifyError> {
+    let mut depths: Vec<Option<isize>> = vec![None; body.end - body.start];
+    let mut pending = vec![(body.start, body.depth)];
+    let mut visit = |i: usize, position: usize, depth: isize, pending: &mut Vec<(usize, isize)>| {
+        if i < body.start || i >= body.end || !is_insn[i] {
+            return error(
+                position,
+                format!("jump to {} which is not an instruction of this body", i),
+            );
+        }
+        if depth < 0 {
+            return error(position, "stack underflow".to_string());
+        }
+        match depths[i - body.start] {
+            Some(d) if d != depth => error(
+                i,
+                format!("stack depth {} differs from {} of another path", depth, d),
+            ),
+            Some(_) => Ok(()),
+            None => {
+                depths[i - body.start] = Some(depth);
+                pending.push((i, depth));
+                Ok(())
+            }
+        }
+    };
+    let first = pending.pop().unwrap();
+    visit(first.0, first.0, first.1, &mut pending)?;
+    while let Some((i, depth)) = pending.pop() {
+        let op = code[i].to_instruction();
+        let operands = &code[i + 1..i + 1 + op.operand_count()];
+        let number = |n: usize| operands[n].to_number();
+        let next = i + operands.len() + 1;
+        if let Some(m) = op.jump_operand() {
+            if op == Op::UnfixedJump {
+                return error(i, "unfixed jump".to_string());
+            }
+            let destination = (i + m) as isize + operands[m - 1].to_number();
+            let depth_at_destination = match op {
+                // Return from the call comes back to the destination with the stack before the frame.
+                Op::Frame => depth,
+                Op::PushFrame => depth + 1,
+                // The end of the body.
+                Op::Closure => -1,
+                _ => depth + stack_effect(op, operands),
+            };
+            if depth_at_destination >= 0 {
+                if destination < 0 {
+                    return error(i, format!("jump to {}", destination));
+                }
+                visit(destination as usize, i, depth_at_destination, &mut pending)?;
+            }
+        }
+        match op {
+            Op::Closure => {
+                visit(
+                    number(0) as usize + i + 1,
+                    i,
+                    depth - number(3),
+                    &mut pending,
+                )?;
+            }
+            Op::Call
+            | Op::ReferGlobalCall
+            | Op::ReferFreeCall
+            | Op::ReferLocalCall
+            | Op::LocalCall => {
+                let argc = number(operands.len() - 1);
+                if depth < argc {
+                    return error(
+                        i,
+                        format!("{} arguments on the stack of depth {}", argc, depth),
+                    );
+                }
+            }
+            Op::TailCall | Op::LocalTailCall => {
+                if depth < number(0) + number(1) {
+                    return error(
+                        i,
+                        format!(
+                            "tail call shifts below the bottom of the stack of depth {}",
+                            depth
+                        ),
+                    );
+                }
+            }
+            Op::Return if depth != number(0) => {
+                return error(
+                    i,
+                    format!("return {} with the stack of depth {}", number(0), depth),
+                );
+            }
+            Op::Return | Op::Halt | Op::LocalJmp => {}
+            _ => visit(next, i, depth + stack_effect(op, operands), &mut pending)?,
+        }
+    }
+    Ok(())
+}
+
+// How many objects the instruction pushes to the stack, negative if it pops.
+fn stack_effect(op: Op, operands: &[Object]) -> isize {
+    let number = |n: usize| operands[n].to_number();
+    match op {
+        Op::Push
+        | Op::ConstantPush
+        | Op::PushConstant
+        | Op::CarPush
+        | Op::CdrPush
+        | Op::ReferFreePush
+        | Op::ReferLocalPush
+        | Op::ReferGlobalPush
+        | Op::ReferLocalPushConstant
+        | Op::PushEnter => 1,
+        Op::Frame => 4,
+        Op::PushFrame => 5,
+        Op::LetFrame => 2,
+        Op::Append2
+        | Op::Cons
+        | Op::Eq
+        | Op::Eqv
+        | Op::Equal
+        | Op::NumberAdd
+        | Op::NumberEqual
+        | Op::NumberGe
+        | Op::NumberGt
+        | Op::NumberLe
+        | Op::NumberLt
+        | Op::NumberMul
+        | Op::NumberDiv
+        | Op::NumberSub
+        | Op::SetCar
+        | Op::SetCdr
+        | Op::VectorRef
+        | Op::SimpleStructRef
+        | Op::MakeVector
+        | Op::BranchNotLe
+        | Op::BranchNotGe
+        | Op::BranchNotLt
+        | Op::BranchNotGt
+        | Op::BranchNotNumberEqual
+        | Op::BranchNotEq
+        | Op::BranchNotEqv
+        | Op::BranchNotEqual
+        | Op::ReferLocalBranchNotLt => -1,
+        Op::VectorSet => -2,
+        Op::Display => -number(0),
+        Op::Leave => -number(0) - 2,
+        Op::Values | Op::Vector => -(number(0) - 1).max(0),
+        Op::Receive if number(1) == 0 => number(0),
+        Op::Receive => number(0) + 1,
+        Op::Shiftj => -number(1),
+        _ => 0,
+    }
+}
@@ -1,18 +1,37 @@
 use std::{
     collections::HashMap,
+    fmt::{self, Display},
+    fs,
+    path::{Path, PathBuf},
     ptr::{null, null_mut},
+    slice,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
 };
 
 use crate::{
-    compiler,
+    backtrace::{code_closure, Backtrace},
+    compile_cache::CacheSession,
+    debugger::{Debugger, Stop},
     equal::Equal,
-    fasl::Fasl,
-    gc::{Gc, GcRef},
+    gc::{Collection, Gc, GcConfig, GcRef},
+    heap_dump::HeapDump,
+    library::{default_features, Libraries},
     objects::{Closure, Object, Pair, Symbol, Vox},
     op::Op,
-    procs::{self, default_free_vars},
+    procs::{call_guardian, compiler_procs, default_free_vars, default_global_procs},
+    read::read_all,
+    snapshot::compiler_snapshot,
+    trace::Tracer,
+    verify::verify,
 };
 
+#[cfg(feature = "profiler")]
+use crate::profiler::{Profile, Profiler};
+
 const STACK_SIZE: usize = 1024;
 const MAX_NUM_VALUES: usize = 256;
 
@@ -56,10 +75,48 @@ macro_rules! number_cmp_op {
     };
 }
 
+// Why a run stopped before reaching Halt.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interrupted {
+    // InterruptHandle::interrupt was called.
+    Requested,
+    // The instruction budget set by Vm::set_fuel ran out.
+    FuelExhausted,
+    // The deadline set by Vm::set_deadline passed.
+    DeadlineExceeded,
+}
+
+impl Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Interrupted::Requested => write!(f, "interrupted"),
+            Interrupted::FuelExhausted => write!(f, "interrupted: instruction budget exhausted"),
+            Interrupted::DeadlineExceeded => write!(f, "interrupted: deadline exceeded"),
+        }
+    }
+}
+
+// Panic payload of (exit). Whoever runs the Vm should catch it and exit with the status.
+#[derive(Debug)]
+pub struct Exit(pub i32);
+
+// Thread safe handle to interrupt a running Vm from outside.
+// The Vm checks it at calls and jumps, so even a loop which never returns can be stopped.
+#[derive(Clone, Debug)]
+pub struct InterruptHandle {
+    flag: Arc<AtomicBool>,
+}
+
+impl InterruptHandle {
+    pub fn interrupt(&self) {
+        self.flag.store(true, Ordering::Relaxed);
+    }
+}
+
 pub struct Vm {
     pub gc: Box<Gc>,
-    // The stack.
-    stack: [Object; STACK_SIZE],
+    // The stack. It is on the heap so that sp and fp stay valid when the Vm is moved.
+    stack: Box<[Object]>,
     // accumulator register.
     pub ac: Object,
     // display closure register.
@@ -74,6 +131,7 @@ pub struct Vm {
     globals: HashMap<GcRef<Symbol>, Object>,
     // We keep the lib_ops here so that the lib_ops live longer than every call of run.
     // If we kept lib_ops as local variable, it can/will be immediately freed after run(lib_ops).
+    // This is empty when the Vm shares the compiler code with other Vms.
     pub lib_ops: Vec<Object>,
     // Return values.
     values: [Object; MAX_NUM_VALUES],
@@ -81,16 +139,59 @@ pub struct Vm {
     is_initialized: bool,
     pub rtds: HashMap<Object, Object>,
     pub should_load_compiler: bool,
+    // Code of the top-level programs being run by eval. Closures keep their code alive after that.
     pub compiled_programs: Vec<Object>,
-    // Note when we add new vars here, please make sure we take care of them in mark_roots.
+    // Procedure and arguments a native procedure asked to tail call. See Vm::tail_call.
+    tail_call_proc: Object,
+    tail_call_args: Vec<Object>,
+    // Interruption. See InterruptHandle, set_fuel and set_deadline.
+    interrupt_flag: Arc<AtomicBool>,
+    fuel: Option<u64>,
+    deadline: Option<Instant>,
+    polls_since_clock_check: usize,
+    // Interruption in a nested run_ops which the outer run_ops should report.
+    pending_interrupt: Option<Interrupted>,
+    // Where resume continues an interrupted run, null if there is nothing to resume.
+    resume_pc: *const Object,
+    // dc when the current run started, so an abandoned interrupted run can be cleaned up.
+    run_dc: Object,
+    // Objects rooted by push_root.
+    roots: Vec<Object>,
+    // Script name and its arguments returned by (command-line).
+    pub command_line: Vec<String>,
+    // Directories where load and import search files.
+    pub load_path: Vec<String>,
+    // Use the Rust versions of compiler procedures instead of the Scheme ones in the embedded compiler.
+    pub use_native_compiler_procs: bool,
+    // Directory of the auto compile cache. None disables the cache.
+    pub cache_dir: Option<PathBuf>,
+    // Cache of each file being loaded, innermost last.
+    cache_sessions: Vec<CacheSession>,
+    // Loaded libraries. They only refer symbols.
+    pub(crate) libraries: Libraries,
+    // Feature identifiers for cond-expand and (features).
+    pub features: Vec<String>,
+    // Breakpoints and stepping. See debugger.rs.
+    debugger: Option<Debugger>,
+    // Traced procedures. See trace.rs.
+    pub tracer: Tracer,
+    // Running profiler. See start_profile.
+    #[cfg(feature = "profiler")]
+    profiler: Option<Profiler>,
+    // Note when we add new vars here, please make sure we take care of them in for_each_root.
     // Otherwise they can cause memory leak or double free.
 }
 
 impl Vm {
+    // The Gc is configured by the environment variables. See GcConfig.
     pub fn new() -> Self {
+        Vm::with_gc_config(GcConfig::from_env())
+    }
+
+    pub fn with_gc_config(gc_config: GcConfig) -> Self {
         Self {
-            gc: Box::new(Gc::new()),
-            stack: [Object::Unspecified; STACK_SIZE],
+            gc: Box::new(Gc::with_config(gc_config)),
+            stack: vec![Object::Unspecified; STACK_SIZE].into_boxed_slice(),
             ac: Object::Unspecified,
             dc: Object::Unspecified,
             expected: Object::Unspecified,
@@ -104,6 +205,27 @@ impl Vm {
             should_load_compiler: false,
             is_initialized: false,
             compiled_programs: vec![],
+            tail_call_proc: Object::Unspecified,
+            tail_call_args: vec![],
+            interrupt_flag: Arc::new(AtomicBool::new(false)),
+            fuel: None,
+            deadline: None,
+            polls_since_clock_check: 0,
+            pending_interrupt: None,
+            resume_pc: null(),
+            run_dc: Object::Unspecified,
+            roots: vec![],
+            command_line: vec![],
+            load_path: vec![],
+            use_native_compiler_procs: true,
+            cache_dir: None,
+            cache_sessions: vec![],
+            libraries: Libraries::default(),
+            features: default_features(),
+            debugger: None,
+            tracer: Tracer::default(),
+            #[cfg(feature = "profiler")]
+            profiler: None,
         }
     }
 
@@ -141,109 +263,517 @@ impl Vm {
         self.dc = Object::Closure(display);
     }
 
+    // Collect the whole heap.
     pub fn mark_and_sweep(&mut self) {
         if self.gc.should_gc() {
-            #[cfg(feature = "debug_log_gc")]
-            println!("-- gc begin");
-
-            self.mark_roots();
-            self.gc.collect_garbage();
+            self.collect_garbage(Collection::Major);
+        }
+    }
 
-            #[cfg(feature = "debug_log_gc")]
-            println!("-- gc end");
+    // Collect the nursery or the whole heap when the Gc needs it.
+    // The VM calls this only where all live objects are reachable from the roots:
+    // after instructions which allocate, at calls, at returns from native procedures and at backward jumps.
+    // Gc::alloc never collects, so native procedures don't need to root objects unless they run Scheme code.
+    // What a native procedure allocates itself is collected after it returns. The ones which loop over call_closure,
+    // such as map and list-sort, collect at the safepoints of the procedures they call and root what they built so far.
+    // The gc_stress feature runs a major collection at each of them after an allocation to find missing roots.
+    #[inline(always)]
+    fn safepoint(&mut self) {
+        if let Some(collection) = self.gc.collection_needed() {
+            self.collect_garbage(collection);
         }
     }
 
+    pub fn collect_garbage(&mut self, collection: Collection) {
+        #[cfg(feature = "debug_log_gc")]
+        println!("-- gc begin");
+
+        self.gc.start_collection(collection);
+        self.mark_roots();
+        self.gc.collect_garbage();
+
+        #[cfg(feature = "debug_log_gc")]
+        println!("-- gc end");
+    }
+
     fn mark_roots(&mut self) {
-        //
-        for &compiled in &self.compiled_programs {
-            self.gc.mark_object(compiled);
+        let mut roots = vec![];
+        self.for_each_root(|_, obj| roots.push(obj));
+        for obj in roots {
+            self.gc.mark_object(obj);
         }
+    }
 
+    // Call f with each GC root and its name. Both mark_roots and heap_dump use this.
+    fn for_each_root(&self, mut f: impl FnMut(fmt::Arguments, Object)) {
+        for (i, &compiled) in self.compiled_programs.iter().enumerate() {
+            f(format_args!("compiled program {}", i), compiled);
+        }
         // Base library ops.
         for &op in &self.lib_ops {
-            self.gc.mark_object(op);
+            f(format_args!("base library"), op);
         }
-
-        // Stack.
-        for &obj in &self.stack[0..self.stack_len()] {
-            self.gc.mark_object(obj);
+        for (i, &obj) in self.stack[0..self.stack_len()].iter().enumerate() {
+            f(format_args!("stack {}", i), obj);
+        }
+        for (i, &obj) in self.values[0..self.num_values].iter().enumerate() {
+            f(format_args!("values {}", i), obj);
+        }
+        // Global variables and their names.
+        for (&symbol, &obj) in self.globals.iter() {
+            f(
+                format_args!("global {}", symbol.string),
+                Object::Symbol(symbol),
+            );
+            f(format_args!("global {}", symbol.string), obj);
+        }
+        // Names exported by libraries.
+        for symbol in self.libraries.symbols() {
+            f(format_args!("library"), Object::Symbol(symbol));
+        }
+        for (&k, &v) in self.rtds.iter() {
+            f(format_args!("RTD table"), k);
+            f(format_args!("RTD table"), v);
+        }
+        // Objects rooted by Rust code.
+        for (i, &obj) in self.roots.iter().enumerate() {
+            f(format_args!("root {}", i), obj);
+        }
+        // Forms and code of files being loaded.
+        for session in &self.cache_sessions {
+            for &obj in session.objects() {
+                f(format_args!("file being loaded"), obj);
+            }
+        }
+        for &obj in self.tracer.procedures() {
+            f(format_args!("traced procedure"), obj);
+        }
+        // Pending tail call from a native procedure.
+        f(format_args!("tail call"), self.tail_call_proc);
+        for &obj in &self.tail_call_args {
+            f(format_args!("tail call"), obj);
         }
+        f(format_args!("register ac"), self.ac);
+        f(format_args!("register dc"), self.dc);
+        f(format_args!("register dc"), self.run_dc);
+        f(format_args!("register expected"), self.expected);
+        #[cfg(feature = "profiler")]
+        if let Some(profiler) = &self.profiler {
+            f(format_args!("profiler"), profiler.last_dc());
+        }
+    }
 
-        // Values.
-        for &obj in &self.values[0..self.num_values] {
-            self.gc.mark_object(obj);
+    // Live objects and what keep them alive. See heap_dump.rs.
+    // This collects the whole heap first, so only live objects remain.
+    pub fn heap_dump(&mut self) -> HeapDump {
+        self.collect_garbage(Collection::Major);
+        let mut roots = vec![];
+        self.for_each_root(|name, obj| roots.push((name.to_string(), obj)));
+        HeapDump::new(&roots, self.gc.bytes_allocated())
+    }
+
+    pub fn run(&mut self, ops: *const Object, ops_len: usize) -> Object {
+        match self.try_run(ops, ops_len) {
+            Ok(ret) => ret,
+            Err(interrupted) => panic!("{}", interrupted),
         }
+    }
 
-        // Symbols.
-        let symbols = self
-            .gc
-            .symbols
-            .values()
-            .cloned()
-            .collect::<Vec<GcRef<Symbol>>>();
-        for symbol in symbols {
-            self.gc.mark_object(Object::Symbol(symbol));
+    // Same as run but returns Err when the run is interrupted.
+    // The interrupted run can be continued with resume as long as ops is alive.
+    // Calling try_run again instead abandons it and the Vm is reusable.
+    pub fn try_run(&mut self, ops: *const Object, ops_len: usize) -> Result<Object, Interrupted> {
+        self.verify_ops(ops, ops_len);
+        if !self.is_initialized {
+            self.initialize(ops, ops_len)?;
         }
+        // Abandon the interrupted run if any.
+        // A run which reached Halt leaves dc as it was, so this is no-op for them.
+        self.resume_pc = null();
+        self.pending_interrupt = None;
+        if let Object::Closure(_) = self.run_dc {
+            self.dc = self.run_dc;
+        }
+        self.run_dc = self.dc;
 
-        // Global variables.
-        for &obj in self.globals.values() {
-            self.gc.mark_object(obj);
+        // Run the program.
+        self.reset_stack();
+        let ret = self.run_ops(ops)?;
+
+        // Clean up so that GC can sweep them.
+        self.reset_roots();
+        Ok(ret)
+    }
+
+    // Reject broken code before it runs. See verify.rs.
+    fn verify_ops(&self, ops: *const Object, ops_len: usize) {
+        if ops_len > 0 {
+            let code = unsafe { slice::from_raw_parts(ops, ops_len) };
+            if let Err(err) = verify(code) {
+                panic!("run: {}", err);
+            }
         }
+    }
 
-        // RTDs.
-        for (k, v) in self.rtds.iter() {
-            self.gc.mark_object(*k);
-            self.gc.mark_object(*v);
+    fn initialize(&mut self, ops: *const Object, ops_len: usize) -> Result<(), Interrupted> {
+        // Create display closure and make free variables accessible.
+        self.initialize_free_vars(ops, ops_len);
+
+        // Load the base library.
+
+        let lib_ops = if self.should_load_compiler {
+            // Register the compiler before interning anything, so that its code can be shared.
+            let lib_ops = self.register_compiler();
+            // (command-line) in the base library returns this.
+            let args: Vec<Object> = self
+                .command_line
+                .iter()
+                .map(|arg| self.gc.new_string(arg))
+                .collect();
+            let args = self.gc.listn(&args);
+            let symbol = self.intern("*command-line-args*");
+            self.set_symbol_value(symbol, args);
+            lib_ops
+        } else {
+            self.lib_ops = vec![Object::Instruction(Op::Halt)];
+            self.lib_ops.as_ptr()
+            //self.register_baselib()
+        };
+        self.reset_stack();
+        // Tracing all calls shows the program, not the base library.
+        let suspended = std::mem::replace(&mut self.tracer.suspended, true);
+        self.run_ops(lib_ops)?;
+        self.tracer.suspended = suspended;
+        if self.should_load_compiler {
+            for (name, proc) in default_global_procs(&mut self.gc) {
+                let symbol = self.intern(name);
+                self.set_symbol_value(symbol, proc);
+            }
+            if self.use_native_compiler_procs {
+                for (name, proc) in compiler_procs(&mut self.gc) {
+                    let symbol = self.intern(name);
+                    self.set_symbol_value(symbol, proc);
+                }
+            }
         }
+        self.run_dc = self.dc;
+        self.is_initialized = true;
+        Ok(())
+    }
 
-        // Registers.
-        self.gc.mark_object(self.ac);
-        self.gc.mark_object(self.dc);
-        self.gc.mark_object(self.expected);
+    // Compile sexp and run it as a top-level program.
+    // This works both from Rust and from native procedures while the VM is running.
+    pub fn eval(&mut self, sexp: Object) -> Object {
+        if !self.is_initialized {
+            // Loading the compiler can collect sexp.
+            self.push_root(sexp);
+            self.load_compiler();
+            self.pop_root();
+        }
+        if let Some(ret) = self.eval_library_form(sexp) {
+            return ret;
+        }
+        let sexp = self.expand_cond_expand(sexp);
+        let code = self.compile(sexp);
+        match code {
+            Object::Vector(v) => {
+                self.compiled_programs.push(code);
+                // Top-level programs refer free variables through the display closure.
+                let dc = self.dc;
+                self.dc = self.run_dc;
+                let ret = self.run_nested(v.data.as_ptr());
+                self.dc = dc;
+                self.compiled_programs.pop();
+                ret
+            }
+            _ if self.pending_interrupt.is_some() => Object::Unspecified,
+            _ => panic!("eval: compiled code expected but got {}", code),
+        }
     }
 
-    pub fn run(&mut self, ops: *const Object, ops_len: usize) -> Object {
+    // Load the compiler unless it is loaded. eval does this on demand.
+    // Loading it before reading any code lets this Vm share the compiler code with other Vms.
+    pub fn load_compiler(&mut self) {
         if !self.is_initialized {
-            // Create display closure and make free variables accessible.
-            self.initialize_free_vars(ops, ops_len);
+            self.should_load_compiler = true;
+            if let Err(interrupted) = self.initialize(null(), 0) {
+                panic!("{}", interrupted);
+            }
+        }
+    }
 
-            // Load the base library.
+    // Compile sexp, or take its code from the compile cache of the file being loaded.
+    fn compile(&mut self, sexp: Object) -> Object {
+        if let Some(session) = self.cache_sessions.last_mut() {
+            if let Some(code) = session.lookup(&mut self.gc, sexp) {
+                return code;
+            }
+        }
+        let symbol = self.intern("compile-no-optimize");
+        let compiler = match self.globals.get(&symbol) {
+            Some(&value) => value,
+            None => panic!("eval: compiler is not loaded"),
+        };
+        // Tracing all calls shows the program, not the compiler.
+        let suspended = std::mem::replace(&mut self.tracer.suspended, true);
+        let code = self.call_closure(compiler, &[sexp]);
+        self.tracer.suspended = suspended;
+        if let (Some(session), Object::Vector(_)) = (self.cache_sessions.last_mut(), code) {
+            session.record(sexp, code);
+        }
+        code
+    }
 
-            let lib_ops = if self.should_load_compiler {
-                self.register_compiler()
-            } else {
-                self.lib_ops = vec![Object::Instruction(Op::Halt)];
-                self.lib_ops.as_ptr()
-                //self.register_baselib()
-            };
-            self.run_ops(lib_ops);
-            self.is_initialized = true;
+    // Read all forms in the file and eval them in order. Returns the value of the last form.
+    // Relative paths which don't exist are searched in load_path.
+    // When cache_dir is set, compiled code is saved there and reused by the next load.
+    pub fn load_file(&mut self, path: &str) -> Object {
+        self.load_compiler();
+        let resolved = self.resolve_path(path);
+        let text = match fs::read_to_string(&resolved) {
+            Ok(text) => text,
+            Err(err) => panic!("load: can't read {}: {}", path, err),
+        };
+        let session = match &self.cache_dir {
+            Some(dir) => CacheSession::start(&mut self.gc, dir, &resolved, &text),
+            None => None,
+        };
+        let session = match session {
+            Some(session) => session,
+            None => return self.eval_string(&text),
+        };
+        self.cache_sessions.push(session);
+        let ret = self.eval_string(&text);
+        if let Some(session) = self.cache_sessions.pop() {
+            session.finish();
         }
-        // Run the program.
-        let ret = self.run_ops(ops);
+        ret
+    }
 
-        // Clean up so that GC can sweep them.
-        self.reset_roots();
+    // Eval all forms in text in order. Returns the value of the last form.
+    pub fn eval_string(&mut self, text: &str) -> Object {
+        self.load_compiler();
+        let mut sexps = match read_all(&mut self.gc, text) {
+            Ok(sexps) => sexps,
+            Err(err) => panic!("read: {:?}", err),
+        };
+        self.push_root(sexps);
+        let mut ret = Object::Unspecified;
+        while let Object::Pair(pair) = sexps {
+            ret = self.eval(pair.car);
+            sexps = pair.cdr;
+        }
+        self.pop_root();
         ret
     }
 
+    fn resolve_path(&self, path: &str) -> PathBuf {
+        let path = Path::new(path);
+        if path.is_relative() && !path.exists() {
+            for dir in &self.load_path {
+                let candidate = Path::new(dir).join(path);
+                if candidate.exists() {
+                    return candidate;
+                }
+            }
+        }
+        path.to_path_buf()
+    }
+
+    // Keep obj alive until the matching pop_root.
+    // Rust code which holds objects while running Scheme code should root them, because GC doesn't know about Rust variables.
+    pub fn push_root(&mut self, obj: Object) {
+        self.roots.push(obj);
+    }
+
+    pub fn pop_root(&mut self) {
+        self.roots.pop();
+    }
+
+    // All values the last run returned. Empty for (values).
+    pub fn return_values(&self) -> Vec<Object> {
+        if self.num_values == 0 {
+            return vec![];
+        }
+        let mut values = vec![self.ac];
+        values.extend_from_slice(&self.values[0..self.num_values - 1]);
+        values
+    }
+
+    // Bring the Vm back to the top level after a run was aborted by a panic.
+    // Globals and loaded programs are kept, so the Vm can run the next program.
+    pub fn reset(&mut self) {
+        self.reset_stack();
+        if let Object::Closure(_) = self.run_dc {
+            self.dc = self.run_dc;
+        }
+        self.resume_pc = null();
+        self.pending_interrupt = None;
+        self.tail_call_proc = Object::Unspecified;
+        self.tail_call_args.clear();
+        self.roots.clear();
+        self.compiled_programs.clear();
+        // Files whose load was aborted aren't saved to the cache.
+        self.cache_sessions.clear();
+        self.libraries.forget_incomplete();
+        self.tracer.suspended = false;
+        self.num_values = 1;
+    }
+
+    // Continue the run interrupted by a previous try_run or resume.
+    pub fn resume(&mut self) -> Result<Object, Interrupted> {
+        if self.resume_pc.is_null() {
+            panic!("resume: no interrupted run");
+        }
+        let pc = self.resume_pc;
+        self.resume_pc = null();
+        let ret = self.run_ops(pc)?;
+        self.reset_roots();
+        Ok(ret)
+    }
+
+    // Returns a handle which can interrupt this Vm from other threads.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        InterruptHandle {
+            flag: self.interrupt_flag.clone(),
+        }
+    }
+
+    // Limit the number of instructions executed from now on. None means no limit.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    // Remaining instruction budget.
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    // Interrupt runs which are still running at deadline. None means no deadline.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+        self.polls_since_clock_check = 0;
+    }
+
+    // Returns the top-level code of the compiler.
+    // The code is shared with other Vms when possible, and copied into this Vm otherwise. See snapshot.rs.
     pub fn register_compiler(&mut self) -> *const Object {
-        let mut fasl = Fasl {
-            bytes: compiler::BIN_COMPILER,
-        };
-        let ops = fasl.read_all_sexp(&mut self.gc);
-        self.lib_ops = ops;
-        self.lib_ops.as_ptr()
+        let snapshot = compiler_snapshot();
+        if snapshot.share_with(&mut self.gc) {
+            self.lib_ops = vec![];
+            snapshot.ops().as_ptr()
+        } else {
+            self.lib_ops = snapshot.relocate(&mut self.gc);
+            self.lib_ops.as_ptr()
+        }
+    }
+
+    // Call a Scheme procedure from Rust and return its result.
+    // This re-enters the VM loop on top of the current stack, so native procedures like map or sort can call back into Scheme.
+    // When the call is the last thing a native procedure does, prefer tail_call which doesn't grow the Rust stack.
+    pub fn call_closure(&mut self, proc: Object, args: &[Object]) -> Object {
+        let argc = args.len() as isize;
+        let mut ops = vec![Object::Instruction(Op::Frame), Object::Number(0)];
+        for &arg in args {
+            ops.push(Object::Instruction(Op::ConstantPush));
+            ops.push(arg);
+        }
+        ops.push(Object::Instruction(Op::Constant));
+        ops.push(proc);
+        ops.push(Object::Instruction(Op::Call));
+        ops.push(Object::Number(argc));
+        ops.push(Object::Instruction(Op::Halt));
+        // Return to the Halt.
+        ops[1] = Object::Number(ops.len() as isize - 2);
+        self.run_nested(ops.as_ptr())
+    }
+
+    // Run ops on top of the current stack and return to the caller at Halt.
+    fn run_nested(&mut self, ops: *const Object) -> Object {
+        if self.sp.is_null() {
+            self.reset_stack();
+        }
+        if self.pending_interrupt.is_some() {
+            return Object::Unspecified;
+        }
+        let (sp, fp, dc) = (self.sp, self.fp, self.dc);
+        match self.run_ops(ops) {
+            Ok(ret) => ret,
+            Err(interrupted) => {
+                // We can't resume in the middle of the native procedure.
+                // Unwind to the caller and let the outer run_ops report the interruption.
+                self.resume_pc = null();
+                self.pending_interrupt = Some(interrupted);
+                self.sp = sp;
+                self.fp = fp;
+                self.dc = dc;
+                Object::Unspecified
+            }
+        }
+    }
+
+    // Ask the VM to call proc with args in place of the native procedure which is currently running.
+    // Native procedures should return the value of this function as is.
+    // The call is made by call_op after the native procedure returns, so it is a proper tail call.
+    pub fn tail_call(&mut self, proc: Object, args: &[Object]) -> Object {
+        self.tail_call_proc = proc;
+        self.tail_call_args = args.to_vec();
+        Object::Unspecified
     }
 
-    fn run_ops(&mut self, ops: *const Object) -> Object {
+    fn reset_stack(&mut self) {
         self.sp = self.stack.as_mut_ptr();
         self.fp = self.sp;
+    }
 
+    // Calls and jumps are where loops go through, so they are where we check interruption.
+    fn is_interrupt_point(op: Op) -> bool {
+        matches!(
+            op,
+            Op::Call
+                | Op::LocalCall
+                | Op::LocalJmp
+                | Op::LocalTailCall
+                | Op::ReferFreeCall
+                | Op::ReferGlobalCall
+                | Op::ReferLocalCall
+                | Op::Shiftj
+                | Op::TailCall
+        )
+    }
+
+    fn poll_interrupt(&mut self, steps: &mut u64) -> Result<(), Interrupted> {
+        if self.interrupt_flag.swap(false, Ordering::Relaxed) {
+            return Err(Interrupted::Requested);
+        }
+        if let Some(fuel) = self.fuel {
+            if *steps >= fuel {
+                self.fuel = Some(0);
+                return Err(Interrupted::FuelExhausted);
+            }
+            self.fuel = Some(fuel - *steps);
+        }
+        *steps = 0;
+        if let Some(deadline) = self.deadline {
+            // Reading the clock is relatively expensive, so we don't do it every time.
+            self.polls_since_clock_check += 1;
+            if self.polls_since_clock_check >= 256 {
+                self.polls_since_clock_check = 0;
+                if Instant::now() >= deadline {
+                    return Err(Interrupted::DeadlineExceeded);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn run_ops(&mut self, ops: *const Object) -> Result<Object, Interrupted> {
         let mut pc: *const Object = ops;
+        // Number of instructions executed since the last poll_interrupt.
+        let mut steps: u64 = 0;
         loop {
             let op: Op = unsafe { *pc }.to_instruction();
+            self.profile_op(op);
             match op {
                 Op::CompileError => todo!(),
                 Op::BranchNotLe => {
@@ -306,6 +836,7 @@ impl Vm {
                     if Pair::is_list(head) {
                         let p = self.gc.append2(head, self.ac);
                         self.set_return_value(p);
+                        self.safepoint();
                     } else {
                         self.arg_err("append", "pair", head);
                     }
@@ -324,6 +855,7 @@ impl Vm {
                     match closure.refer_free(n) {
                         Object::Vox(mut vox) => {
                             vox.value = self.ac;
+                            self.gc.write_barrier(vox, self.ac);
                         }
                         _ => {
                             panic!("assign_free: vox not found")
@@ -338,7 +870,10 @@ impl Vm {
                 Op::AssignLocal => {
                     let n = self.isize_operand(&mut pc);
                     match self.refer_local(n) {
-                        Object::Vox(mut vox) => vox.value = self.ac,
+                        Object::Vox(mut vox) => {
+                            vox.value = self.ac;
+                            self.gc.write_barrier(vox, self.ac);
+                        }
                         _ => {
                             panic!("assign_local: vox not found")
                         }
@@ -348,8 +883,21 @@ impl Vm {
                     let n = self.isize_operand(&mut pc);
                     let vox = self.gc.alloc(Vox::new(self.index(self.sp, n)));
                     self.index_set(self.sp, n, Object::Vox(vox));
+                    self.safepoint();
                 }
-                Op::Caar => todo!(),
+                Op::Caar => match self.ac {
+                    Object::Pair(pair) => match pair.car {
+                        Object::Pair(pair) => {
+                            self.set_return_value(pair.car);
+                        }
+                        obj => {
+                            self.arg_err("caar", "pair", obj);
+                        }
+                    },
+                    obj => {
+                        self.arg_err("caar", "pair", obj);
+                    }
+                },
                 Op::Cadr => match self.ac {
                     Object::Pair(pair) => match pair.cdr {
                         Object::Pair(pair) => {
@@ -366,7 +914,19 @@ impl Vm {
                 Op::Car => {
                     self.car_op();
                 }
-                Op::Cdar => todo!(),
+                Op::Cdar => match self.ac {
+                    Object::Pair(pair) => match pair.car {
+                        Object::Pair(pair) => {
+                            self.set_return_value(pair.cdr);
+                        }
+                        obj => {
+                            self.arg_err("cdar", "pair", obj);
+                        }
+                    },
+                    obj => {
+                        self.arg_err("cdar", "pair", obj);
+                    }
+                },
                 Op::Cddr => match self.ac {
                     Object::Pair(pair) => match pair.cdr {
                         Object::Pair(pair) => {
@@ -385,14 +945,14 @@ impl Vm {
                 }
                 Op::Closure => {
                     self.closure_op(&mut pc);
-                    // TODO: Tentative GC here.
-                    self.mark_and_sweep();
+                    self.safepoint();
                 }
                 Op::Cons => {
                     let car = self.pop();
                     let cdr = self.ac;
                     let pair = self.gc.cons(car, cdr);
                     self.set_return_value(pair);
+                    self.safepoint();
                 }
                 Op::Constant => {
                     self.constant_op(&mut pc);
@@ -418,10 +978,12 @@ impl Vm {
                         Object::False,
                     ));
                     display.prev = self.dc;
+                    display.code = self.current_code();
 
                     let display = Object::Closure(display);
                     self.dc = display;
                     self.sp = self.dec(self.sp, num_free_vars);
+                    self.safepoint();
                 }
                 Op::Enter => {
                     let n = self.isize_operand(&mut pc);
@@ -477,6 +1039,8 @@ impl Vm {
                 Op::LocalJmp => {
                     let jump_offset = self.isize_operand(&mut pc);
                     pc = self.jump(pc, jump_offset - 1);
+                    // Loops jump backward.
+                    self.safepoint();
                 }
                 Op::MakeContinuation => todo!(),
                 Op::MakeVector => match self.pop() {
@@ -484,6 +1048,7 @@ impl Vm {
                         let v = vec![self.ac; size as usize];
                         let v = self.gc.new_vector(&v);
                         self.set_return_value(v);
+                        self.safepoint();
                     }
                     obj => {
                         self.arg_err("make-vector", "numbers", obj);
@@ -565,11 +1130,13 @@ impl Vm {
                 Op::RestoreContinuation => todo!(),
                 Op::Return => {
                     let n = self.operand(&mut pc).to_number();
+                    self.return_hook();
                     self.return_n(n, &mut pc);
                 }
                 Op::SetCar => match self.pop() {
                     Object::Pair(mut pair) => {
                         pair.car = self.ac;
+                        self.gc.write_barrier(pair, self.ac);
                         self.set_return_value(Object::Unspecified);
                     }
                     obj => {
@@ -579,6 +1146,7 @@ impl Vm {
                 Op::SetCdr => match self.pop() {
                     Object::Pair(mut pair) => {
                         pair.cdr = self.ac;
+                        self.gc.write_barrier(pair, self.ac);
                         self.set_return_value(Object::Unspecified);
                     }
                     obj => {
@@ -677,6 +1245,7 @@ impl Vm {
                         }
                         self.push(ret);
                     }
+                    self.safepoint();
                 }
                 Op::UnfixedJump => todo!(),
                 Op::Stop => todo!(),
@@ -746,6 +1315,7 @@ impl Vm {
                             let idx = idx as usize;
                             if idx < v.data.len() {
                                 v.data[idx] = self.ac;
+                                self.gc.write_barrier(v, self.ac);
                             } else {
                                 self.arg_err("vector-set", "valid idx to vector", obj);
                             }
@@ -870,13 +1440,16 @@ impl Vm {
                     let argc = self.isize_operand(&mut pc);
                     // Locall is lighter than Call
                     // We can omit checking closure type and arguments length.
+                    self.safepoint();
                     match self.ac {
                         Object::Closure(c) => {
+                            self.profile_call(c);
                             self.dc = self.ac;
                             // todo
                             //self.cl = self.ac;
                             pc = c.ops;
                             self.fp = self.dec(self.sp, argc);
+                            self.call_hook(c);
                         }
                         obj => {
                             panic!("LocalCall: Bug {}", obj)
@@ -901,6 +1474,7 @@ impl Vm {
                     }
                     let vec = self.gc.new_vector(&v);
                     self.set_return_value(vec);
+                    self.safepoint();
                 }
                 Op::SimpleStructRef => match (self.pop(), self.ac) {
                     (Object::SimpleStruct(s), Object::Number(idx)) => {
@@ -925,17 +1499,31 @@ impl Vm {
                     let depth = self.isize_operand(&mut pc);
                     let diff = self.isize_operand(&mut pc);
                     self.sp = self.shift_args_to_bottom(self.sp, depth, diff);
+                    self.safepoint();
                     let closure = self.ac.to_closure();
+                    self.profile_call(closure);
                     let argc = depth;
                     self.dc = self.ac;
                     pc = closure.ops;
                     self.fp = self.dec(self.sp, argc);
+                    self.call_hook(closure);
                 }
             }
             self.print_vm(op);
             pc = self.jump(pc, 1);
+            steps += 1;
+            if Self::is_interrupt_point(op) {
+                if let Some(interrupted) = self.pending_interrupt.take() {
+                    // Interrupted in a nested run_ops. The native procedure didn't get its value, so this is not resumable.
+                    return Err(interrupted);
+                }
+                if let Err(interrupted) = self.poll_interrupt(&mut steps) {
+                    self.resume_pc = pc;
+                    return Err(interrupted);
+                }
+            }
         }
-        self.ac
+        Ok(self.ac)
     }
 
     #[inline(always)]
@@ -954,19 +1542,33 @@ impl Vm {
         }
         // Don't call self.alloc here.
         // Becase it can trigger gc and free the allocated object *before* it is rooted.
-        let c = self.gc.alloc(Closure::new(
+        // ops points to the last operand (src_info) and the body follows it.
+        let mut c = self.gc.alloc(Closure::new(
             *pc,
-            size - 1,
+            size - 5,
             arg_len,
             is_optional_arg,
             free_vars,
             src_info,
         ));
+        c.code = self.current_code();
         self.set_return_value(Object::Closure(c));
         self.sp = self.dec(self.sp, num_free_vars);
         *pc = self.jump(*pc, size as isize - 6);
     }
 
+    // The code vector which the running code is in.
+    // Closures run their own code, and top-level code is the program eval is running.
+    fn current_code(&self) -> Object {
+        match self.dc {
+            Object::Closure(closure) if !closure.code.is_unspecified() => closure.code,
+            _ => match self.compiled_programs.last() {
+                Some(&code) => code,
+                None => Object::Unspecified,
+            },
+        }
+    }
+
     #[inline(always)]
     fn bool_operand(&mut self, pc: &mut *const Object) -> bool {
         self.operand(pc).to_bool()
@@ -1020,6 +1622,10 @@ impl Vm {
         self.set_return_value(v);
     }
 
+    pub fn global_value(&self, symbol: GcRef<Symbol>) -> Option<Object> {
+        self.globals.get(&symbol).copied()
+    }
+
     pub fn set_symbol_value(&mut self, symbol: GcRef<Symbol>, value: Object) {
         self.globals.insert(symbol, value);
     }
@@ -1118,9 +1724,11 @@ impl Vm {
     #[inline(always)]
     fn call_op(&mut self, pc: &mut *const Object, argc: isize) {
         let mut argc = argc;
+        self.safepoint();
         'call: loop {
             match self.ac {
                 Object::Closure(closure) => {
+                    self.profile_call(closure);
                     self.dc = self.ac;
                     // TODO:
                     // self.cl = self.ac;
@@ -1152,64 +1760,39 @@ impl Vm {
                             closure.argc, argc
                         );
                     }
+                    self.call_hook(closure);
                 }
                 Object::Procedure(procedure) => {
-                    let start = unsafe { self.sp.offset_from(self.stack.as_ptr()) } - argc;
-                    let start: usize = start as usize;
-                    let uargc: usize = argc as usize;
-                    let args = &self.stack[start..start + uargc];
-
-                    // copying args here because we can't borrow.
-                    let args = &args.to_owned()[..];
-
-                    // We convert apply call to Op::Call.
-                    if procedure.func as usize == procs::apply as usize {
-                        if argc == 1 {
-                            panic!("apply: need two or more arguments but only 1 argument");
-                        }
-                        self.sp = self.dec(self.sp, argc);
-                        self.ac = args[0];
-                        // (apply proc arg1 arg2 ... args-as-list)
-                        // We push arguments here. The last argument is flatten list.
-                        for i in 1..argc {
-                            if i == argc - 1 {
-                                let mut last_pair = args[i as usize];
-                                if !last_pair.is_list() {
-                                    panic!(
-                                        "apply: last arguments shoulbe proper list but got {}",
-                                        last_pair
-                                    );
-                                }
-                                let mut j: isize = 0;
-                                loop {
-                                    if last_pair.is_nil() {
-                                        argc = argc - 2 + j;
-                                        continue 'call;
-                                    } else {
-                                        match last_pair {
-                                            Object::Pair(pair) => {
-                                                self.push(pair.car);
-                                                last_pair = pair.cdr;
-                                            }
-                                            _ => {
-                                                panic!("never reached");
-                                            }
-                                        }
-                                    }
-                                    j = j + 1;
-                                }
-                            } else {
-                                self.push(args[i as usize]);
-                            }
-                        }
-                    } else {
-                        // TODO: Take care of cl.
-                        // self.cl = self.ac
+                    let args = &self.stack_args(argc)[..];
 
-                        self.ac = (procedure.func)(self, args);
+                    // TODO: Take care of cl.
+                    // self.cl = self.ac
+
+                    self.native_call_hook(Object::Procedure(procedure), args);
+                    self.ac = (procedure.func)(self, args);
+                    if self.tail_call_proc.is_unspecified() {
+                        self.native_return_hook(Object::Procedure(procedure), args);
                         self.return_n(argc, pc);
+                        // Collect what the procedure allocated.
+                        self.safepoint();
+                    } else {
+                        // The procedure asked for a tail call, replace its arguments with the new ones and call again.
+                        self.sp = self.dec(self.sp, argc);
+                        let tail_call_args = std::mem::take(&mut self.tail_call_args);
+                        for &arg in &tail_call_args {
+                            self.push(arg);
+                        }
+                        argc = tail_call_args.len() as isize;
+                        self.ac = self.tail_call_proc;
+                        self.tail_call_proc = Object::Unspecified;
+                        continue 'call;
                     }
                 }
+                Object::Guardian(guardian) => {
+                    let args = &self.stack_args(argc)[..];
+                    self.ac = call_guardian(self, guardian, args);
+                    self.return_n(argc, pc);
+                }
                 _ => {
                     panic!("can't call {:?}", self.ac);
                 }
@@ -1218,6 +1801,14 @@ impl Vm {
         }
     }
 
+    // Copy the top argc objects of the stack, because native procedures can't borrow them from the Vm.
+    fn stack_args(&self, argc: isize) -> Vec<Object> {
+        let start = unsafe { self.sp.offset_from(self.stack.as_ptr()) } - argc;
+        let start: usize = start as usize;
+        let uargc: usize = argc as usize;
+        self.stack[start..start + uargc].to_owned()
+    }
+
     fn reset_roots(&mut self) {
         // Clean up display closure so that Objects in ops can be freed.
         let mut closure = self.dc.to_closure();
@@ -1234,6 +1825,9 @@ impl Vm {
     }
 
     fn push(&mut self, value: Object) {
+        if self.stack_len() >= STACK_SIZE {
+            panic!("stack overflow");
+        }
         unsafe {
             *self.sp = value;
             self.sp = self.inc(self.sp, 1);
@@ -1276,6 +1870,194 @@ impl Vm {
     #[cfg(not(feature = "debug_log_vm"))]
     fn print_vm(&mut self, _: Op) {}
 
+    // Start profiling the code this Vm runs. See profiler.rs.
+    #[cfg(feature = "profiler")]
+    pub fn start_profile(&mut self) {
+        self.profiler = Some(Profiler::start());
+    }
+
+    // Stop profiling and return the result, None if the Vm is not profiling.
+    #[cfg(feature = "profiler")]
+    pub fn stop_profile(&mut self) -> Option<Profile> {
+        self.profiler.take().map(Profiler::stop)
+    }
+
+    #[cfg(feature = "profiler")]
+    pub fn is_profiling(&self) -> bool {
+        self.profiler.is_some()
+    }
+
+    #[cfg(feature = "profiler")]
+    fn profile_op(&mut self, op: Op) {
+        let should_sample = match &mut self.profiler {
+            Some(profiler) => {
+                profiler.count_op(op, self.dc);
+                profiler.should_sample()
+            }
+            None => return,
+        };
+        if should_sample {
+            let mut callers = self.caller_closures();
+            callers.reverse();
+            if let Some(profiler) = &mut self.profiler {
+                profiler.sample(&callers, self.dc);
+            }
+        }
+    }
+    #[cfg(not(feature = "profiler"))]
+    fn profile_op(&mut self, _: Op) {}
+
+    #[cfg(feature = "profiler")]
+    fn profile_call(&mut self, closure: GcRef<Closure>) {
+        if let Some(profiler) = &mut self.profiler {
+            profiler.count_call(closure);
+        }
+    }
+    #[cfg(not(feature = "profiler"))]
+    fn profile_call(&mut self, _: GcRef<Closure>) {}
+
+    // Display closures of the callers in the call frames on the stack, innermost first.
+    // Each frame saves the fp of its outer frame just below the fp.
+    // A call frame is pc*, dc, cl and fp (see frame_op), while a let frame is dc and fp.
+    fn caller_closures(&self) -> Vec<Object> {
+        let base = self.stack.as_ptr();
+        let mut closures = vec![];
+        let mut fp = self.fp as *const Object;
+        while !fp.is_null() && fp > base && fp <= self.sp {
+            let index = unsafe { fp.offset_from(base) } as usize;
+            let saved_fp = match self.stack[index - 1] {
+                Object::ObjectPointer(saved_fp) => saved_fp as *const Object,
+                _ => break,
+            };
+            if index >= 4 {
+                if let Object::ProgramCounter(_) = self.stack[index - 4] {
+                    closures.push(self.stack[index - 3]);
+                }
+            }
+            // Frames are below their inner frames.
+            if saved_fp >= fp {
+                break;
+            }
+            fp = saved_fp;
+        }
+        closures
+    }
+
+    pub fn set_debugger(&mut self, debugger: Debugger) {
+        self.debugger = Some(debugger);
+    }
+
+    // The debugger, which reads stdin and writes stdout unless set_debugger gave another.
+    pub fn debugger(&mut self) -> &mut Debugger {
+        self.debugger.get_or_insert_with(Debugger::default)
+    }
+
+    // True when calls and returns can be traced or stop the VM.
+    fn is_watching_calls(&self) -> bool {
+        !self.tracer.is_idle() || matches!(&self.debugger, Some(debugger) if !debugger.is_idle())
+    }
+
+    // Called when closure is entered and its arguments are in place.
+    fn call_hook(&mut self, closure: GcRef<Closure>) {
+        if !self.is_watching_calls() {
+            return;
+        }
+        let depth = self.caller_closures().len();
+        let procedure = Object::Closure(closure);
+        if self.tracer.is_traced(procedure) {
+            let args = self.closure_args(closure);
+            self.tracer.call(procedure, &args, depth);
+        }
+        if matches!(&self.debugger, Some(debugger) if debugger.stops_at_call(closure, depth)) {
+            let args = self.closure_args(closure);
+            self.debug_stop(procedure, depth, args, None);
+        }
+    }
+
+    // Called when the current closure returns ac.
+    fn return_hook(&mut self) {
+        if !self.is_watching_calls() {
+            return;
+        }
+        let closure = match code_closure(self.dc) {
+            Some(closure) => closure,
+            None => return,
+        };
+        let depth = self.caller_closures().len();
+        let procedure = Object::Closure(closure);
+        if self.tracer.is_traced(procedure) {
+            self.tracer.ret(procedure, self.ac, depth);
+        }
+        if matches!(&self.debugger, Some(debugger) if debugger.stops_at_return(depth)) {
+            let args = self.closure_args(closure);
+            self.debug_stop(procedure, depth, args, Some(self.ac));
+        }
+    }
+
+    // Number of call frames including the one of the native procedure called with args on the stack.
+    // Native procedures don't change fp, and their args are at fp only when they are tail called.
+    fn native_depth(&self, args: &[Object]) -> usize {
+        let depth = self.caller_closures().len();
+        if self.dec(self.sp, args.len() as isize) == self.fp {
+            depth
+        } else {
+            depth + 1
+        }
+    }
+
+    // Called before a native procedure is called with args.
+    fn native_call_hook(&mut self, procedure: Object, args: &[Object]) {
+        if !self.tracer.is_idle() && self.tracer.is_traced(procedure) {
+            let depth = self.native_depth(args);
+            self.tracer.call(procedure, args, depth);
+        }
+    }
+
+    // Called when a native procedure returns ac. It may return for a closure which tail called it.
+    fn native_return_hook(&mut self, procedure: Object, args: &[Object]) {
+        if !self.is_watching_calls() {
+            return;
+        }
+        let depth = self.native_depth(args);
+        if self.tracer.is_traced(procedure) {
+            self.tracer.ret(procedure, self.ac, depth);
+        }
+        if matches!(&self.debugger, Some(debugger) if debugger.stops_at_return(depth)) {
+            self.debug_stop(procedure, depth, args.to_vec(), Some(self.ac));
+        }
+    }
+
+    // Arguments of the current call of closure.
+    fn closure_args(&self, closure: GcRef<Closure>) -> Vec<Object> {
+        unsafe { slice::from_raw_parts(self.fp, closure.argc as usize) }.to_vec()
+    }
+
+    fn debug_stop(
+        &mut self,
+        procedure: Object,
+        depth: usize,
+        args: Vec<Object>,
+        value: Option<Object>,
+    ) {
+        let stop = Stop {
+            procedure,
+            depth,
+            args,
+            value,
+            backtrace: self.backtrace(),
+        };
+        if let Some(debugger) = &mut self.debugger {
+            debugger.enter(&stop);
+        }
+    }
+
+    // Active frames, innermost first. See backtrace.rs.
+    pub fn backtrace(&self) -> Backtrace {
+        let mut closures = vec![self.dc];
+        closures.extend(self.caller_closures());
+        Backtrace::new(&closures, &self.globals)
+    }
+
     #[inline(always)]
     fn set_return_value(&mut self, obj: Object) {
         self.ac = obj;
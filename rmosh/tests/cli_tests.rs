@@ -0,0 +1,227 @@
+use std::{
+    fs,
+    io::Write,
+    process::{Command, Output, Stdio},
+};
+
+fn rmosh(args: &[&str]) -> Output {
+    // Keep the auto compile cache out of the home directory.
+    let cache_dir = std::env::temp_dir().join("rmosh-cli-test-cache");
+    Command::new(env!("CARGO_BIN_EXE_rmosh"))
+        .env("RMOSH_CACHE_DIR", cache_dir)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+#[test]
+fn test_eval_option() {
+    let output = rmosh(&["-e", "(display (+ 1 2))"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "3\n");
+}
+
+#[test]
+fn test_exit_status() {
+    assert_eq!(rmosh(&["-e", "(exit 3)"]).status.code(), Some(3));
+    assert_eq!(rmosh(&["-e", "(exit #f)"]).status.code(), Some(1));
+    assert_eq!(rmosh(&["-e", "(exit)"]).status.code(), Some(0));
+}
+
+#[test]
+fn test_uncaught_error() {
+    let output = rmosh(&["-e", "(car 1)"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("car"));
+}
+
+#[test]
+fn test_uncaught_error_backtrace() {
+    let output = rmosh(&["-e", "(define (f x) (+ (car x) 1)) (f 1)"]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("backtrace:\n    0  (f x)\n    1  <top-level>\n"));
+}
+
+#[test]
+fn test_print_stack() {
+    let output = rmosh(&["-e", "(define (f x) (print-stack) x) (display (f 3))"]);
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = stdout(&output);
+    assert!(stdout.starts_with("  0  (f x)\n;; "));
+    assert!(stdout.contains("  ; #<procedure print-stack>\n"));
+    assert!(stdout.contains("  1  <top-level>\n"));
+    assert!(stdout.ends_with("3\n"));
+}
+
+#[test]
+fn test_unknown_option() {
+    assert_eq!(rmosh(&["--no-such-option"]).status.code(), Some(2));
+}
+
+#[test]
+fn test_script_args_and_loadpath() {
+    let dir = std::env::temp_dir().join(format!("rmosh-cli-test-{}", std::process::id()));
+    let lib_dir = dir.join("lib");
+    fs::create_dir_all(&lib_dir).unwrap();
+    fs::write(lib_dir.join("twice.scm"), "(define (twice x) (* x 2))").unwrap();
+    let script = dir.join("main.scm");
+    fs::write(
+        &script,
+        "(load \"twice.scm\") (display (twice 4)) (display (command-line))",
+    )
+    .unwrap();
+    let loadpath = format!("--loadpath={}", lib_dir.display());
+    let script = script.to_str().unwrap();
+    let output = rmosh(&[&loadpath, script, "a", "-b"]);
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        stdout(&output),
+        format!("8\n(\"{}\" \"a\" \"-b\")\n", script)
+    );
+}
+
+#[test]
+fn test_import_library_from_loadpath() {
+    let dir = std::env::temp_dir().join(format!("rmosh-lib-test-{}", std::process::id()));
+    fs::create_dir_all(dir.join("srfi")).unwrap();
+    fs::write(
+        dir.join("srfi").join("%3a99.sls"),
+        "(library (srfi :99) (export answer) (import (rnrs)) (display 1) (define answer 42))",
+    )
+    .unwrap();
+    let loadpath = format!("--loadpath={}", dir.display());
+    // The second import uses the loaded library, so 1 is displayed once.
+    let output = rmosh(&[
+        &loadpath,
+        "-e",
+        "(import (rnrs) (srfi :99)) (import (srfi :99)) (display answer)",
+    ]);
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "1\n42\n");
+}
+
+#[test]
+fn test_import_library_from_lib() {
+    // Libraries in the lib directory of this repository start with a license comment or #!r6rs.
+    let loadpath = format!("--loadpath={}/../lib", env!("CARGO_MANIFEST_DIR"));
+    let output = rmosh(&[
+        &loadpath,
+        "-e",
+        "(import (rnrs) (yuni lib ssax private output) (clos std-protocols make))
+         (display (and (procedure? cout) (string=? nl (string #\\newline))))
+         (display (class-make 'c '(3) list (lambda (inst args) (set-cdr! inst args))))",
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "#t\n(c 3)\n");
+}
+
+#[test]
+fn test_import_mosh_library_from_lib() {
+    // Only (mosh) itself is builtin, so (mosh queue) is loaded from lib/mosh/queue.ss.
+    let loadpath = format!("--loadpath={}/../lib", env!("CARGO_MANIFEST_DIR"));
+    let output = rmosh(&[
+        &loadpath,
+        "-e",
+        "(import (rnrs) (mosh) (mosh queue))
+         (define q (make-queue))
+         (queue-push! q 1)
+         (queue-push! q 2)
+         (display (queue-pop! q))
+         (display (queue-empty? q))",
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "1\n#f\n");
+}
+
+#[test]
+fn test_define_library_include() {
+    let dir = std::env::temp_dir().join(format!("rmosh-r7rs-test-{}", std::process::id()));
+    fs::create_dir_all(dir.join("test")).unwrap();
+    fs::write(
+        dir.join("test").join("lib.sld"),
+        "(define-library (test lib)
+           (import (scheme base))
+           (include-library-declarations \"decls.scm\"))",
+    )
+    .unwrap();
+    fs::write(
+        dir.join("test").join("decls.scm"),
+        "(export twice UPPER) (include \"body.scm\") (include-ci \"body-ci.scm\")",
+    )
+    .unwrap();
+    fs::write(
+        dir.join("test").join("body.scm"),
+        "(define (twice x) (* x 2))",
+    )
+    .unwrap();
+    fs::write(dir.join("test").join("body-ci.scm"), "(DEFINE UPPER 3)").unwrap();
+    let loadpath = format!("--loadpath={}", dir.display());
+    let output = rmosh(&[
+        &loadpath,
+        "-e",
+        "(import (test lib)) (display (twice upper))",
+    ]);
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "6\n");
+}
+
+#[test]
+fn test_batch() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rmosh"))
+        .arg("-b")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"(define a 4) (display (* a a))")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "16\n");
+}
+
+#[test]
+fn test_profile_option() {
+    let output = rmosh(&[
+        "--profile",
+        "-e",
+        "(define (f n) (if (= n 0) 0 (f (- n 1)))) (f 10)",
+    ]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if cfg!(feature = "profiler") {
+        assert_eq!(output.status.code(), Some(0));
+        assert!(stderr.contains(";; flat profile"));
+        assert!(stderr.contains("(f n)"));
+    } else {
+        assert_eq!(output.status.code(), Some(2));
+        assert!(stderr.contains("profiler"));
+    }
+}
+
+#[test]
+fn test_trace_env() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rmosh"))
+        .env("RMOSH_TRACE", "1")
+        .args([
+            "--disable-acc",
+            "-e",
+            "(define (f x) (+ x 1)) (display (f 1))",
+        ])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "2\n");
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("-> (f 1)\n<- f returns 2\n"));
+}
@@ -1,10 +1,24 @@
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
 use rmosh::{
     self,
+    debugger::Debugger,
+    disasm::disassemble_closure,
     equal::Equal,
-    objects::{Closure, Object, Pair, Procedure, SString, Symbol, Vector},
+    gc::{GcConfig, GcRef, ObjectType},
+    objects::{
+        Closure, EqHashtable, Guardian, Object, Pair, Procedure, SString, Symbol, Vector, WeakBox,
+        Weakness,
+    },
     op::Op,
-    read::read,
-    vm::Vm,
+    read::{read, read_all},
+    snapshot::compiler_snapshot,
+    verify::verify,
+    vm::{Interrupted, Vm},
 };
 
 pub static SIZE_OF_CLOSURE: usize = std::mem::size_of::<Closure>();
@@ -13,6 +27,9 @@ pub static SIZE_OF_PROCEDURE: usize = std::mem::size_of::<Procedure>();
 pub static SIZE_OF_STRING: usize = std::mem::size_of::<SString>();
 pub static SIZE_OF_SYMBOL: usize = std::mem::size_of::<Symbol>();
 pub static SIZE_OF_VECTOR: usize = std::mem::size_of::<Vector>();
+pub static SIZE_OF_WEAK_BOX: usize = std::mem::size_of::<WeakBox>();
+pub static SIZE_OF_EQ_HASHTABLE: usize = std::mem::size_of::<EqHashtable>();
+pub static SIZE_OF_GUARDIAN: usize = std::mem::size_of::<Guardian>();
 
 /*
 fn show_size() {
@@ -43,15 +60,16 @@ fn test_ops_with_size(vm: &mut Vm, ops: Vec<Object>, expected: Object, expected_
     vm.expected = expected;
 
     let ret = vm.run(ops.as_ptr(), ops.len());
-    // Remove reference to ret.
-    vm.ac = Object::Unspecified;
-    vm.mark_and_sweep();
-    assert_eq!(vm.gc.bytes_allocated(), SIZE_OF_MIN_VM + expected_heap_diff);
+    // Compare ret before it is freed.
     let e = Equal::new();
     if !e.is_equal(&mut vm.gc, &ret, &expected) {
         println!("ret={} expected={}", ret, expected);
         assert_eq!(ret, expected);
     }
+    // Remove reference to ret.
+    vm.ac = Object::Unspecified;
+    vm.mark_and_sweep();
+    assert_eq!(vm.gc.bytes_allocated(), SIZE_OF_MIN_VM + expected_heap_diff);
 }
 
 // (and)
@@ -108,7 +126,7 @@ fn call0() {
         &mut vm,
         ops,
         expected,
-        SIZE_OF_SYMBOL * 1 + SIZE_OF_STRING * 0,
+        SIZE_OF_SYMBOL * 0 + SIZE_OF_STRING * 0,
     );
 }
 
@@ -155,7 +173,7 @@ fn call1() {
         &mut vm,
         ops,
         expected,
-        SIZE_OF_SYMBOL * 1 + SIZE_OF_STRING * 1,
+        SIZE_OF_SYMBOL * 0 + SIZE_OF_STRING * 0,
     );
 }
 
@@ -206,7 +224,7 @@ fn call2() {
         &mut vm,
         ops,
         expected,
-        SIZE_OF_SYMBOL * 2 + SIZE_OF_STRING * 1,
+        SIZE_OF_SYMBOL * 0 + SIZE_OF_STRING * 0,
     );
 }
 
@@ -581,7 +599,7 @@ fn test6() {
         &mut vm,
         ops,
         expected,
-        SIZE_OF_SYMBOL * 1 + SIZE_OF_STRING * 1,
+        SIZE_OF_SYMBOL * 0 + SIZE_OF_STRING * 0,
     );
 }
 
@@ -634,7 +652,7 @@ fn test7() {
         &mut vm,
         ops,
         expected,
-        SIZE_OF_SYMBOL * 2 + SIZE_OF_STRING * 0,
+        SIZE_OF_SYMBOL * 0 + SIZE_OF_STRING * 0,
     );
 }
 
@@ -673,7 +691,7 @@ fn test8() {
         &mut vm,
         ops,
         expected,
-        SIZE_OF_SYMBOL * 1 + SIZE_OF_STRING * 0,
+        SIZE_OF_SYMBOL * 0 + SIZE_OF_STRING * 0,
     );
 }
 
@@ -716,7 +734,7 @@ fn test9() {
         &mut vm,
         ops,
         expected,
-        SIZE_OF_SYMBOL * 2 + SIZE_OF_STRING * 0,
+        SIZE_OF_SYMBOL * 0 + SIZE_OF_STRING * 0,
     );
 }
 
@@ -773,7 +791,7 @@ fn test10() {
         &mut vm,
         ops,
         expected,
-        SIZE_OF_SYMBOL * 1 + SIZE_OF_STRING * 0,
+        SIZE_OF_SYMBOL * 0 + SIZE_OF_STRING * 0,
     );
 }
 
@@ -993,7 +1011,7 @@ fn test197() {
         &mut vm,
         ops,
         expected,
-        SIZE_OF_SYMBOL * 2 + SIZE_OF_STRING * 0,
+        SIZE_OF_SYMBOL * 1 + SIZE_OF_STRING * 0,
     );
 }
 
@@ -1111,7 +1129,7 @@ fn test58() {
         &mut vm,
         ops,
         expected,
-        SIZE_OF_SYMBOL * 1 + SIZE_OF_STRING * 0,
+        SIZE_OF_SYMBOL * 0 + SIZE_OF_STRING * 0,
     );
 }
 
@@ -1154,7 +1172,7 @@ fn test80() {
         &mut vm,
         ops,
         expected,
-        SIZE_OF_SYMBOL * 1 + SIZE_OF_STRING * 1,
+        SIZE_OF_SYMBOL * 1 + SIZE_OF_STRING * 0,
     );
 }
 
@@ -1204,7 +1222,7 @@ fn test14() {
         &mut vm,
         ops,
         expected,
-        SIZE_OF_SYMBOL * 2 + SIZE_OF_STRING * 0,
+        SIZE_OF_SYMBOL * 0 + SIZE_OF_STRING * 0,
     );
 }
 
@@ -1300,7 +1318,7 @@ fn test287() {
         &mut vm,
         ops,
         expected,
-        SIZE_OF_SYMBOL * 1 + SIZE_OF_STRING * 0,
+        SIZE_OF_SYMBOL * 0 + SIZE_OF_STRING * 0,
     );
 }
 
@@ -1567,7 +1585,7 @@ fn test185() {
         &mut vm,
         ops,
         expected,
-        SIZE_OF_SYMBOL * 1 + SIZE_OF_STRING * 0 + SIZE_OF_PAIR,
+        SIZE_OF_SYMBOL * 0 + SIZE_OF_STRING * 0 + SIZE_OF_PAIR,
     );
 }
 
@@ -1633,7 +1651,7 @@ fn test184() {
         &mut vm,
         ops,
         expected,
-        SIZE_OF_SYMBOL * 1 + SIZE_OF_STRING * 0 + SIZE_OF_PAIR * 1,
+        SIZE_OF_SYMBOL * 0 + SIZE_OF_STRING * 0 + SIZE_OF_PAIR * 1,
     );
 }
 
@@ -1655,7 +1673,7 @@ fn test128() {
         &mut vm,
         ops,
         expected,
-        SIZE_OF_SYMBOL * 1 + SIZE_OF_STRING * 0,
+        SIZE_OF_SYMBOL * 0 + SIZE_OF_STRING * 0,
     );
 }
 
@@ -1773,7 +1791,7 @@ fn test221() {
         &mut vm,
         ops,
         expected,
-        SIZE_OF_SYMBOL * 3 + SIZE_OF_STRING * 0,
+        SIZE_OF_SYMBOL * 0 + SIZE_OF_STRING * 0,
     );
 }
 
@@ -1904,7 +1922,7 @@ fn test11() {
         &mut vm,
         ops,
         expected,
-        SIZE_OF_SYMBOL * 2 + SIZE_OF_STRING * 0,
+        SIZE_OF_SYMBOL * 0 + SIZE_OF_STRING * 0,
     );
 }
 
@@ -1974,7 +1992,7 @@ fn test191() {
         &mut vm,
         ops,
         expected,
-        SIZE_OF_SYMBOL * 1 + SIZE_OF_STRING * 0,
+        SIZE_OF_SYMBOL * 0 + SIZE_OF_STRING * 0,
     );
 }
 
@@ -2040,7 +2058,7 @@ fn test55() {
         &mut vm,
         ops,
         expected,
-        SIZE_OF_SYMBOL * 1 + SIZE_OF_STRING * 0,
+        SIZE_OF_SYMBOL * 0 + SIZE_OF_STRING * 0,
     );
 }
 
@@ -2230,7 +2248,7 @@ fn test63() {
         &mut vm,
         ops,
         expected,
-        SIZE_OF_SYMBOL * 2 + SIZE_OF_STRING * 0,
+        SIZE_OF_SYMBOL * 0 + SIZE_OF_STRING * 0,
     );
 }
 
@@ -2329,7 +2347,7 @@ fn test60() {
         &mut vm,
         ops,
         expected,
-        SIZE_OF_SYMBOL * 1 + SIZE_OF_STRING * 0,
+        SIZE_OF_SYMBOL * 0 + SIZE_OF_STRING * 0,
     );
 }
 
@@ -2516,3 +2534,1252 @@ fn test_compiler3() {
         _ => {}
     }
 }
+
+// Compile the expression and return the compiled code.
+fn compile(vm: &mut Vm, code: &str) -> GcRef<Vector> {
+    vm.should_load_compiler = true;
+    let sexp = read(&mut vm.gc, code).unwrap();
+    let ops = vec![
+        Object::Instruction(Op::Frame),
+        Object::Number(8),
+        Object::Instruction(Op::Constant),
+        sexp,
+        Object::Instruction(Op::Push),
+        Object::Instruction(Op::ReferGlobal),
+        vm.gc.symbol_intern("compile-no-optimize"),
+        Object::Instruction(Op::Call),
+        Object::Number(1),
+        Object::Instruction(Op::Halt),
+    ];
+    let compiled = vm.run(ops.as_ptr(), ops.len());
+    // Keep the compiled code alive while it runs.
+    vm.compiled_programs.push(compiled);
+    match compiled {
+        Object::Vector(v) => v,
+        obj => panic!("compiled code expected but got {}", obj),
+    }
+}
+
+// Compile the expression and run it.
+fn compile_and_run(vm: &mut Vm, code: &str) -> Object {
+    let v = compile(vm, code);
+    vm.run(v.data.as_ptr(), v.data.len())
+}
+
+#[test]
+fn test_apply_tail_call() {
+    let mut vm = Vm::new();
+    compile_and_run(
+        &mut vm,
+        "(define (f n) (if (= n 0) (quote done) (apply f (list (- n 1)))))",
+    );
+    let ret = compile_and_run(&mut vm, "(f 10000)");
+    let expected = vm.gc.symbol_intern("done");
+    assert_eq!(ret, expected);
+}
+
+#[test]
+fn test_apply_native() {
+    let mut vm = Vm::new();
+    let ret = compile_and_run(&mut vm, "(apply cons 1 (quote (2)))");
+    vm.expected = vm.gc.cons(Object::Number(1), Object::Number(2));
+    let e = Equal::new();
+    assert!(e.is_equal(&mut vm.gc, &ret, &vm.expected));
+}
+
+#[test]
+fn test_call_closure() {
+    let mut vm = Vm::new();
+    let closure = compile_and_run(&mut vm, "(lambda (a b) (cons b a))");
+    vm.expected = closure;
+    let ret = vm.call_closure(closure, &[Object::Number(1), Object::Number(2)]);
+    let expected = vm.gc.cons(Object::Number(2), Object::Number(1));
+    let e = Equal::new();
+    assert!(e.is_equal(&mut vm.gc, &ret, &expected));
+}
+
+#[test]
+fn test_vm_apply_nested() {
+    let mut vm = Vm::new();
+    let ret = compile_and_run(
+        &mut vm,
+        "(vm/apply (lambda (a) (+ 1 (vm/apply car (list a)))) (quote ((1 2))))",
+    );
+    assert_eq!(ret, Object::Number(2));
+}
+
+// Make a list 0 ... n - 1 for the higher-order procedure tests.
+fn define_iota(vm: &mut Vm) {
+    vm.eval_string(
+        "(define (iota n) (let loop ((i (- n 1)) (acc (quote ()))) (if (< i 0) acc (loop (- i 1) (cons i acc)))))",
+    );
+}
+
+#[test]
+fn test_map() {
+    let mut vm = Vm::new();
+    define_iota(&mut vm);
+    // The procedure allocates, so collections run while map holds the values it made so far.
+    let ret = vm.eval_string(
+        "(let loop ((l (map (lambda (a b) (cons a b)) (iota 300) (iota 200))) (sum 0))
+           (if (null? l) sum (loop (cdr l) (+ sum (car (car l)) (cdr (car l))))))",
+    );
+    assert_eq!(ret, Object::Number(39800));
+}
+
+#[test]
+fn test_for_each() {
+    let mut vm = Vm::new();
+    define_iota(&mut vm);
+    let ret = vm.eval_string(
+        "(let ((sum 0)) (for-each (lambda (a b) (set! sum (+ sum a b)) (make-vector 10 a)) (iota 100) (iota 100)) sum)",
+    );
+    assert_eq!(ret, Object::Number(9900));
+}
+
+#[test]
+fn test_vector_map() {
+    let mut vm = Vm::new();
+    define_iota(&mut vm);
+    let ret = vm.eval_string(
+        "(let ((v (vector-map (lambda (a b) (list a b)) (list->vector (iota 300)) (list->vector (iota 250)))))
+           (let loop ((i 0) (sum 0))
+             (if (= i (vector-length v)) sum (loop (+ i 1) (+ sum (car (vector-ref v i)) (cadr (vector-ref v i)))))))",
+    );
+    assert_eq!(ret, Object::Number(62250));
+}
+
+#[test]
+fn test_hashtable_walk() {
+    let mut vm = Vm::new();
+    define_iota(&mut vm);
+    // Entries are walked from a snapshot, so deleting them while walking is safe.
+    let ret = vm.eval_string(
+        "(let ((h (make-eq-hashtable)) (sum 0))
+           (for-each (lambda (i) (hashtable-set! h i (* i 2))) (iota 100))
+           (hashtable-walk h (lambda (k v) (hashtable-delete! h k) (set! sum (+ sum k v)) (iota 10)))
+           (list sum (hashtable-size h)))",
+    );
+    assert_eq!(ret.to_string(), "(14850 0)");
+}
+
+#[test]
+fn test_list_sort() {
+    let mut vm = Vm::new();
+    define_iota(&mut vm);
+    // Sorting by the car keeps pairs with the same car in their original order.
+    let ret = vm.eval_string(
+        "(map cdr (list-sort (lambda (a b) (make-vector 5) (< (car a) (car b)))
+                             (map (lambda (i) (cons (- 3 (mod i 4)) i)) (iota 12))))",
+    );
+    assert_eq!(ret.to_string(), "(3 7 11 2 6 10 1 5 9 0 4 8)");
+}
+
+#[test]
+fn test_interrupt_fuel() {
+    let mut vm = Vm::new();
+    compile_and_run(&mut vm, "(define (spin) (spin))");
+    let v = compile(&mut vm, "(spin)");
+    vm.set_fuel(Some(1000));
+    assert_eq!(
+        vm.try_run(v.data.as_ptr(), v.data.len()),
+        Err(Interrupted::FuelExhausted)
+    );
+    assert_eq!(vm.fuel(), Some(0));
+
+    // The Vm is still usable.
+    vm.set_fuel(None);
+    let ret = compile_and_run(&mut vm, "(cons 1 2)");
+    vm.expected = vm.gc.cons(Object::Number(1), Object::Number(2));
+    let e = Equal::new();
+    assert!(e.is_equal(&mut vm.gc, &ret, &vm.expected));
+}
+
+#[test]
+fn test_interrupt_resume() {
+    let mut vm = Vm::new();
+    compile_and_run(
+        &mut vm,
+        "(define (count i n) (if (= i n) i (count (+ i 1) n)))",
+    );
+    let v = compile(&mut vm, "(count 0 1000)");
+    vm.set_fuel(Some(100));
+    let mut ret = vm.try_run(v.data.as_ptr(), v.data.len());
+    let mut num_interrupted = 0;
+    while ret == Err(Interrupted::FuelExhausted) {
+        num_interrupted += 1;
+        vm.set_fuel(Some(100));
+        ret = vm.resume();
+    }
+    assert!(num_interrupted > 10);
+    assert_eq!(ret, Ok(Object::Number(1000)));
+}
+
+#[test]
+fn test_interrupt_handle() {
+    let mut vm = Vm::new();
+    compile_and_run(&mut vm, "(define (spin) (spin))");
+    let v = compile(&mut vm, "(spin)");
+    let handle = vm.interrupt_handle();
+    let interrupter = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        handle.interrupt();
+    });
+    assert_eq!(
+        vm.try_run(v.data.as_ptr(), v.data.len()),
+        Err(Interrupted::Requested)
+    );
+    interrupter.join().unwrap();
+}
+
+#[test]
+fn test_interrupt_deadline() {
+    let mut vm = Vm::new();
+    compile_and_run(&mut vm, "(define (spin) (spin))");
+    let v = compile(&mut vm, "(spin)");
+    vm.set_deadline(Some(Instant::now() + Duration::from_millis(50)));
+    assert_eq!(
+        vm.try_run(v.data.as_ptr(), v.data.len()),
+        Err(Interrupted::DeadlineExceeded)
+    );
+}
+
+#[test]
+fn test_eval() {
+    let mut vm = Vm::new();
+    let sexp = read(&mut vm.gc, "(define (f x) (* x 2))").unwrap();
+    vm.eval(sexp);
+    let sexp = read(&mut vm.gc, "(f 21)").unwrap();
+    assert_eq!(vm.eval(sexp), Object::Number(42));
+    // eval from Scheme re-enters the compiler while the VM is running.
+    let sexp = read(&mut vm.gc, "(+ 1 (eval (quote (f 3)) #f))").unwrap();
+    assert_eq!(vm.eval(sexp), Object::Number(7));
+}
+
+#[test]
+fn test_read_all() {
+    let mut vm = Vm::new();
+    let text = "#!r6rs
+; A comment.
+#| A block #| nested |# comment. |#
+'a `(b ,c ,@d) #'e [f . g] #(h #;(skipped) i) j ; The last line has no newline.";
+    let data = read_all(&mut vm.gc, text).unwrap();
+    assert_eq!(
+        data.to_string(),
+        "('a (quasiquote (b (unquote c) (unquote-splicing d))) (syntax e) (f . g) #[h, i] j)"
+    );
+}
+
+#[test]
+fn test_eval_after_error() {
+    let mut vm = Vm::new();
+    let sexp = read(&mut vm.gc, "(define (f x) (car x))").unwrap();
+    vm.eval(sexp);
+    let sexp = read(&mut vm.gc, "(f 3)").unwrap();
+    let ret = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| vm.eval(sexp)));
+    assert!(ret.is_err());
+    vm.reset();
+    let sexp = read(&mut vm.gc, "(f (quote (1 2)))").unwrap();
+    assert_eq!(vm.eval(sexp), Object::Number(1));
+}
+
+#[test]
+fn test_move_vm_after_run() {
+    fn new_vm() -> Vm {
+        let mut vm = Vm::new();
+        vm.eval_string("(define (f x) (cons x x))");
+        vm
+    }
+    // Moving a Vm which has run must not break its stack pointers.
+    let mut vms: Vec<Vm> = (0..2).map(|_| new_vm()).collect();
+    for vm in vms.iter_mut() {
+        vm.eval_string("(gc)");
+        assert_eq!(vm.eval_string("(car (f 3))"), Object::Number(3));
+    }
+}
+
+#[test]
+#[should_panic(expected = "stack overflow")]
+fn test_stack_overflow() {
+    let mut vm = Vm::new();
+    vm.eval_string("(define (f n) (if (= n 0) 0 (+ 1 (f (- n 1))))) (f 100000)");
+}
+
+#[test]
+fn test_eval_releases_code() {
+    let mut vm = Vm::new();
+    vm.eval_string("(define (make-adder n) (lambda (x) (+ x n))) (define add1 (make-adder 1))");
+    vm.eval_string("(gc)");
+    let before = vm.gc.bytes_allocated();
+    // Code of programs which made no closures is freed after they return.
+    for _ in 0..100 {
+        vm.eval_string("(+ 1 2)");
+    }
+    vm.eval_string("(gc)");
+    assert_eq!(vm.gc.bytes_allocated(), before);
+    // Closures keep their code alive.
+    assert_eq!(vm.eval_string("(add1 41)"), Object::Number(42));
+}
+
+#[test]
+fn test_return_values() {
+    let mut vm = Vm::new();
+    let sexp = read(&mut vm.gc, "(values 1 2 3)").unwrap();
+    vm.eval(sexp);
+    assert_eq!(
+        vm.return_values(),
+        vec![Object::Number(1), Object::Number(2), Object::Number(3)]
+    );
+    let sexp = read(&mut vm.gc, "(values)").unwrap();
+    vm.eval(sexp);
+    assert_eq!(vm.return_values(), vec![]);
+    let sexp = read(&mut vm.gc, "4").unwrap();
+    vm.eval(sexp);
+    assert_eq!(vm.return_values(), vec![Object::Number(4)]);
+}
+
+#[test]
+fn test_write_string() {
+    let mut vm = Vm::new();
+    let obj = read(
+        &mut vm.gc,
+        "(a \"b\" #\\space #\\c #(1 2) (quote d) (e . f))",
+    )
+    .unwrap();
+    assert_eq!(
+        obj.to_write_string(),
+        "(a \"b\" #\\space #\\c #(1 2) 'd (e . f))"
+    );
+}
+
+#[test]
+fn test_library() {
+    let mut vm = Vm::new();
+    vm.eval_string(
+        "(library (test lib (1))
+           (export twice (rename (thrice triple)))
+           (import (rnrs base (6)))
+           (define (twice x) (* x 2))
+           (define (thrice x) (* x 3)))",
+    );
+    vm.eval_string(
+        "(import (rnrs)
+                 (prefix (test lib) t:)
+                 (rename (only (test lib) triple) (triple tri))
+                 (rename (rnrs) (car kar)))",
+    );
+    assert_eq!(vm.eval_string("(t:twice 4)"), Object::Number(8));
+    assert_eq!(vm.eval_string("(t:triple 4)"), Object::Number(12));
+    assert_eq!(vm.eval_string("(tri 5)"), Object::Number(15));
+    assert_eq!(vm.eval_string("(kar (quote (1 2)))"), Object::Number(1));
+}
+
+#[test]
+fn test_import_unknown_name() {
+    let mut vm = Vm::new();
+    vm.eval_string("(library (test lib) (export a) (import (rnrs)) (define a 1))");
+    let ret = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        vm.eval_string("(import (only (test lib) b))")
+    }));
+    assert!(ret.is_err());
+}
+
+#[test]
+fn test_define_library() {
+    let mut vm = Vm::new();
+    vm.eval_string(
+        "(define-library (test stack)
+           (export make-stack (rename stack-size size))
+           (import (scheme base))
+           (cond-expand
+             (rmosh (begin (define (make-stack) (quote ()))))
+             (else (begin (define (make-stack) #f))))
+           (begin (define (stack-size s) (length s))))",
+    );
+    vm.eval_string("(import (scheme base) (test stack))");
+    assert_eq!(vm.eval_string("(size (make-stack))"), Object::Number(0));
+}
+
+#[test]
+fn test_cond_expand() {
+    let mut vm = Vm::new();
+    vm.features.push("test-feature".to_string());
+    let ret = vm.eval_string(
+        "(cond-expand
+           ((and r7rs test-feature (not no-such-feature)) 1)
+           (else 2))",
+    );
+    assert_eq!(ret, Object::Number(1));
+    let ret = vm.eval_string("(+ 10 (cond-expand ((or no-such-feature (library (rnrs))) 2)))");
+    assert_eq!(ret, Object::Number(12));
+    vm.eval_string("(cond-expand (no-such-feature (define a 1)) (else (define a 3)))");
+    assert_eq!(vm.eval_string("a"), Object::Number(3));
+    let ret = vm.eval_string("(memq (quote test-feature) (features))");
+    assert!(ret.is_pair());
+    // cond-expand in an argument list is a variable, not a form.
+    vm.eval_string("(define cond-expand 2)");
+    let ret = vm.eval_string("(list 1 cond-expand (car (list 3)))");
+    assert_eq!(ret.to_write_string(), "(1 2 3)");
+}
+
+#[test]
+fn test_compile_cache() {
+    let dir = std::env::temp_dir().join(format!("rmosh-cache-test-{}", std::process::id()));
+    let cache_dir = dir.join("cache");
+    std::fs::create_dir_all(&dir).unwrap();
+    let source = dir.join("source.scm");
+    std::fs::write(&source, "(define (f x) (* x 2)) (f (car (quote (21 0))))").unwrap();
+    let source = source.to_str().unwrap();
+
+    let mut vm = Vm::new();
+    vm.cache_dir = Some(cache_dir.clone());
+    assert_eq!(vm.load_file(source), Object::Number(42));
+    let entries: Vec<_> = std::fs::read_dir(&cache_dir).unwrap().collect();
+    assert_eq!(entries.len(), 1);
+    let entry = entries[0].as_ref().unwrap().path();
+    let written = std::fs::metadata(&entry).unwrap().modified().unwrap();
+
+    // A fresh Vm uses the cached code and doesn't rewrite the entry.
+    let mut vm = Vm::new();
+    vm.cache_dir = Some(cache_dir.clone());
+    assert_eq!(vm.load_file(source), Object::Number(42));
+    assert_eq!(
+        std::fs::metadata(&entry).unwrap().modified().unwrap(),
+        written
+    );
+
+    // A corrupted or truncated entry is a cache miss, and it is rewritten.
+    let valid = std::fs::read(&entry).unwrap();
+    let mut corrupted = valid.clone();
+    // The first tag after the 36 bytes header.
+    corrupted[36] = 0xff;
+    let truncated = valid[..valid.len() - 5].to_vec();
+    for broken in [corrupted, truncated] {
+        std::fs::write(&entry, broken).unwrap();
+        let mut vm = Vm::new();
+        vm.cache_dir = Some(cache_dir.clone());
+        assert_eq!(vm.load_file(source), Object::Number(42));
+        assert_eq!(std::fs::read(&entry).unwrap(), valid);
+    }
+
+    // Changing the source invalidates the entry.
+    std::fs::write(source, "(define (f x) (* x 3)) (f (car (quote (21 0))))").unwrap();
+    let mut vm = Vm::new();
+    vm.cache_dir = Some(cache_dir);
+    assert_eq!(vm.load_file(source), Object::Number(63));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_shared_compiler() {
+    let mut vm1 = Vm::new();
+    vm1.eval_string("(define (f x) (* x 2))");
+    let mut vm2 = Vm::new();
+    assert_eq!(
+        vm2.eval_string("(define (f x) (+ x 1)) (f 1)"),
+        Object::Number(2)
+    );
+    assert_eq!(vm1.eval_string("(f 3)"), Object::Number(6));
+    // Both Vms run the same compiler code, so they share its symbols.
+    assert_eq!(vm1.gc.intern("lambda"), vm2.gc.intern("lambda"));
+    // So do Vms in other threads.
+    let handles: Vec<_> = (0..2)
+        .map(|_| thread::spawn(|| Vm::new().eval_string("(+ 1 2)") == Object::Number(3)))
+        .collect();
+    for handle in handles {
+        assert!(handle.join().unwrap());
+    }
+
+    // A Vm which interned the compiler's symbols before loading it gets its own copy of the compiler.
+    let mut vm3 = Vm::new();
+    let lambda = vm3.gc.intern("lambda");
+    assert_eq!(
+        vm3.eval_string("((lambda (x) (* x x)) 4)"),
+        Object::Number(16)
+    );
+    assert_eq!(vm3.gc.intern("lambda"), lambda);
+    assert_ne!(vm1.gc.intern("lambda"), lambda);
+}
+
+#[test]
+fn test_disassemble() {
+    let mut vm = Vm::new();
+    let f = vm.eval_string("(define (f x) (if (null? x) \"empty\" (car (g x)))) f");
+    let closure = match f {
+        Object::Closure(closure) => closure,
+        obj => panic!("closure expected but got {}", obj),
+    };
+    let listing = disassemble_closure(closure);
+    let (header, body) = listing.split_once('\n').unwrap();
+    assert!(header.ends_with("args: 1, free variables: 0"));
+    assert_eq!(
+        body,
+        "    0  ReferLocal 0
+    2  BranchNotNull L0
+    4  Constant \"empty\"
+    6  Return 1
+L0:
+    8  Frame L1
+   10  ReferLocal 0
+   12  Push
+   13  ReferGlobal g
+   15  Call 1
+L1:
+   17  Car
+   18  Return 1
+"
+    );
+
+    // Free variables are shown with their values.
+    let g = vm.eval_string("(let ((y 1)) (lambda () (car y)))");
+    let listing = disassemble_closure(g.to_closure());
+    assert!(listing.contains("    0  ReferFree 0  ; 1\n"));
+}
+
+#[test]
+fn test_verify() {
+    let mut vm = Vm::new();
+    let symbol = vm.gc.symbol_intern("a");
+    let position = |code: &[Object]| verify(code).map_err(|err| err.position);
+    assert_eq!(position(compiler_snapshot().ops()), Ok(()));
+    assert_eq!(
+        position(&[
+            Object::Instruction(Op::Constant),
+            Object::Number(1),
+            Object::Instruction(Op::Test),
+            Object::Number(4),
+            Object::Instruction(Op::ConstantPush),
+            Object::Number(2),
+            Object::Instruction(Op::Cons),
+            Object::Instruction(Op::Halt),
+        ]),
+        Ok(())
+    );
+    // Missing operand.
+    assert_eq!(position(&[Object::Instruction(Op::Constant)]), Err(0));
+    // Wrong kind of operand.
+    assert_eq!(
+        position(&[
+            Object::Instruction(Op::ReferGlobal),
+            Object::Number(1),
+            Object::Instruction(Op::Halt),
+        ]),
+        Err(0)
+    );
+    // Jump into the middle of an instruction.
+    assert_eq!(
+        position(&[
+            Object::Instruction(Op::LocalJmp),
+            Object::Number(2),
+            Object::Instruction(Op::ReferGlobal),
+            symbol,
+            Object::Instruction(Op::Halt),
+        ]),
+        Err(0)
+    );
+    // Stack underflow.
+    assert_eq!(
+        position(&[Object::Instruction(Op::Cons), Object::Instruction(Op::Halt),]),
+        Err(0)
+    );
+    // Paths join with different stack depths.
+    assert_eq!(
+        position(&[
+            Object::Instruction(Op::Test),
+            Object::Number(2),
+            Object::Instruction(Op::Push),
+            Object::Instruction(Op::Halt),
+        ]),
+        Err(3)
+    );
+
+    // run rejects broken code.
+    let ops = [Object::Instruction(Op::Cons), Object::Instruction(Op::Halt)];
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        vm.run(ops.as_ptr(), ops.len());
+    }));
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_backtrace() {
+    let mut vm = Vm::new();
+    vm.eval_string(
+        "(define (g x) (car x)) (define (f x) (+ 1 (g x))) (define h (lambda (y) (let ((z (f y))) z))) (define k h)",
+    );
+    let ret = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| vm.eval_string("(k 3)")));
+    assert!(ret.is_err());
+    let frames: Vec<String> = vm
+        .backtrace()
+        .frames
+        .iter()
+        .map(|frame| frame.to_string())
+        .collect();
+    assert_eq!(frames, ["(g x)", "(f x)", "(h y)", "<top-level>"]);
+    vm.reset();
+
+    // Closures are also named by the globals they are bound to.
+    let ret = vm.eval_string(
+        "(define f (let ((n 0)) (lambda () (set! n (+ n 1)) (backtrace)))) (define (main) (list (f))) (main)",
+    );
+    assert_eq!(
+        ret.to_write_string(),
+        "((\"(lambda) bound to f\" \"(main)\" \"<top-level>\"))"
+    );
+}
+
+// Output of the debugger which tests can read after the debugger is moved into a Vm.
+#[derive(Clone, Default)]
+struct SharedOutput(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+impl std::io::Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_debugger() {
+    let mut vm = Vm::new();
+    let output = SharedOutput::default();
+    let commands = "locals\nbt\nstep\nfinish\nnext\ncontinue\n";
+    vm.set_debugger(Debugger::new(
+        Box::new(std::io::Cursor::new(commands)),
+        Box::new(output.clone()),
+    ));
+    vm.eval_string(
+        // f is tail called, so g isn't in the backtrace.
+        "(define (sq x) (* x x)) (define (f a b) (+ (sq a) (sq b))) (define (g) (let ((y 2)) (f 1 y)))",
+    );
+    let ret = vm.eval_string("(break (quote f)) (g)");
+    assert_eq!(ret, Object::Number(5));
+    assert_eq!(
+        String::from_utf8_lossy(&output.0.borrow()),
+        "-> (f 1 2)
+debug>   a = 1
+  b = 2
+debug> backtrace:
+    0  (f a b)
+    1  <top-level>
+debug> -> (sq 1)
+debug> <- (sq 1) returns 1
+debug> -> (sq 2)
+debug> "
+    );
+
+    // Breakpoints can be removed, and the end of the input continues.
+    let ret = vm.eval_string("(unbreak) (g)");
+    assert_eq!(ret, Object::Number(5));
+    vm.eval_string("(break (quote sq))");
+    let ret = vm.eval_string("(g)");
+    assert_eq!(ret, Object::Number(5));
+    assert!(String::from_utf8_lossy(&output.0.borrow())
+        .ends_with("-> (sq 1)\ndebug> -> (sq 2)\ndebug> "));
+}
+
+#[test]
+fn test_trace() {
+    let mut vm = Vm::new();
+    let output = SharedOutput::default();
+    vm.tracer.set_output(Box::new(output.clone()));
+    vm.eval_string("(define (fact n) (if (= n 0) 1 (* n (fact (- n 1))))) (define (f n) (vector->list (vector (fact n))))");
+    let ret = vm.eval_string("(trace fact) (fact 2)");
+    assert_eq!(ret, Object::Number(2));
+    assert_eq!(
+        String::from_utf8_lossy(&output.0.borrow()),
+        "-> (fact 2)
+  -> (fact 1)
+    -> (fact 0)
+    <- fact returns 1
+  <- fact returns 1
+<- fact returns 2
+"
+    );
+    output.0.borrow_mut().clear();
+
+    // Native procedures can be traced too, and a tail call doesn't make a deeper frame.
+    vm.eval_string("(untrace) (trace vector->list)");
+    let ret = vm.eval_string("(list (f 1))");
+    assert_eq!(ret.to_write_string(), "((1))");
+    assert_eq!(
+        String::from_utf8_lossy(&output.0.borrow()),
+        "-> (vector->list #(1))\n<- vector->list returns (1)\n"
+    );
+    output.0.borrow_mut().clear();
+
+    // Tracing all calls doesn't show the compiler.
+    vm.eval_string("(untrace)");
+    vm.tracer.all = true;
+    vm.eval_string("(f 0)");
+    vm.tracer.all = false;
+    assert_eq!(
+        String::from_utf8_lossy(&output.0.borrow()),
+        "-> (f 0)
+  -> (fact 0)
+  <- fact returns 1
+-> (vector->list #(1))
+<- vector->list returns (1)
+"
+    );
+}
+
+#[test]
+fn test_get_closure_name() {
+    let mut vm = Vm::new();
+    let ret = vm.eval_string("(define (f a b) a) (%get-closure-name f)");
+    assert_eq!(ret.to_write_string(), "(f a b)");
+    let ret = vm.eval_string("(%get-closure-name car)");
+    assert_eq!(ret, Object::False);
+}
+
+#[cfg(feature = "profiler")]
+#[test]
+fn test_profile() {
+    let mut vm = Vm::new();
+    vm.eval_string("(define (fib n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2)))))");
+    vm.start_profile();
+    assert!(vm.is_profiling());
+    let ret = vm.eval_string("(fib 15)");
+    let profile = vm.stop_profile().unwrap();
+    assert_eq!(ret, Object::Number(610));
+    assert!(!vm.is_profiling());
+    assert_eq!(profile.calls("(fib n)"), Some(1973));
+    let report = profile.to_string();
+    assert!(report.contains(";; flat profile"));
+    assert!(report.contains(";; call graph"));
+    assert!(report.contains("NumberSub"));
+
+    // profile returns what the thunk returns.
+    let ret = vm.eval_string("(profile (lambda () (fib 10)))");
+    assert_eq!(ret, Object::Number(55));
+    assert!(!vm.is_profiling());
+}
+// The native compiler procedures must generate the same code as the Scheme ones.
+#[test]
+fn test_native_compiler_procs() {
+    let sources = [
+        "(define (fib n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2)))))",
+        "(let loop ((i 0) (acc (quote ()))) (if (= i 10) (reverse acc) (loop (+ i 1) (cons i acc))))",
+        "(let ((x 1) (y 2)) (let ((f (lambda () (set! x (+ x y)) x))) (f) (f)))",
+        "(define (f a b . c) (cond ((null? c) a) ((pair? c) (car c)) (else b)))",
+        "(letrec ((even? (lambda (n) (if (= n 0) #t (odd? (- n 1))))) (odd? (lambda (n) (if (= n 0) #f (even? (- n 1)))))) (even? 100))",
+        "(lambda (x) (lambda (y) (lambda (z) (set! x y) (list x y z))))",
+        "(define (g a b) (if (<= a b) (if (>= a 0) (if (> b 10) a b) 0) (if (= a b) 1 2)))",
+    ];
+    let compile = |use_native_compiler_procs: bool, source: &str| {
+        let mut vm = Vm::new();
+        vm.use_native_compiler_procs = use_native_compiler_procs;
+        vm.eval_string("#t");
+        let symbol = vm.gc.intern("compile-no-optimize");
+        let compiler = vm.global_value(symbol).unwrap();
+        let sexp = read(&mut vm.gc, source).unwrap();
+        format!("{:?}", vm.call_closure(compiler, &[sexp]))
+    };
+    for source in sources {
+        assert_eq!(compile(true, source), compile(false, source), "{}", source);
+    }
+}
+
+#[test]
+fn test_list_transpose() {
+    let mut vm = Vm::new();
+    let ret = vm.eval_string("(map (lambda (a b) (* a b)) (quote (1 2 3)) (quote (4 5 6)))");
+    assert_eq!(ret.to_write_string(), "(4 10 18)");
+    let ret = vm.eval_string("(list-transpose+ (quote (1 2)) (quote (3)))");
+    assert_eq!(ret, Object::False);
+}
+
+#[test]
+fn test_write_barrier() {
+    let mut vm = Vm::new();
+    // These objects survive the collections while this is compiled, so they are old.
+    vm.eval_string(
+        "(define v (make-vector 2 #f))
+         (define p (cons #f #f))
+         (define h (make-eq-hashtable))
+         (define set-x! (let ((x #f)) (lambda (v) (set! x v) (lambda () x))))
+         (define (loop i) (if (< i 100) (begin ((lambda () (list i i))) (loop (+ i 1)))))",
+    );
+    // Old objects refer young ones only by mutation.
+    // Closures created in the loop run minor collections which must keep the young ones.
+    let ret = vm.eval_string(
+        "(vector-set! v 0 (list 1 2))
+         (set-car! p (list 3))
+         (set-cdr! p (list 4))
+         (hashtable-set! h (quote k) (list 5))
+         (define get-x (set-x! (list 6)))
+         (loop 0)
+         (list (vector-ref v 0) p (hashtable-ref h (quote k) #f) (get-x))",
+    );
+    assert_eq!(ret.to_write_string(), "((1 2) ((3) 4) (5) (6))");
+}
+
+#[test]
+fn test_safepoints() {
+    let mut vm = Vm::new();
+    // A loop which creates no closures is collected at its calls and allocations.
+    vm.eval_string("(define (loop i) (if (= i 0) 0 (begin (cons i i) (list i i) (loop (- i 1)))))");
+    let before = vm.gc.bytes_allocated();
+    let ret = vm.eval_string("(loop 100000)");
+    assert_eq!(ret, Object::Number(0));
+    assert!(vm.gc.bytes_allocated() < before + 100000 * SIZE_OF_PAIR);
+}
+
+#[test]
+fn test_safepoints_in_native_loops() {
+    let mut vm = Vm::new();
+    define_iota(&mut vm);
+    vm.eval_string("(define l (iota 10000))");
+    let before = vm.gc.bytes_allocated();
+    // Native procedures don't collect themselves, but the procedures they call back are collected at their safepoints.
+    vm.eval_string("(for-each (lambda (x) (make-vector 100 x)) l)");
+    vm.eval_string("(list-sort (lambda (a b) (make-vector 100 a) (< a b)) l)");
+    let garbage = 10000 * 100 * std::mem::size_of::<Object>();
+    assert!(vm.gc.stats().peak_heap_size < before + garbage / 10);
+}
+
+#[test]
+fn test_weak_box() {
+    let mut vm = Vm::new();
+    let list = vm.gc.listn(&[Object::Number(1), Object::Number(2)]);
+    let weak_box = vm.gc.new_weak_box(list);
+    let expected = vm.gc.listn(&[weak_box, list]);
+    let ops = vec![
+        Object::Instruction(Op::Constant),
+        expected,
+        Object::Instruction(Op::Halt),
+    ];
+    test_ops_with_size(&mut vm, ops, expected, SIZE_OF_WEAK_BOX + SIZE_OF_PAIR * 4);
+    assert_eq!(weak_box.to_weak_box().value, list);
+
+    // Only the weak box refers the list now.
+    let ops = vec![
+        Object::Instruction(Op::Constant),
+        weak_box,
+        Object::Instruction(Op::Halt),
+    ];
+    test_ops_with_size(&mut vm, ops, weak_box, SIZE_OF_WEAK_BOX);
+    assert_eq!(weak_box.to_weak_box().value, Object::False);
+}
+
+#[test]
+fn test_weak_hashtable() {
+    let mut vm = Vm::new();
+    let hashtable = vm.gc.new_weak_eq_hashtable(Weakness::WeakKey);
+    let key1 = vm.gc.list1(Object::Number(1));
+    let key2 = vm.gc.list1(Object::Number(2));
+    let value1 = vm.gc.list1(Object::Number(10));
+    let value2 = vm.gc.list1(Object::Number(20));
+    hashtable.to_eq_hashtable().set(key1, value1);
+    hashtable.to_eq_hashtable().set(key2, value2);
+    let expected = vm.gc.listn(&[hashtable, key2]);
+    let ops = vec![
+        Object::Instruction(Op::Constant),
+        expected,
+        Object::Instruction(Op::Halt),
+    ];
+    // The entry of key1 is removed.
+    // value1 was marked as a value of the hashtable, so it is freed by the next collection.
+    test_ops_with_size(
+        &mut vm,
+        ops.clone(),
+        expected,
+        SIZE_OF_EQ_HASHTABLE + SIZE_OF_PAIR * 5,
+    );
+    assert_eq!(hashtable.to_eq_hashtable().size(), 1);
+    assert_eq!(hashtable.to_eq_hashtable().get(key2, Object::False), value2);
+    test_ops_with_size(
+        &mut vm,
+        ops,
+        expected,
+        SIZE_OF_EQ_HASHTABLE + SIZE_OF_PAIR * 4,
+    );
+}
+
+#[test]
+fn test_ephemeron_hashtable() {
+    let mut vm = Vm::new();
+    let weak = vm.gc.new_weak_eq_hashtable(Weakness::WeakKey);
+    let ephemeron = vm.gc.new_weak_eq_hashtable(Weakness::Ephemeron);
+    // Values refer their keys.
+    let key1 = vm.gc.list1(Object::Number(1));
+    let key2 = vm.gc.list1(Object::Number(2));
+    let value1 = vm.gc.cons(key1, key1);
+    let value2 = vm.gc.cons(key2, key2);
+    weak.to_eq_hashtable().set(key1, value1);
+    ephemeron.to_eq_hashtable().set(key2, value2);
+    let expected = vm.gc.listn(&[weak, ephemeron]);
+    let ops = vec![
+        Object::Instruction(Op::Constant),
+        expected,
+        Object::Instruction(Op::Halt),
+    ];
+    // The value keeps the key of the weak hashtable alive, but not the one of the ephemeron hashtable.
+    test_ops_with_size(
+        &mut vm,
+        ops,
+        expected,
+        SIZE_OF_EQ_HASHTABLE * 2 + SIZE_OF_PAIR * 4,
+    );
+    assert_eq!(weak.to_eq_hashtable().size(), 1);
+    assert_eq!(ephemeron.to_eq_hashtable().size(), 0);
+}
+
+#[test]
+fn test_weak_references_minor_collection() {
+    let mut vm = Vm::new();
+    vm.eval_string("(define ht (make-ephemeron-eq-hashtable)) (define wb (make-weak-box #f))");
+    vm.ac = Object::Unspecified;
+    vm.mark_and_sweep();
+    // The hashtable is old now, and the keys are young.
+    vm.eval_string(
+        "(define (fill i) (if (= i 0) 0 (begin (hashtable-set! ht (list i) i) (fill (- i 1)))))
+         (define (keep i) (if (= i 0) 0 (begin (cons i i) (keep (- i 1)))))",
+    );
+    vm.eval_string("(define k (list 0)) (hashtable-set! ht k (cons k k)) (fill 100)");
+    // Minor collections run while this allocates.
+    vm.eval_string("(keep 10000)");
+    assert_eq!(
+        vm.eval_string("(eq? (car (hashtable-ref ht k #f)) k)"),
+        Object::True
+    );
+    // Keys promoted before they died are freed by a major collection.
+    vm.ac = Object::Unspecified;
+    vm.mark_and_sweep();
+    assert_eq!(vm.eval_string("(hashtable-size ht)"), Object::Number(1));
+}
+
+#[test]
+fn test_guardian() {
+    let mut vm = Vm::new();
+    let guardian = vm.gc.new_guardian();
+    let list = vm.gc.listn(&[Object::Number(1), Object::Number(2)]);
+    guardian.to_guardian().register(list, list);
+    let ops = vec![
+        Object::Instruction(Op::Constant),
+        guardian,
+        Object::Instruction(Op::Halt),
+    ];
+    // The list is unreachable but resurrected.
+    test_ops_with_size(
+        &mut vm,
+        ops.clone(),
+        guardian,
+        SIZE_OF_GUARDIAN + SIZE_OF_PAIR * 2,
+    );
+    assert_eq!(guardian.to_guardian().pop(), Some(list));
+    assert_eq!(guardian.to_guardian().pop(), None);
+
+    // It is freed once the guardian returned it.
+    test_ops_with_size(&mut vm, ops, guardian, SIZE_OF_GUARDIAN);
+}
+
+#[test]
+fn test_guardian_procedure() {
+    let mut vm = Vm::new();
+    vm.eval_string(
+        "(define g (make-guardian)) (define x (list 1 2)) (define y (list 3))
+         (g x) (g y (quote y-is-dead))",
+    );
+    assert_eq!(vm.eval_string("(g)"), Object::False);
+    vm.eval_string("(set! x #f) (set! y #f)");
+    vm.ac = Object::Unspecified;
+    vm.mark_and_sweep();
+    assert_eq!(vm.eval_string("(cadr (g))"), Object::Number(2));
+    assert_eq!(vm.eval_string("(g)"), vm.gc.symbol_intern("y-is-dead"));
+    assert_eq!(vm.eval_string("(g)"), Object::False);
+    assert_eq!(vm.eval_string("(procedure? g)"), Object::True);
+}
+
+static NUM_FINALIZED_VECTORS: AtomicUsize = AtomicUsize::new(0);
+
+fn count_vector(obj: Object) {
+    if let Object::Vector(v) = obj {
+        assert_eq!(v.data.len(), 3);
+        NUM_FINALIZED_VECTORS.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn test_finalizer() {
+    let mut vm = Vm::new();
+    vm.gc.set_finalizer(ObjectType::Vector, count_vector);
+    let kept = vm.gc.new_vector(&vec![Object::Number(1); 3]);
+    for _ in 0..10 {
+        vm.gc.new_vector(&vec![Object::Number(1); 3]);
+    }
+    let ops = vec![
+        Object::Instruction(Op::Constant),
+        kept,
+        Object::Instruction(Op::Halt),
+    ];
+    test_ops_with_size(&mut vm, ops, kept, SIZE_OF_VECTOR);
+    assert_eq!(NUM_FINALIZED_VECTORS.load(Ordering::SeqCst), 10);
+}
+
+#[test]
+fn test_gc_stats() {
+    let mut vm = Vm::new();
+    let list = vm
+        .gc
+        .listn(&[Object::Number(1), Object::Number(2), Object::Number(3)]);
+    vm.gc.listn(&[Object::Number(4)]);
+    let ops = vec![
+        Object::Instruction(Op::Constant),
+        list,
+        Object::Instruction(Op::Halt),
+    ];
+    test_ops_with_size(&mut vm, ops, list, SIZE_OF_PAIR * 3);
+    let stats = vm.gc.stats();
+    assert_eq!(stats.num_collections, 1);
+    assert_eq!(stats.num_major_collections, 1);
+    assert_eq!(stats.last_pause, stats.total_pause);
+    assert_eq!(stats.heap_size, vm.gc.bytes_allocated());
+    assert!(stats.peak_heap_size >= stats.heap_size + SIZE_OF_PAIR);
+    assert!(stats.live_objects.contains(&(ObjectType::Pair, 3)));
+    assert!(stats
+        .live_bytes
+        .contains(&(ObjectType::Pair, SIZE_OF_PAIR * 3)));
+    assert!(stats
+        .live_bytes
+        .contains(&(ObjectType::Procedure, SIZE_OF_PROCEDURE * 623)));
+    let live_bytes: usize = stats.live_bytes.iter().map(|(_, bytes)| bytes).sum();
+    assert_eq!(live_bytes, stats.heap_size);
+}
+
+// The stress mode collects more often than this expects.
+#[cfg(not(feature = "gc_stress"))]
+#[test]
+fn test_gc_stats_procedure() {
+    let mut vm = Vm::new();
+    // Minor collections also run while this is compiled, but major ones don't.
+    vm.eval_string("(define (collections) (cdr (assq (quote major-collections) (gc-stats))))");
+    vm.eval_string("(define before (collections)) (gc)");
+    assert_eq!(
+        vm.eval_string("(- (collections) before)"),
+        Object::Number(1)
+    );
+    let num_pairs =
+        vm.eval_string("(cdr (assq (quote pair) (cdr (assq (quote live-objects) (gc-stats)))))");
+    assert!(num_pairs.is_number());
+}
+
+#[cfg(not(feature = "gc_stress"))]
+#[test]
+fn test_gc_config() {
+    let program = "(define (loop i) (if (= i 0) 0 (begin (cons i i) (loop (- i 1))))) (loop 20000)";
+    let mut eager = Vm::with_gc_config(GcConfig {
+        initial_threshold: 0,
+        grow_factor: 1.0,
+        ..GcConfig::default()
+    });
+    eager.eval_string(program);
+    let mut lazy = Vm::with_gc_config(GcConfig {
+        initial_threshold: 1 << 30,
+        grow_factor: 2.0,
+        ..GcConfig::default()
+    });
+    lazy.eval_string(program);
+    assert_eq!(lazy.gc.stats().num_major_collections, 0);
+    assert!(eager.gc.stats().num_major_collections > 0);
+}
+
+#[test]
+fn test_pages() {
+    // Pairs and voxes live in pages, so build long-lived and short-lived lists of both and compare with boxes.
+    // The stress mode collects at each safepoint after an allocation, so it builds smaller ones.
+    let (num_live, num_rounds) = if cfg!(feature = "gc_stress") {
+        (1000, 100)
+    } else {
+        (5000, 500)
+    };
+    let program = format!(
+        "(define (make i acc) (if (= i 0) acc (make (- i 1) (cons i acc))))
+         (define (boxes i acc) (if (= i 0) acc (boxes (- i 1) (let ((v i)) (set! v (+ v 1)) (cons (lambda () v) acc)))))
+         (define live (make {} (quote ())))
+         (define (churn i) (if (= i 0) 0 (begin (make 100 (quote ())) (boxes 10 (quote ())) (churn (- i 1)))))
+         (churn {})
+         (define live2 (boxes 100 (quote ())))
+         (churn {})
+         (define (sum l acc) (if (null? l) acc (sum (cdr l) (+ acc (car l)))))",
+        num_live, num_rounds, num_rounds
+    );
+    let mut sizes = vec![];
+    for use_pages in [true, false] {
+        let mut vm = Vm::with_gc_config(GcConfig {
+            use_pages,
+            ..GcConfig::default()
+        });
+        vm.eval_string(&program);
+        assert_eq!(vm.eval_string("(length live)"), Object::Number(num_live));
+        assert_eq!(
+            vm.eval_string("(sum live 0)"),
+            Object::Number(num_live * (num_live + 1) / 2)
+        );
+        assert_eq!(vm.eval_string("((car live2))"), Object::Number(2));
+        vm.ac = Object::Unspecified;
+        vm.mark_and_sweep();
+        let stats = vm.gc.stats();
+        assert!(stats.num_collections > 0);
+        sizes.push(stats.heap_size);
+    }
+    assert_eq!(sizes[0], sizes[1]);
+}
+
+#[cfg(feature = "gc_stress")]
+#[test]
+#[should_panic(expected = "is referenced after it was freed")]
+fn test_gc_stress_dangling_reference() {
+    let mut vm = Vm::new();
+    vm.eval_string("#t");
+    let pair = vm.gc.cons(Object::Number(1), Object::Number(2));
+    // Nothing roots the pair, so this frees it.
+    vm.collect_garbage(rmosh::gc::Collection::Major);
+    vm.push_root(pair);
+    vm.collect_garbage(rmosh::gc::Collection::Major);
+}
+
+// Dereferencing the freed object aborts, so the test runs itself again in a child process for that.
+#[cfg(debug_assertions)]
+#[test]
+fn test_freed_gc_ref() {
+    if std::env::var_os("RMOSH_TEST_FREED_GC_REF").is_some() {
+        let mut vm = Vm::new();
+        vm.eval_string("#t");
+        let pair = vm.gc.cons(Object::Number(1), Object::Number(2)).to_pair();
+        vm.collect_garbage(rmosh::gc::Collection::Major);
+        // This can take the memory of the pair.
+        vm.gc.cons(Object::Number(3), Object::Number(4));
+        let _ = pair.car;
+        return;
+    }
+    let output = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "test_freed_gc_ref", "--nocapture"])
+        .env("RMOSH_TEST_FREED_GC_REF", "1")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("refers an object which was freed"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_symbol_collection() {
+    let mut vm = Vm::new();
+    vm.eval_string(
+        "(define kept (string->symbol \"kept-symbol\"))
+         (define (make i) (if (= i 0) 0 (begin (string->symbol (number->string i)) (make (- i 1)))))
+         (make 1000)
+         (define hash (symbol-hash (string->symbol \"transient\")))",
+    );
+    vm.ac = Object::Unspecified;
+    vm.mark_and_sweep();
+    // Only the symbols which something refers stay interned.
+    assert!(!vm.gc.symbols.contains_key("500"));
+    assert!(!vm.gc.symbols.contains_key("transient"));
+    assert!(vm.gc.symbols.contains_key("kept-symbol"));
+    assert!(vm.gc.symbols.contains_key("kept"));
+    assert_eq!(
+        vm.eval_string("(eq? kept (string->symbol \"kept-symbol\"))"),
+        Object::True
+    );
+    // The hash doesn't change when a symbol is interned again.
+    assert_eq!(
+        vm.eval_string("(= hash (symbol-hash (string->symbol \"transient\")))"),
+        Object::True
+    );
+}
+
+#[test]
+fn test_gensym() {
+    let mut vm = Vm::new();
+    vm.eval_string("(define g1 (gensym)) (define g2 (gensym (quote foo)))");
+    assert_eq!(vm.eval_string("(symbol? g1)"), Object::True);
+    assert_eq!(vm.eval_string("(eq? g1 (gensym))"), Object::False);
+    assert_eq!(
+        vm.eval_string("(string=? (symbol->string g1) (symbol->string g2))"),
+        Object::False
+    );
+    // Gensyms are not interned.
+    assert_eq!(
+        vm.eval_string("(eq? g1 (string->symbol (symbol->string g1)))"),
+        Object::False
+    );
+    assert_eq!(
+        vm.eval_string("(eq? (ungensym g2) (quote foo))"),
+        Object::True
+    );
+    assert_eq!(
+        vm.eval_string("(eq? (ungensym (quote bar)) (quote bar))"),
+        Object::True
+    );
+    vm.eval_string("(gensym-prefix-set! (quote tmp))");
+    let symbol = vm.eval_string("(gensym)").to_symbol();
+    assert!(symbol.string.starts_with("tmp"));
+    assert_eq!(
+        vm.eval_string("(= (symbol-hash (quote abc)) (symbol-hash (string->symbol \"abc\")))"),
+        Object::True
+    );
+}
+
+#[test]
+fn test_heap_dump() {
+    let mut vm = Vm::new();
+    vm.eval_string("(define leak (vector 1 (list 2 3)))");
+    let list = vm.eval_string("(vector-ref leak 1)");
+    vm.ac = Object::Unspecified;
+    let dump = vm.heap_dump();
+    // After a full collection, every object in the heap is found.
+    let live_bytes: usize = dump.objects.iter().map(|o| o.size).sum();
+    assert_eq!(live_bytes, dump.heap_size);
+    assert_eq!(dump.heap_size, vm.gc.bytes_allocated());
+
+    let id = dump.id_of(list).unwrap();
+    let (root, path) = dump.path(id);
+    assert_eq!(root, "global leak");
+    assert_eq!(path.len(), 2);
+    assert_eq!(dump.objects[path[0]].obj_type, ObjectType::Vector);
+    assert_eq!(dump.objects[path[1]].obj_type, ObjectType::Pair);
+    // The global keeps its name too.
+    assert!(dump.root_totals().contains(&(
+        "global leak".to_string(),
+        4,
+        SIZE_OF_SYMBOL + SIZE_OF_VECTOR + SIZE_OF_PAIR * 2
+    )));
+
+    let mut json = vec![];
+    dump.write_json(&mut json).unwrap();
+    let json = String::from_utf8(json).unwrap();
+    assert!(json.starts_with("{\n  \"heap_size\": "));
+    assert!(json.contains(&format!(
+        "{{\"id\": {}, \"type\": \"vector\", \"size\": {}, \"root\": \"global leak\"}}",
+        path[0], SIZE_OF_VECTOR
+    )));
+    assert!(json.contains(&format!(
+        "{{\"id\": {}, \"type\": \"pair\", \"size\": {}, \"retainer\": {}}}",
+        path[1], SIZE_OF_PAIR, path[0]
+    )));
+}
+
+#[test]
+fn test_heap_dump_procedure() {
+    let mut vm = Vm::new();
+    let path = std::env::temp_dir().join(format!("rmosh-heap-dump-{}.json", std::process::id()));
+    vm.eval_string(&format!(
+        "(define s \"leaked string\") (heap-dump \"{}\")",
+        path.display()
+    ));
+    let json = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(json.contains(r#""root": "global s", "value": "leaked string"}"#));
+    assert!(json.trim_end().ends_with('}'));
+}